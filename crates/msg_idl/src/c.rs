@@ -0,0 +1,91 @@
+use std::fmt::Write;
+
+use crate::{upper_snake, Idl, Item, Type};
+
+/// 输出文档注释
+fn doc(out: &mut String, indent: &str, doc: &[String]) {
+    doc.iter()
+        .for_each(|line| writeln!(out, "{}/// {}", indent, line).unwrap());
+}
+
+/// C 不支持数字中的 `_`，常量名保持不变
+fn c_value(value: &str) -> String {
+    match value.starts_with(|x: char| x.is_ascii_digit()) {
+        true => value.replace('_', ""),
+        false => value.to_string(),
+    }
+}
+
+/// 生成 C 头文件，枚举成员会加上枚举名作为前缀，例如 `MESSAGE_TYPE_PING_MSG`
+pub fn generate(idl: &Idl, guard: &str) -> String {
+    let mut out = String::new();
+    out.push_str("// 由 msg_idl 自动生成，请修改 IDL 文件而不是这个文件\n");
+    writeln!(out, "#ifndef {}", guard).unwrap();
+    writeln!(out, "#define {}", guard).unwrap();
+    out.push_str("\n#include <stdint.h>\n");
+    for item in &idl.items {
+        out.push('\n');
+        match item {
+            Item::Const {
+                doc: docs,
+                name,
+                value,
+                ..
+            } => {
+                doc(&mut out, "", docs);
+                writeln!(out, "#define {} {}", name, c_value(value)).unwrap();
+            }
+            Item::Enum {
+                doc: docs,
+                name,
+                variants,
+            } => {
+                doc(&mut out, "", docs);
+                writeln!(out, "enum {} {{", name).unwrap();
+                for variant in variants {
+                    doc(&mut out, "    ", &variant.doc);
+                    writeln!(
+                        out,
+                        "    {}_{} = {},",
+                        upper_snake(name),
+                        upper_snake(&variant.name),
+                        c_value(&variant.value)
+                    )
+                    .unwrap();
+                }
+                out.push_str("};\n");
+            }
+            Item::Struct {
+                doc: docs,
+                name,
+                fields,
+                ..
+            } => {
+                doc(&mut out, "", docs);
+                writeln!(out, "typedef struct {} {{", name).unwrap();
+                for field in fields {
+                    doc(&mut out, "    ", &field.doc);
+                    match &field.ty {
+                        Type::Scalar(scalar) => {
+                            writeln!(out, "    {} {};", scalar.c_name(), field.name).unwrap()
+                        }
+                        Type::Struct(ty) => writeln!(out, "    {} {};", ty, field.name).unwrap(),
+                        Type::Array(scalar, len) => writeln!(
+                            out,
+                            "    {} {}[{}];",
+                            scalar.c_name(),
+                            field.name,
+                            c_value(len)
+                        )
+                        .unwrap(),
+                    }
+                }
+                writeln!(out, "}} {};", name).unwrap();
+            }
+            // 服务接口已经被展开为消息类型和负载结构
+            Item::Interface { .. } => {}
+        }
+    }
+    writeln!(out, "\n#endif // {}", guard).unwrap();
+    out
+}
@@ -0,0 +1,312 @@
+//! 消息接口描述语言 (IDL)
+//! 描述内核与用户程序之间共享的常量、枚举、`#[repr(C)]` 结构以及服务接口，
+//! 在 build.rs 中解析后生成 Rust 代码和 C 头文件，保证两种语言看到的布局一致
+//!
+//! ```text
+//! /// 文档注释会被复制到生成的代码中
+//! const NAME_LEN: usize = 64;
+//!
+//! enum MessageType {
+//!     NoneMsg = 0,
+//! }
+//!
+//! /// payload 表示这个结构可以作为消息的负载
+//! payload struct NamePayload {
+//!     name: [u8; NAME_LEN],
+//! }
+//!
+//! /// 服务接口，每个 rpc 的请求和回复消息类型会追加到 MessageType 中
+//! interface Ping: MessageType {
+//!     /// 请求的消息类型为 9，回复的消息类型为 10
+//!     rpc ping(value: usize) -> (value: usize) = 9;
+//! }
+//! ```
+
+mod c;
+mod parser;
+mod rpc;
+mod rust;
+
+pub use parser::parse;
+
+/// 短消息最多携带的负载字数，需要和 messages.idl 中的 `SHORT_MSG_WORDS` 一致
+pub const SHORT_MSG_WORDS: usize = 3;
+
+/// 基本类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scalar {
+    U8,
+    U16,
+    U32,
+    U64,
+    Usize,
+    Isize,
+}
+
+impl Scalar {
+    /// 从名称解析基本类型
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "u8" => Scalar::U8,
+            "u16" => Scalar::U16,
+            "u32" => Scalar::U32,
+            "u64" => Scalar::U64,
+            "usize" => Scalar::Usize,
+            "isize" => Scalar::Isize,
+            _ => return None,
+        })
+    }
+
+    /// Rust 中的类型名
+    pub fn rust_name(&self) -> &'static str {
+        match self {
+            Scalar::U8 => "u8",
+            Scalar::U16 => "u16",
+            Scalar::U32 => "u32",
+            Scalar::U64 => "u64",
+            Scalar::Usize => "usize",
+            Scalar::Isize => "isize",
+        }
+    }
+
+    /// C 中的类型名
+    pub fn c_name(&self) -> &'static str {
+        match self {
+            Scalar::U8 => "uint8_t",
+            Scalar::U16 => "uint16_t",
+            Scalar::U32 => "uint32_t",
+            Scalar::U64 => "uint64_t",
+            Scalar::Usize => "uintptr_t",
+            Scalar::Isize => "intptr_t",
+        }
+    }
+}
+
+/// 字段类型
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    /// 基本类型
+    Scalar(Scalar),
+    /// 数组，长度为数字或者常量名
+    Array(Scalar, String),
+    /// 之前定义的结构
+    Struct(String),
+}
+
+/// 结构中的字段
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub ty: Type,
+}
+
+/// 枚举中的成员
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub value: String,
+}
+
+/// IDL 中的一项定义
+#[derive(Debug, Clone)]
+pub enum Item {
+    /// 常量
+    Const {
+        doc: Vec<String>,
+        name: String,
+        ty: Scalar,
+        value: String,
+    },
+    /// 枚举，在 Rust 中表示为 `#[repr(usize)]` 的 enum
+    Enum {
+        doc: Vec<String>,
+        name: String,
+        variants: Vec<Variant>,
+    },
+    /// `#[repr(C)]` 结构，`payload` 表示可以作为消息的负载
+    Struct {
+        doc: Vec<String>,
+        name: String,
+        fields: Vec<Field>,
+        payload: bool,
+    },
+    /// 服务接口，`tag` 为消息类型所在的枚举
+    Interface {
+        doc: Vec<String>,
+        name: String,
+        tag: String,
+        rpcs: Vec<Rpc>,
+    },
+}
+
+/// 服务接口中的一个远程调用
+/// 请求的消息类型为 `id`，回复的消息类型为 `id + 1`
+#[derive(Debug, Clone)]
+pub struct Rpc {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub id: u64,
+    pub params: Vec<Field>,
+    pub returns: Vec<Field>,
+}
+
+impl Rpc {
+    /// 请求的消息类型名称
+    pub fn request_tag(&self) -> String {
+        format!("{}Msg", camel(&self.name))
+    }
+
+    /// 回复的消息类型名称
+    pub fn reply_tag(&self) -> String {
+        format!("{}ReplyMsg", camel(&self.name))
+    }
+
+    /// 请求负载的结构名称，没有参数时没有负载
+    pub fn request_struct(&self) -> String {
+        format!("{}Request", camel(&self.name))
+    }
+
+    /// 回复负载的结构名称，没有返回值时没有负载
+    pub fn reply_struct(&self) -> String {
+        format!("{}Reply", camel(&self.name))
+    }
+
+    /// 请求和回复都最多只有 [SHORT_MSG_WORDS] 个 usize 时可以使用短消息
+    pub fn is_short(&self) -> bool {
+        let short = |fields: &[Field]| {
+            fields.len() <= SHORT_MSG_WORDS
+                && fields.iter().all(|x| x.ty == Type::Scalar(Scalar::Usize))
+        };
+        short(&self.params) && short(&self.returns)
+    }
+}
+
+/// 解析后的 IDL 文件
+#[derive(Debug, Clone, Default)]
+pub struct Idl {
+    pub items: Vec<Item>,
+}
+
+impl Idl {
+    /// 生成 Rust 代码，生成的代码需要在依赖 `num_enum` 并且定义了 `Payload` trait 的 crate 中使用
+    pub fn to_rust(&self) -> String {
+        rust::generate(&self.lower())
+    }
+
+    /// 生成 C 头文件，`guard` 为头文件保护宏的名称
+    pub fn to_c(&self, guard: &str) -> String {
+        c::generate(&self.lower(), guard)
+    }
+
+    /// 根据服务接口生成客户端函数和服务端 trait，每个接口生成一个模块
+    /// 生成的代码需要在作用域中有 `call`, `call_short`, `payload` 和 `error_reply`
+    pub fn to_rust_rpc(&self) -> String {
+        rpc::generate(self)
+    }
+
+    /// 将服务接口展开为消息类型和负载结构
+    fn lower(&self) -> Idl {
+        let interfaces: Vec<_> = self
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Interface { tag, rpcs, .. } => Some((tag, rpcs)),
+                _ => None,
+            })
+            .collect();
+        let mut items = Vec::new();
+        for item in &self.items {
+            match item {
+                Item::Interface { .. } => {}
+                Item::Enum {
+                    doc,
+                    name,
+                    variants,
+                } => {
+                    let mut variants = variants.clone();
+                    for rpc in interfaces
+                        .iter()
+                        .filter(|(tag, _)| *tag == name)
+                        .flat_map(|(_, rpcs)| rpcs.iter())
+                    {
+                        variants.push(Variant {
+                            doc: rpc.doc.clone(),
+                            name: rpc.request_tag(),
+                            value: rpc.id.to_string(),
+                        });
+                        variants.push(Variant {
+                            doc: rpc.doc.clone(),
+                            name: rpc.reply_tag(),
+                            value: (rpc.id + 1).to_string(),
+                        });
+                    }
+                    items.push(Item::Enum {
+                        doc: doc.clone(),
+                        name: name.clone(),
+                        variants,
+                    });
+                }
+                _ => items.push(item.clone()),
+            }
+        }
+        // 请求和回复的负载结构放在最后，它们的字段可能用到前面定义的结构
+        for rpc in interfaces.iter().flat_map(|(_, rpcs)| rpcs.iter()) {
+            for (name, fields) in [
+                (rpc.request_struct(), &rpc.params),
+                (rpc.reply_struct(), &rpc.returns),
+            ] {
+                if !fields.is_empty() {
+                    items.push(Item::Struct {
+                        doc: rpc.doc.clone(),
+                        name,
+                        fields: fields.clone(),
+                        payload: true,
+                    });
+                }
+            }
+        }
+        Idl { items }
+    }
+}
+
+/// 将下划线命名转换为驼峰命名，例如 `read_block` 转换为 `ReadBlock`
+pub(crate) fn camel(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// 解析数字，支持 `0x` 前缀和 `_` 分隔符
+pub(crate) fn parse_number(value: &str) -> Option<u64> {
+    let value = value.replace('_', "");
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// 将驼峰命名转换为大写下划线命名，用于生成 C 中的枚举常量
+/// 连续的大写字母视为一个单词，例如 `VMMapRange` 转换为 `VM_MAP_RANGE`
+pub(crate) fn upper_snake(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut ret = String::new();
+    for (i, ch) in chars.iter().enumerate() {
+        if i != 0 && ch.is_ascii_uppercase() {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|x| x.is_ascii_lowercase());
+            if !prev.is_ascii_uppercase() || next_lower {
+                ret.push('_');
+            }
+        }
+        ret.push(ch.to_ascii_uppercase());
+    }
+    ret
+}
@@ -0,0 +1,27 @@
+//! 根据 IDL 文件生成 C 头文件，由根目录的 `make header` 调用
+//!
+//! ```text
+//! msg_idl <IDL 文件> <头文件> <头文件保护宏>
+//! ```
+
+use std::{env, fs, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let [_, idl_file, header, guard] = args.as_slice() else {
+        eprintln!("usage: msg_idl <idl file> <header> <guard>");
+        process::exit(1);
+    };
+    let source = fs::read_to_string(idl_file).unwrap_or_else(|err| {
+        eprintln!("can't read {}: {}", idl_file, err);
+        process::exit(1);
+    });
+    let idl = msg_idl::parse(&source).unwrap_or_else(|err| {
+        eprintln!("{}: {}", idl_file, err);
+        process::exit(1);
+    });
+    fs::write(header, idl.to_c(guard)).unwrap_or_else(|err| {
+        eprintln!("can't write {}: {}", header, err);
+        process::exit(1);
+    });
+}
@@ -0,0 +1,394 @@
+use crate::{parse_number, Field, Idl, Item, Rpc, Scalar, Type, Variant};
+
+/// 词法单元
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// 文档注释
+    Doc(String),
+    /// 标识符或关键字
+    Ident(String),
+    /// 数字，保留原始的写法
+    Number(String),
+    /// 标点符号
+    Punct(char),
+}
+
+/// 带有行号的词法单元
+type Spanned = (usize, Token);
+
+/// 将 IDL 源码拆分为词法单元，普通注释会被忽略
+fn tokenize(source: &str) -> Result<Vec<Spanned>, String> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let lineno = index + 1;
+        let line = line.trim();
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, ch)) = chars.next() {
+            match ch {
+                _ if ch.is_whitespace() => {}
+                '/' if line[start..].starts_with("///") => {
+                    tokens.push((lineno, Token::Doc(line[start + 3..].trim().to_string())));
+                    break;
+                }
+                '/' if line[start..].starts_with("//") => break,
+                '-' if line[start..].starts_with("->") => {
+                    chars.next();
+                    tokens.push((lineno, Token::Punct('>')));
+                }
+                '{' | '}' | '[' | ']' | '(' | ')' | ';' | ':' | ',' | '=' => {
+                    tokens.push((lineno, Token::Punct(ch)))
+                }
+                _ if ch.is_ascii_alphanumeric() || ch == '_' => {
+                    let mut end = start + ch.len_utf8();
+                    while let Some((i, c)) = chars.peek() {
+                        if !c.is_ascii_alphanumeric() && *c != '_' {
+                            break;
+                        }
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    let word = line[start..end].to_string();
+                    match ch.is_ascii_digit() {
+                        true => tokens.push((lineno, Token::Number(word))),
+                        false => tokens.push((lineno, Token::Ident(word))),
+                    }
+                }
+                _ => return Err(format!("line {}: unexpected character '{}'", lineno, ch)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// 语法分析器
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+    /// 已经定义的结构，可以作为后面结构的字段类型
+    structs: Vec<String>,
+}
+
+impl Parser {
+    /// 当前词法单元所在的行号
+    fn lineno(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map(|x| x.0)
+            .unwrap_or(0)
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("line {}: {}", self.lineno(), msg))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|x| &x.1)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|x| x.1.clone());
+        self.pos += 1;
+        token
+    }
+
+    /// 读取连续的文档注释
+    fn docs(&mut self) -> Vec<String> {
+        let mut docs = Vec::new();
+        while let Some(Token::Doc(doc)) = self.peek() {
+            docs.push(doc.clone());
+            self.pos += 1;
+        }
+        docs
+    }
+
+    fn expect_punct(&mut self, punct: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(x)) if x == punct => Ok(()),
+            _ => {
+                self.pos -= 1;
+                self.error(&format!("expected '{}'", punct))
+            }
+        }
+    }
+
+    /// 如果下一个词法单元是 `punct` 则跳过并返回 true
+    fn eat_punct(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(x)) => Ok(x),
+            _ => {
+                self.pos -= 1;
+                self.error("expected identifier")
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Number(x)) => Ok(x),
+            _ => {
+                self.pos -= 1;
+                self.error("expected number")
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Scalar, String> {
+        let name = self.ident()?;
+        match Scalar::from_name(&name) {
+            Some(scalar) => Ok(scalar),
+            None => {
+                self.pos -= 1;
+                self.error(&format!("unknown type '{}'", name))
+            }
+        }
+    }
+
+    /// 字段类型，基本类型、之前定义的结构或者 `[scalar; len]`
+    fn field_type(&mut self) -> Result<Type, String> {
+        if let Some(Token::Ident(name)) = self.peek() {
+            if self.structs.contains(name) {
+                let name = name.clone();
+                self.pos += 1;
+                return Ok(Type::Struct(name));
+            }
+        }
+        if !self.eat_punct('[') {
+            return Ok(Type::Scalar(self.scalar()?));
+        }
+        let scalar = self.scalar()?;
+        self.expect_punct(';')?;
+        let len = match self.next() {
+            Some(Token::Number(x)) | Some(Token::Ident(x)) => x,
+            _ => {
+                self.pos -= 1;
+                return self.error("expected array length");
+            }
+        };
+        self.expect_punct(']')?;
+        Ok(Type::Array(scalar, len))
+    }
+
+    /// `const NAME: type = value;`
+    fn item_const(&mut self, doc: Vec<String>) -> Result<Item, String> {
+        let name = self.ident()?;
+        self.expect_punct(':')?;
+        let ty = self.scalar()?;
+        self.expect_punct('=')?;
+        let value = self.number()?;
+        self.expect_punct(';')?;
+        Ok(Item::Const {
+            doc,
+            name,
+            ty,
+            value,
+        })
+    }
+
+    /// `enum Name { Variant = value, ... }`
+    fn item_enum(&mut self, doc: Vec<String>) -> Result<Item, String> {
+        let name = self.ident()?;
+        self.expect_punct('{')?;
+        let mut variants = Vec::new();
+        loop {
+            let doc = self.docs();
+            if self.eat_punct('}') {
+                break;
+            }
+            let name = self.ident()?;
+            self.expect_punct('=')?;
+            let value = self.number()?;
+            variants.push(Variant { doc, name, value });
+            if !self.eat_punct(',') {
+                self.expect_punct('}')?;
+                break;
+            }
+        }
+        Ok(Item::Enum {
+            doc,
+            name,
+            variants,
+        })
+    }
+
+    /// 字段列表 `field: type, ...`，以 `end` 结束
+    fn fields(&mut self, end: char) -> Result<Vec<Field>, String> {
+        let mut fields = Vec::new();
+        loop {
+            let doc = self.docs();
+            if self.eat_punct(end) {
+                break;
+            }
+            let name = self.ident()?;
+            self.expect_punct(':')?;
+            let ty = self.field_type()?;
+            fields.push(Field { doc, name, ty });
+            if !self.eat_punct(',') {
+                self.expect_punct(end)?;
+                break;
+            }
+        }
+        Ok(fields)
+    }
+
+    /// `[payload] struct Name { field: type, ... }`
+    fn item_struct(&mut self, doc: Vec<String>, payload: bool) -> Result<Item, String> {
+        let name = self.ident()?;
+        self.expect_punct('{')?;
+        let fields = self.fields('}')?;
+        self.structs.push(name.clone());
+        Ok(Item::Struct {
+            doc,
+            name,
+            fields,
+            payload,
+        })
+    }
+
+    /// `rpc name(param: type, ...) [-> (ret: type, ...)] = id;`
+    fn rpc(&mut self, doc: Vec<String>) -> Result<Rpc, String> {
+        match self.ident()?.as_str() {
+            "rpc" => {}
+            _ => {
+                self.pos -= 1;
+                return self.error("expected 'rpc'");
+            }
+        }
+        let name = self.ident()?;
+        self.expect_punct('(')?;
+        let params = self.fields(')')?;
+        let mut returns = Vec::new();
+        if self.eat_punct('>') {
+            self.expect_punct('(')?;
+            returns = self.fields(')')?;
+        }
+        self.expect_punct('=')?;
+        let id = self.number()?;
+        let id = match parse_number(&id) {
+            Some(id) => id,
+            None => return self.error(&format!("invalid rpc id '{}'", id)),
+        };
+        self.expect_punct(';')?;
+        Ok(Rpc {
+            doc,
+            name,
+            id,
+            params,
+            returns,
+        })
+    }
+
+    /// `interface Name: Enum { rpc ... }`
+    fn item_interface(&mut self, doc: Vec<String>) -> Result<Item, String> {
+        let name = self.ident()?;
+        self.expect_punct(':')?;
+        let tag = self.ident()?;
+        self.expect_punct('{')?;
+        let mut rpcs = Vec::new();
+        loop {
+            let doc = self.docs();
+            if self.eat_punct('}') {
+                break;
+            }
+            rpcs.push(self.rpc(doc)?);
+        }
+        Ok(Item::Interface {
+            doc,
+            name,
+            tag,
+            rpcs,
+        })
+    }
+}
+
+/// 解析 IDL 源码，出错时返回带有行号的错误信息
+pub fn parse(source: &str) -> Result<Idl, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+        structs: Vec::new(),
+    };
+    let mut idl = Idl::default();
+    loop {
+        let doc = parser.docs();
+        let item = match parser.next() {
+            None => break,
+            Some(Token::Ident(keyword)) => match keyword.as_str() {
+                "const" => parser.item_const(doc)?,
+                "enum" => parser.item_enum(doc)?,
+                "struct" => parser.item_struct(doc, false)?,
+                "payload" => match parser.ident()?.as_str() {
+                    "struct" => parser.item_struct(doc, true)?,
+                    _ => {
+                        parser.pos -= 1;
+                        return parser.error("expected 'struct'");
+                    }
+                },
+                "interface" => parser.item_interface(doc)?,
+                _ => {
+                    parser.pos -= 1;
+                    return parser.error(&format!("unknown keyword '{}'", keyword));
+                }
+            },
+            Some(_) => {
+                parser.pos -= 1;
+                return parser.error("expected 'const', 'enum', 'struct' or 'interface'");
+            }
+        };
+        idl.items.push(item);
+    }
+    validate(&idl)?;
+    Ok(idl)
+}
+
+/// 检查服务接口使用的枚举是否存在，rpc 名称是否重复，以及展开后枚举中的值是否重复
+fn validate(idl: &Idl) -> Result<(), String> {
+    let mut rpc_names: Vec<&str> = Vec::new();
+    for item in &idl.items {
+        if let Item::Interface {
+            name, tag, rpcs, ..
+        } = item
+        {
+            let has_tag = idl
+                .items
+                .iter()
+                .any(|x| matches!(x, Item::Enum { name, .. } if name == tag));
+            if !has_tag {
+                return Err(format!("interface {}: unknown enum '{}'", name, tag));
+            }
+            for rpc in rpcs {
+                if rpc_names.contains(&rpc.name.as_str()) {
+                    return Err(format!("interface {}: duplicate rpc '{}'", name, rpc.name));
+                }
+                rpc_names.push(&rpc.name);
+            }
+        }
+    }
+    // rpc 的请求和回复会追加到枚举中，和已有的成员或者其他 rpc 的编号重复时无法区分消息
+    for item in &idl.lower().items {
+        if let Item::Enum { name, variants, .. } = item {
+            let mut values = Vec::new();
+            for variant in variants {
+                let Some(value) = parse_number(&variant.value) else {
+                    continue;
+                };
+                if values.contains(&value) {
+                    return Err(format!(
+                        "enum {}: duplicate value {} of '{}'",
+                        name, variant.value, variant.name
+                    ));
+                }
+                values.push(value);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use std::fmt::Write;
+
+use crate::{rust::rust_type, upper_snake, Field, Idl, Item, Rpc};
+
+/// 输出文档注释
+fn doc(out: &mut String, indent: &str, doc: &[String]) {
+    doc.iter()
+        .for_each(|line| writeln!(out, "{}/// {}", indent, line).unwrap());
+}
+
+/// 生成代码的最大行宽，和 rustfmt 的默认值一致
+const MAX_WIDTH: usize = 100;
+
+/// 输出函数调用或者函数签名 `{indent}{head}(args){tail}`，超过最大行宽时每个参数占一行
+fn call_list(out: &mut String, indent: &str, head: &str, args: &[String], tail: &str) {
+    let line = format!("{}{}({}){}", indent, head, args.join(", "), tail);
+    if line.chars().count() <= MAX_WIDTH {
+        out.push_str(&line);
+        out.push('\n');
+        return;
+    }
+    writeln!(out, "{}{}(", indent, head).unwrap();
+    args.iter()
+        .for_each(|arg| writeln!(out, "{}    {},", indent, arg).unwrap());
+    writeln!(out, "{}){}", indent, tail).unwrap();
+}
+
+/// 参数列表，例如 `["a: usize", "b: [u8; 4]"]`
+fn params(fields: &[Field]) -> Vec<String> {
+    fields
+        .iter()
+        .map(|x| format!("{}: {}", x.name, rust_type(&x.ty)))
+        .collect()
+}
+
+/// 返回值类型，没有返回值时为 `()`，多个返回值时为元组
+fn returns(fields: &[Field]) -> String {
+    match fields {
+        [field] => rust_type(&field.ty),
+        _ => format!(
+            "({})",
+            fields
+                .iter()
+                .map(|x| rust_type(&x.ty))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// 字段名称列表，多个字段时使用括号组成元组
+fn names(fields: &[Field], prefix: &str) -> String {
+    let names: Vec<_> = fields
+        .iter()
+        .map(|x| format!("{}{}", prefix, x.name))
+        .collect();
+    match names.len() {
+        1 => names[0].clone(),
+        _ => format!("({})", names.join(", ")),
+    }
+}
+
+/// 输出用字段构建负载结构的消息 `{indent}{head}RawMessage::new(..){tail}`，没有字段时构建没有负载的消息
+fn message(
+    out: &mut String,
+    indent: &str,
+    head: &str,
+    tail: &str,
+    rpc_tag: &str,
+    name: &str,
+    fields: &[Field],
+) {
+    let args = match fields.is_empty() {
+        true => vec![rpc_tag.to_string()],
+        false => vec![
+            rpc_tag.to_string(),
+            format!(
+                "{} {{ {} }}",
+                name,
+                fields
+                    .iter()
+                    .map(|x| x.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ],
+    };
+    let func = match fields.is_empty() {
+        true => "RawMessage::empty",
+        false => "RawMessage::new",
+    };
+    call_list(out, indent, &format!("{}{}", head, func), &args, tail);
+}
+
+/// 生成客户端函数
+fn client(out: &mut String, tag: &str, rpc: &Rpc) {
+    let request_tag = format!("{}::{}", tag, rpc.request_tag());
+    let reply_tag = format!("{}::{}", tag, rpc.reply_tag());
+    out.push('\n');
+    doc(out, "    ", &rpc.doc);
+    let mut args = vec!["server: usize".to_string()];
+    args.extend(params(&rpc.params));
+    call_list(
+        out,
+        "    ",
+        &format!("pub fn {}", rpc.name),
+        &args,
+        &format!(" -> Result<{}, UserError> {{", returns(&rpc.returns)),
+    );
+    message(
+        out,
+        "        ",
+        "let request = ",
+        ";",
+        &request_tag,
+        &rpc.request_struct(),
+        &rpc.params,
+    );
+    // 请求和回复的负载都足够短时使用短消息，只通过寄存器传递
+    let call = match rpc.is_short() {
+        true => format!("call_short(server, request, {})?", reply_tag),
+        false => format!("call(server, request, {})?", reply_tag),
+    };
+    match rpc.returns.is_empty() {
+        true => writeln!(out, "        {};\n        Ok(())", call).unwrap(),
+        false => {
+            writeln!(out, "        let reply = {};", call).unwrap();
+            writeln!(
+                out,
+                "        let reply: {} = payload(&reply)?;",
+                rpc.reply_struct()
+            )
+            .unwrap();
+            writeln!(out, "        Ok({})", names(&rpc.returns, "reply.")).unwrap();
+        }
+    }
+    out.push_str("    }\n");
+}
+
+/// 生成服务端 trait
+fn server(out: &mut String, name: &str, rpcs: &[Rpc]) {
+    writeln!(
+        out,
+        "\n    /// {} 服务需要实现的接口，`source` 为请求的来源任务",
+        name
+    )
+    .unwrap();
+    out.push_str("    pub trait Server {\n");
+    for rpc in rpcs {
+        doc(out, "        ", &rpc.doc);
+        let mut args = vec!["&mut self".to_string(), "source: usize".to_string()];
+        args.extend(params(&rpc.params));
+        call_list(
+            out,
+            "        ",
+            &format!("fn {}", rpc.name),
+            &args,
+            &format!(" -> Result<{}, UserError>;", returns(&rpc.returns)),
+        );
+    }
+    out.push_str("    }\n");
+}
+
+/// 生成分发函数
+fn dispatch(out: &mut String, name: &str, tag: &str, rpcs: &[Rpc]) {
+    writeln!(
+        out,
+        "\n    /// 处理 {} 服务的请求，返回需要回复的消息，请求不属于这个服务时返回 None",
+        name
+    )
+    .unwrap();
+    out.push_str("    /// 服务返回错误或者请求的负载不正确时回复错误消息\n");
+    call_list(
+        out,
+        "    ",
+        "pub fn dispatch<S: Server + ?Sized>",
+        &[
+            "server: &mut S".to_string(),
+            "request: &RawMessage".to_string(),
+        ],
+        " -> Option<RawMessage> {",
+    );
+    out.push_str("        let source = request.header.source;\n");
+    out.push_str("        let reply = match request.msg_type()? {\n");
+    for rpc in rpcs {
+        writeln!(out, "            {}::{} => {{", tag, rpc.request_tag()).unwrap();
+        // 解析负载、调用服务和构建回复分别占一行
+        match rpc.params.is_empty() {
+            true => writeln!(
+                out,
+                "                server\n                    .{}(source)",
+                rpc.name
+            )
+            .unwrap(),
+            false => {
+                writeln!(
+                    out,
+                    "                payload::<{}>(request)",
+                    rpc.request_struct()
+                )
+                .unwrap();
+                let mut args = vec!["source".to_string()];
+                args.extend(rpc.params.iter().map(|x| format!("req.{}", x.name)));
+                call_list(
+                    out,
+                    "                    ",
+                    &format!(".and_then(|req| server.{}", rpc.name),
+                    &args,
+                    ")",
+                );
+            }
+        }
+        let pattern = match rpc.returns.is_empty() {
+            true => "_".to_string(),
+            false => names(&rpc.returns, ""),
+        };
+        message(
+            out,
+            "                    ",
+            &format!(".map(|{}| ", pattern),
+            ")",
+            &format!("{}::{}", tag, rpc.reply_tag()),
+            &rpc.reply_struct(),
+            &rpc.returns,
+        );
+        out.push_str("            }\n");
+    }
+    out.push_str("            _ => return None,\n");
+    out.push_str("        };\n");
+    out.push_str("        Some(reply.unwrap_or_else(error_reply))\n");
+    out.push_str("    }\n");
+}
+
+/// 生成客户端函数和服务端接口
+pub fn generate(idl: &Idl) -> String {
+    let mut out = String::from("// 由 msg_idl 自动生成，请修改 IDL 文件而不是这个文件\n");
+    for item in &idl.items {
+        let Item::Interface {
+            doc: docs,
+            name,
+            tag,
+            rpcs,
+        } = item
+        else {
+            continue;
+        };
+        out.push('\n');
+        doc(&mut out, "", docs);
+        writeln!(out, "pub mod {} {{", upper_snake(name).to_lowercase()).unwrap();
+        out.push_str("    use super::*;\n");
+        rpcs.iter().for_each(|rpc| client(&mut out, tag, rpc));
+        server(&mut out, name, rpcs);
+        dispatch(&mut out, name, tag, rpcs);
+        out.push_str("}\n");
+    }
+    out
+}
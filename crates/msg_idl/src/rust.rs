@@ -0,0 +1,75 @@
+use std::fmt::Write;
+
+use crate::{Idl, Item, Type};
+
+/// 输出文档注释
+fn doc(out: &mut String, indent: &str, doc: &[String]) {
+    doc.iter()
+        .for_each(|line| writeln!(out, "{}/// {}", indent, line).unwrap());
+}
+
+/// Rust 中的类型
+pub(crate) fn rust_type(ty: &Type) -> String {
+    match ty {
+        Type::Scalar(scalar) => scalar.rust_name().to_string(),
+        Type::Array(scalar, len) => format!("[{}; {}]", scalar.rust_name(), len),
+        Type::Struct(name) => name.clone(),
+    }
+}
+
+/// 生成 Rust 代码
+pub fn generate(idl: &Idl) -> String {
+    let mut out = String::from("// 由 msg_idl 自动生成，请修改 IDL 文件而不是这个文件\n");
+    for item in &idl.items {
+        out.push('\n');
+        match item {
+            Item::Const {
+                doc: docs,
+                name,
+                ty,
+                value,
+            } => {
+                doc(&mut out, "", docs);
+                writeln!(out, "pub const {}: {} = {};", name, ty.rust_name(), value).unwrap();
+            }
+            Item::Enum {
+                doc: docs,
+                name,
+                variants,
+            } => {
+                doc(&mut out, "", docs);
+                out.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n");
+                out.push_str("#[derive(num_enum::IntoPrimitive, num_enum::TryFromPrimitive)]\n");
+                out.push_str("#[repr(usize)]\n");
+                writeln!(out, "pub enum {} {{", name).unwrap();
+                for variant in variants {
+                    doc(&mut out, "    ", &variant.doc);
+                    writeln!(out, "    {} = {},", variant.name, variant.value).unwrap();
+                }
+                out.push_str("}\n");
+            }
+            Item::Struct {
+                doc: docs,
+                name,
+                fields,
+                payload,
+            } => {
+                doc(&mut out, "", docs);
+                out.push_str("#[repr(C)]\n");
+                out.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n");
+                writeln!(out, "pub struct {} {{", name).unwrap();
+                for field in fields {
+                    doc(&mut out, "    ", &field.doc);
+                    writeln!(out, "    pub {}: {},", field.name, rust_type(&field.ty)).unwrap();
+                }
+                out.push_str("}\n");
+                if *payload {
+                    writeln!(out, "\nunsafe impl Payload for {} {{}}", name).unwrap();
+                }
+            }
+            // 服务接口已经被展开为消息类型和负载结构
+            Item::Interface { .. } => {}
+        }
+    }
+    out
+}
@@ -0,0 +1,156 @@
+//! 使用仓库中的 messages.idl 和一些错误的 IDL 检查解析和代码生成
+
+use msg_idl::{parse, Item};
+
+/// 内核和用户程序共享的 IDL 文件
+const MESSAGES_IDL: &str = include_str!("../../syscall_consts/messages.idl");
+
+/// 提交到仓库中的 C 头文件
+const C_HEADER: &str = include_str!("../../syscall_consts/include/syscall_consts.h");
+
+/// 获取枚举 `name` 中所有成员的名称和值
+fn variants(items: &[Item], name: &str) -> Vec<(String, String)> {
+    items
+        .iter()
+        .find_map(|item| match item {
+            Item::Enum {
+                name: enum_name,
+                variants,
+                ..
+            } if enum_name == name => Some(
+                variants
+                    .iter()
+                    .map(|x| (x.name.clone(), x.value.clone()))
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn parse_messages_idl() {
+    let idl = parse(MESSAGES_IDL).unwrap();
+    // 每个服务接口的 rpc 都会生成请求和回复的消息类型以及负载结构
+    let rust = idl.to_rust();
+    for item in &idl.items {
+        let Item::Interface { rpcs, .. } = item else {
+            continue;
+        };
+        for rpc in rpcs {
+            assert!(rust.contains(&format!("{} = {},", rpc.request_tag(), rpc.id)));
+            assert!(rust.contains(&format!("{} = {},", rpc.reply_tag(), rpc.id + 1)));
+            if !rpc.params.is_empty() {
+                assert!(rust.contains(&format!("pub struct {} {{", rpc.request_struct())));
+            }
+        }
+    }
+    // 原始的枚举成员保持不变
+    assert!(variants(&idl.items, "MessageType").contains(&("NoneMsg".into(), "0".into())));
+}
+
+#[test]
+fn generate_rpc() {
+    let rpc = parse(MESSAGES_IDL).unwrap().to_rust_rpc();
+    assert!(rpc.contains("pub mod ping {"));
+    assert!(rpc.contains("pub fn ping(server: usize, value: usize) -> Result<usize, UserError> {"));
+    // 只有 usize 参数和返回值的 rpc 使用短消息
+    assert!(rpc.contains("call_short(server, request, MessageType::PingReplyMsg)?"));
+    assert!(rpc.contains("call(server, request, MessageType::ReadBlockReplyMsg)?"));
+    assert!(rpc.contains("pub trait Server {"));
+}
+
+#[test]
+fn header_in_sync() {
+    let header = parse(MESSAGES_IDL).unwrap().to_c("SYSCALL_CONSTS_H");
+    assert!(
+        header == C_HEADER,
+        "syscall_consts.h is out of date, run `make header`"
+    );
+}
+
+#[test]
+fn generate_header() {
+    let source = "
+        /// 名称的长度
+        const NAME_LEN: usize = 0x40;
+
+        enum MessageType {
+            NoneMsg = 0,
+        }
+
+        payload struct NamePayload {
+            name: [u8; NAME_LEN],
+        }
+
+        interface Ping: MessageType {
+            rpc ping(value: usize) -> (value: usize) = 9;
+        }
+    ";
+    let header = parse(source).unwrap().to_c("TEST_H");
+    assert!(header.contains("#ifndef TEST_H\n#define TEST_H\n"));
+    assert!(header.contains("/// 名称的长度\n#define NAME_LEN 0x40\n"));
+    assert!(header.contains("MESSAGE_TYPE_PING_MSG = 9,"));
+    assert!(header.contains("MESSAGE_TYPE_PING_REPLY_MSG = 10,"));
+    assert!(header.contains("uint8_t name[NAME_LEN];"));
+    assert!(header.contains("uintptr_t value;"));
+    assert!(header.ends_with("#endif // TEST_H\n"));
+}
+
+#[test]
+fn reject_duplicate_id() {
+    // rpc 的回复编号 10 和已有的成员重复
+    let source = "
+        enum MessageType {
+            NoneMsg = 0,
+            OtherMsg = 10,
+        }
+
+        interface Ping: MessageType {
+            rpc ping(value: usize) -> (value: usize) = 9;
+        }
+    ";
+    let err = parse(source).unwrap_err();
+    assert!(err.contains("duplicate value 10"), "{}", err);
+
+    // 两个 rpc 的编号重复
+    let source = "
+        enum MessageType {
+            NoneMsg = 0,
+        }
+
+        interface A: MessageType {
+            rpc a() = 3;
+        }
+
+        interface B: MessageType {
+            rpc b() = 4;
+        }
+    ";
+    let err = parse(source).unwrap_err();
+    assert!(err.contains("duplicate value 4"), "{}", err);
+}
+
+#[test]
+fn reject_invalid_idl() {
+    let source = "
+        enum MessageType {
+            NoneMsg = 0,
+        }
+
+        interface A: MessageType {
+            rpc a() = 3;
+            rpc a() = 5;
+        }
+    ";
+    assert!(parse(source).unwrap_err().contains("duplicate rpc 'a'"));
+
+    let source = "interface A: Unknown {\n    rpc a() = 3;\n}\n";
+    assert!(parse(source)
+        .unwrap_err()
+        .contains("unknown enum 'Unknown'"));
+
+    // 错误信息包含行号
+    let source = "const A: usize = 1;\nconst B: usize = ?;\n";
+    assert!(parse(source).unwrap_err().starts_with("line 2:"));
+}
@@ -0,0 +1,26 @@
+use std::{env, fs, path::Path};
+
+/// ABI 定义文件
+const IDL_FILE: &str = "messages.idl";
+/// C 头文件，由根目录的 `make header` 生成并提交到仓库中供 C 程序使用
+const C_HEADER: &str = "include/syscall_consts.h";
+/// C 头文件的保护宏，需要和 Makefile 中的一致
+const C_HEADER_GUARD: &str = "SYSCALL_CONSTS_H";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", IDL_FILE);
+    println!("cargo:rerun-if-changed={}", C_HEADER);
+    let source = fs::read_to_string(IDL_FILE).expect("can't read messages.idl");
+    let idl = msg_idl::parse(&source).unwrap_or_else(|err| panic!("{}: {}", IDL_FILE, err));
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("abi.rs"), idl.to_rust()).unwrap();
+
+    // 构建时不修改源码目录，只检查提交的头文件是否和 IDL 一致
+    if fs::read_to_string(C_HEADER).ok() != Some(idl.to_c(C_HEADER_GUARD)) {
+        panic!(
+            "{} is out of date, run `make header` to regenerate it",
+            C_HEADER
+        );
+    }
+}
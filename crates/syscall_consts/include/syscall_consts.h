@@ -0,0 +1,429 @@
+// 由 msg_idl 自动生成，请修改 IDL 文件而不是这个文件
+#ifndef SYSCALL_CONSTS_H
+#define SYSCALL_CONSTS_H
+
+#include <stdint.h>
+
+/// 一般用在 [SysCall::IPC] 的参数，表示接收任一 user app 发送的 IPC 消息
+#define IPC_ANY 0
+
+/// 指 ROOT SERVER
+#define VM_SERVER 1
+
+/// 存储 Service Name 的字符串长度
+#define NAME_LEN 64
+
+/// 路径的最大长度
+#define PATH_LEN 128
+
+/// 块设备中一个块的大小
+#define BLOCK_SIZE 0x200
+
+/// 任务 panic 时的退出码
+#define EXIT_PANIC 101
+
+/// IPC 发送消息
+#define IPC_SEND 0x10000
+
+/// IPC 接收消息
+#define IPC_RECV 0x20000
+
+/// IPC 不阻塞，对方没有准备好时直接返回
+#define IPC_NON_BLOCK 0x40000
+
+/// 由内核发送的消息，用户程序不能使用
+#define IPC_KERNEL 0x80000
+
+/// 不使用快速路径，用于测试对比
+#define IPC_NO_FAST_PATH 0x100000
+
+/// 消息负载的最大长度，目前最大的负载为 [WriteBlockRequest]
+#define MAX_PAYLOAD_LEN 0x208
+
+/// 短消息最多携带的负载字数，负载通过三个参数寄存器传递
+#define SHORT_MSG_WORDS 3
+
+/// 短消息 IPC 的标志位参数中，消息标签从这一位开始
+#define SHORT_TAG_SHIFT 32
+
+/// 系统调用编号
+enum SysCall {
+    /// IPC
+    SYS_CALL_IPC = 1,
+    /// 通知目标任务当前任务有等待它取回的异步消息，当前任务会加入目标任务的待取回队列
+    SYS_CALL_NOTIFY = 2,
+    /// 串口输出
+    SYS_CALL_SERIAL_WRITE = 3,
+    /// 串口读取
+    SYS_CALL_SERIAL_READ = 4,
+    /// 创建任务
+    SYS_CALL_TASK_CREATE = 5,
+    /// 销毁任务
+    SYS_CALL_TASK_DESTORY = 6,
+    /// 退出任务，参数为退出码，退出码通过 GraceExit 异常消息发送给 pager
+    SYS_CALL_TASK_EXIT = 7,
+    /// 获取当前任务
+    SYS_CALL_TASK_SELF = 8,
+    /// 分配物理内存
+    SYS_CALL_PM_ALLOC = 9,
+    /// 映射虚拟内存
+    SYS_CALL_VM_MAP = 10,
+    /// 取消映射虚拟内存
+    SYS_CALL_VM_UNMAP = 11,
+    /// 监听中断
+    SYS_CALL_IRQ_LISTEN = 12,
+    /// 取消监听中断
+    SYS_CALL_IRQ_UNLISTEN = 13,
+    /// 定时器
+    SYS_CALL_TIME = 14,
+    /// 获取当前系统时间
+    SYS_CALL_UP_TIME = 15,
+    /// HinaVM
+    SYS_CALL_HINA_VM = 16,
+    /// 关闭系统
+    SYS_CALL_SHUTDOWN = 17,
+    /// 翻译虚拟页表
+    SYS_CALL_TRANS_V_ADDR = 18,
+    /// 获取任务信息
+    SYS_CALL_TASK_INFO = 19,
+    /// 映射一段连续的虚拟内存
+    SYS_CALL_VM_MAP_RANGE = 20,
+    /// 取消映射一段连续的虚拟内存
+    SYS_CALL_VM_UNMAP_RANGE = 21,
+    /// 创建共享当前地址空间的线程
+    SYS_CALL_THREAD_CREATE = 22,
+    /// 当用户地址中的值等于期望值时等待
+    SYS_CALL_FUTEX_WAIT = 23,
+    /// 唤醒在用户地址上等待的任务
+    SYS_CALL_FUTEX_WAKE = 24,
+    /// 短消息 IPC，消息的标签和负载只通过寄存器传递
+    SYS_CALL_IPC_SHORT = 25,
+    /// 释放物理内存，只有 pager 可以释放，释放前需要取消所有的映射
+    SYS_CALL_PM_FREE = 26,
+    /// 使用内核的随机数填充缓冲区，随机数不能用于密码学用途
+    SYS_CALL_GET_RANDOM = 27,
+    /// 取出待取回队列中下一个有异步消息的任务，队列为空时返回 NotFound
+    SYS_CALL_ASYNC_SENDER = 28,
+};
+
+/// 异常类型
+enum ExceptionType {
+    /// 任务主动退出，异常消息的 uaddr 为退出码
+    EXCEPTION_TYPE_GRACE_EXIT = 0,
+    EXCEPTION_TYPE_INVALID_ADDR = 1,
+    EXCEPTION_TYPE_INVALID_PAGER_REPLY = 2,
+    EXCEPTION_TYPE_ILLEGAL_EXCEPTION = 3,
+    /// 栈溢出，访问了栈下方的保护区域
+    EXCEPTION_TYPE_STACK_OVERFLOW = 4,
+};
+
+/// 消息类型，作为 [MessageHeader] 和短消息的标签
+/// 服务接口中 rpc 的消息类型会追加到这里，编号不能和这里的重复
+enum MessageType {
+    /// 空消息
+    MESSAGE_TYPE_NONE_MSG = 0,
+    MESSAGE_TYPE_EXCEPTION_MSG = 1,
+    MESSAGE_TYPE_PAGE_FAULT_MSG = 2,
+    MESSAGE_TYPE_PAGE_FAULT_REPLY_MSG = 3,
+    MESSAGE_TYPE_NOTIFY_MSG = 4,
+    MESSAGE_TYPE_NOTIFY_IRQ_MSG = 5,
+    MESSAGE_TYPE_NOTIFY_TIMER_MSG = 6,
+    /// 取回服务发送失败的异步消息，回复为原来的消息
+    MESSAGE_TYPE_ASYNC_RECV_MSG = 7,
+    MESSAGE_TYPE_ASYNC_RECV_REPLY_MSG = 8,
+    MESSAGE_TYPE_DESTROY_TASK_MSG = 13,
+    MESSAGE_TYPE_DESTROY_TASK_REPLY_MSG = 14,
+    MESSAGE_TYPE_SERVICE_LOOKUP_MSG = 15,
+    MESSAGE_TYPE_SERVICE_LOOKUP_REPLY_MSG = 16,
+    MESSAGE_TYPE_SERVICE_REGISTER_MSG = 17,
+    MESSAGE_TYPE_SERVICE_REGISTER_REPLY_MSG = 18,
+    /// 监控的任务退出，由 vm 发送给监控的任务，负载为 [TaskDestroyedPayload]
+    MESSAGE_TYPE_TASK_DESTROYED_MSG = 21,
+    MESSAGE_TYPE_VM_MAP_PHYSICAL_MSG = 22,
+    MESSAGE_TYPE_VM_MAP_PHYSICAL_REPLY_MSG = 23,
+    MESSAGE_TYPE_VM_ALLOC_PHYSICAL_MSG = 24,
+    MESSAGE_TYPE_VM_ALLOC_PHYSICAL_REPLY_MSG = 25,
+    MESSAGE_TYPE_NET_OPEN_MSG = 30,
+    MESSAGE_TYPE_NET_OPEN_REPLY_MSG = 31,
+    MESSAGE_TYPE_NET_RECV_MSG = 32,
+    MESSAGE_TYPE_NET_SEND_MSG = 33,
+    MESSAGE_TYPE_NET_SEND_REPLY_MSG = 34,
+    MESSAGE_TYPE_FS_OPEN_MSG = 35,
+    MESSAGE_TYPE_FS_OPEN_REPLY_MSG = 36,
+    MESSAGE_TYPE_FS_CLOSE_MSG = 37,
+    MESSAGE_TYPE_FS_CLOSE_REPLY_MSG = 38,
+    MESSAGE_TYPE_FS_READ_MSG = 39,
+    MESSAGE_TYPE_FS_READ_REPLY_MSG = 40,
+    MESSAGE_TYPE_FS_WRITE_MSG = 41,
+    MESSAGE_TYPE_FS_WRITE_REPLY_MSG = 42,
+    MESSAGE_TYPE_FS_MKFILE_MSG = 45,
+    MESSAGE_TYPE_FS_MKFILE_REPLY_MSG = 46,
+    MESSAGE_TYPE_FS_MKDIR_MSG = 47,
+    MESSAGE_TYPE_FS_MKDIR_REPLY_MSG = 48,
+    MESSAGE_TYPE_FS_DELETE_MSG = 49,
+    MESSAGE_TYPE_FS_DELETE_REPLY_MSG = 50,
+    MESSAGE_TYPE_TCPIP_CONNECT_MSG = 51,
+    MESSAGE_TYPE_TCPIP_CONNECT_REPLY_MSG = 52,
+    MESSAGE_TYPE_TCPIP_CLOSE_MSG = 53,
+    MESSAGE_TYPE_TCPIP_CLOSE_REPLY_MSG = 54,
+    MESSAGE_TYPE_TCPIP_WRITE_MSG = 55,
+    MESSAGE_TYPE_TCPIP_WRITE_REPLY_MSG = 56,
+    MESSAGE_TYPE_TCPIP_READ_MSG = 57,
+    MESSAGE_TYPE_TCPIP_READ_REPLY_MSG = 58,
+    MESSAGE_TYPE_TCPIP_DNS_RESOLVE_MSG = 59,
+    MESSAGE_TYPE_TCPIP_DNS_RESOLVE_REPLY_MSG = 60,
+    MESSAGE_TYPE_TCPIP_DATA_MSG = 61,
+    MESSAGE_TYPE_TCPIP_CLOSEDMSG = 62,
+    /// 错误回复，负载为 [ErrorPayload]
+    MESSAGE_TYPE_ERROR_REPLY_MSG = 65,
+    /// 发送 ping，返回服务回复的值
+    MESSAGE_TYPE_PING_MSG = 9,
+    /// 发送 ping，返回服务回复的值
+    MESSAGE_TYPE_PING_REPLY_MSG = 10,
+    /// 读取一个块
+    MESSAGE_TYPE_READ_BLOCK_MSG = 26,
+    /// 读取一个块
+    MESSAGE_TYPE_READ_BLOCK_REPLY_MSG = 27,
+    /// 写入一个块
+    MESSAGE_TYPE_WRITE_BLOCK_MSG = 28,
+    /// 写入一个块
+    MESSAGE_TYPE_WRITE_BLOCK_REPLY_MSG = 29,
+    /// 获取块设备大小，单位为块
+    MESSAGE_TYPE_CAPACITY_MSG = 63,
+    /// 获取块设备大小，单位为块
+    MESSAGE_TYPE_CAPACITY_REPLY_MSG = 64,
+    /// 读取文件夹中第 `index` 个文件的名称，`num` 为 0 时表示没有更多的文件
+    MESSAGE_TYPE_READ_DIR_MSG = 43,
+    /// 读取文件夹中第 `index` 个文件的名称，`num` 为 0 时表示没有更多的文件
+    MESSAGE_TYPE_READ_DIR_REPLY_MSG = 44,
+    /// 获取文件的大小，单位为字节
+    MESSAGE_TYPE_FILE_SIZE_MSG = 74,
+    /// 获取文件的大小，单位为字节
+    MESSAGE_TYPE_FILE_SIZE_REPLY_MSG = 75,
+    /// 从文件的 `offset` 处读取最多一个块，`len` 为读取的长度，为 0 时表示已经读到文件结尾
+    MESSAGE_TYPE_READ_FILE_MSG = 76,
+    /// 从文件的 `offset` 处读取最多一个块，`len` 为读取的长度，为 0 时表示已经读到文件结尾
+    MESSAGE_TYPE_READ_FILE_REPLY_MSG = 77,
+    /// 从文件系统加载 ELF 文件并创建任务，返回任务 id，文件会一直保留在内存中用于按需分页
+    MESSAGE_TYPE_SPAWN_TASK_MSG = 11,
+    /// 从文件系统加载 ELF 文件并创建任务，返回任务 id，文件会一直保留在内存中用于按需分页
+    MESSAGE_TYPE_SPAWN_TASK_REPLY_MSG = 12,
+    /// 释放通过 VmAllocPhysicalMsg 申请的内存，`uaddr` 和 `size` 需要和申请时一致
+    MESSAGE_TYPE_FREE_MEMORY_MSG = 66,
+    /// 释放通过 VmAllocPhysicalMsg 申请的内存，`uaddr` 和 `size` 需要和申请时一致
+    MESSAGE_TYPE_FREE_MEMORY_REPLY_MSG = 67,
+    /// 取消通过 VmMapPhysicalMsg 映射的物理内存，`uaddr` 和 `size` 需要和映射时一致
+    MESSAGE_TYPE_UNMAP_PHYSICAL_MSG = 68,
+    /// 取消通过 VmMapPhysicalMsg 映射的物理内存，`uaddr` 和 `size` 需要和映射时一致
+    MESSAGE_TYPE_UNMAP_PHYSICAL_REPLY_MSG = 69,
+    /// 查找服务对应的任务 ID，服务还没有注册时返回 NotFound，不会等待注册
+    MESSAGE_TYPE_FIND_SERVICE_MSG = 70,
+    /// 查找服务对应的任务 ID，服务还没有注册时返回 NotFound，不会等待注册
+    MESSAGE_TYPE_FIND_SERVICE_REPLY_MSG = 71,
+    /// 注销服务，只有注册服务的任务可以注销
+    MESSAGE_TYPE_UNREGISTER_SERVICE_MSG = 72,
+    /// 注销服务，只有注册服务的任务可以注销
+    MESSAGE_TYPE_UNREGISTER_SERVICE_REPLY_MSG = 73,
+    /// 监控任务 `tid` 的退出，任务退出时向请求的任务发送一次 TaskDestroyedMsg
+    MESSAGE_TYPE_WATCH_TASKS_MSG = 19,
+    /// 监控任务 `tid` 的退出，任务退出时向请求的任务发送一次 TaskDestroyedMsg
+    MESSAGE_TYPE_WATCH_TASKS_REPLY_MSG = 20,
+};
+
+/// 消息头
+typedef struct MessageHeader {
+    /// 消息类型，见 [MessageType]
+    uintptr_t msg_type;
+    /// 负载的长度
+    uintptr_t len;
+    /// 消息来源任务 ID，发送时由内核填写
+    uintptr_t source;
+} MessageHeader;
+
+/// 固定布局的消息，IPC 系统调用传递的缓冲区就是这个结构
+typedef struct RawMessage {
+    MessageHeader header;
+    /// 消息负载，根据消息类型解释为对应的 Payload 结构
+    uint8_t payload[MAX_PAYLOAD_LEN];
+} RawMessage;
+
+/// 页错误消息
+typedef struct PageFaultPayload {
+    uintptr_t tid;
+    uintptr_t uaddr;
+    uintptr_t ip;
+    /// 页错误原因，见 PageFaultReason
+    uintptr_t fault;
+} PageFaultPayload;
+
+/// 异常消息
+typedef struct ExceptionPayload {
+    uintptr_t tid;
+    /// 异常类型，见 [ExceptionType]
+    uintptr_t exception;
+    uintptr_t uaddr;
+    uintptr_t ip;
+} ExceptionPayload;
+
+/// 通知消息
+typedef struct NotifyPayload {
+    /// 通知集合的 bitset
+    uintptr_t notifications;
+} NotifyPayload;
+
+/// 只携带一个值的消息，例如服务查询回复
+typedef struct ValuePayload {
+    uintptr_t value;
+} ValuePayload;
+
+/// 携带服务名称的消息，名称以 `\0` 结尾
+typedef struct NamePayload {
+    uint8_t name[NAME_LEN];
+} NamePayload;
+
+/// 任务退出消息
+typedef struct TaskDestroyedPayload {
+    uintptr_t tid;
+    /// 退出码，只有任务主动退出时有效
+    uintptr_t exit_code;
+    /// 销毁任务的异常类型，见 ExceptionType，任务主动退出时为 GraceExit
+    uintptr_t exception;
+} TaskDestroyedPayload;
+
+/// 申请内存
+typedef struct VmAllocPayload {
+    uintptr_t size;
+} VmAllocPayload;
+
+/// 申请内存回复
+typedef struct VmAllocReplyPayload {
+    uintptr_t uaddr;
+    uintptr_t paddr;
+} VmAllocReplyPayload;
+
+/// 映射物理内存
+typedef struct VmMapPayload {
+    uintptr_t paddr;
+    uintptr_t size;
+    uintptr_t map_flags;
+} VmMapPayload;
+
+/// 映射物理内存回复
+typedef struct VmMapReplyPayload {
+    uintptr_t uaddr;
+} VmMapReplyPayload;
+
+/// 错误回复，服务处理请求失败或者不支持请求时回复
+typedef struct ErrorPayload {
+    /// 错误码，见 SysCallError
+    intptr_t code;
+} ErrorPayload;
+
+/// 发送 ping，返回服务回复的值
+typedef struct PingRequest {
+    uintptr_t value;
+} PingRequest;
+
+/// 发送 ping，返回服务回复的值
+typedef struct PingReply {
+    uintptr_t value;
+} PingReply;
+
+/// 读取一个块
+typedef struct ReadBlockRequest {
+    uintptr_t block_index;
+} ReadBlockRequest;
+
+/// 读取一个块
+typedef struct ReadBlockReply {
+    uint8_t buffer[BLOCK_SIZE];
+} ReadBlockReply;
+
+/// 写入一个块
+typedef struct WriteBlockRequest {
+    uintptr_t block_index;
+    uint8_t buffer[BLOCK_SIZE];
+} WriteBlockRequest;
+
+/// 获取块设备大小，单位为块
+typedef struct CapacityReply {
+    uintptr_t blocks;
+} CapacityReply;
+
+/// 读取文件夹中第 `index` 个文件的名称，`num` 为 0 时表示没有更多的文件
+typedef struct ReadDirRequest {
+    uint8_t path[PATH_LEN];
+    uintptr_t index;
+} ReadDirRequest;
+
+/// 读取文件夹中第 `index` 个文件的名称，`num` 为 0 时表示没有更多的文件
+typedef struct ReadDirReply {
+    uint8_t buffer[PATH_LEN];
+    uintptr_t num;
+} ReadDirReply;
+
+/// 获取文件的大小，单位为字节
+typedef struct FileSizeRequest {
+    uint8_t path[PATH_LEN];
+} FileSizeRequest;
+
+/// 获取文件的大小，单位为字节
+typedef struct FileSizeReply {
+    uintptr_t size;
+} FileSizeReply;
+
+/// 从文件的 `offset` 处读取最多一个块，`len` 为读取的长度，为 0 时表示已经读到文件结尾
+typedef struct ReadFileRequest {
+    uint8_t path[PATH_LEN];
+    uintptr_t offset;
+} ReadFileRequest;
+
+/// 从文件的 `offset` 处读取最多一个块，`len` 为读取的长度，为 0 时表示已经读到文件结尾
+typedef struct ReadFileReply {
+    uint8_t buffer[BLOCK_SIZE];
+    uintptr_t len;
+} ReadFileReply;
+
+/// 从文件系统加载 ELF 文件并创建任务，返回任务 id，文件会一直保留在内存中用于按需分页
+typedef struct SpawnTaskRequest {
+    uint8_t path[PATH_LEN];
+} SpawnTaskRequest;
+
+/// 从文件系统加载 ELF 文件并创建任务，返回任务 id，文件会一直保留在内存中用于按需分页
+typedef struct SpawnTaskReply {
+    uintptr_t tid;
+} SpawnTaskReply;
+
+/// 释放通过 VmAllocPhysicalMsg 申请的内存，`uaddr` 和 `size` 需要和申请时一致
+typedef struct FreeMemoryRequest {
+    uintptr_t uaddr;
+    uintptr_t size;
+} FreeMemoryRequest;
+
+/// 取消通过 VmMapPhysicalMsg 映射的物理内存，`uaddr` 和 `size` 需要和映射时一致
+typedef struct UnmapPhysicalRequest {
+    uintptr_t uaddr;
+    uintptr_t size;
+} UnmapPhysicalRequest;
+
+/// 查找服务对应的任务 ID，服务还没有注册时返回 NotFound，不会等待注册
+typedef struct FindServiceRequest {
+    uint8_t name[NAME_LEN];
+} FindServiceRequest;
+
+/// 查找服务对应的任务 ID，服务还没有注册时返回 NotFound，不会等待注册
+typedef struct FindServiceReply {
+    uintptr_t tid;
+} FindServiceReply;
+
+/// 注销服务，只有注册服务的任务可以注销
+typedef struct UnregisterServiceRequest {
+    uint8_t name[NAME_LEN];
+} UnregisterServiceRequest;
+
+/// 监控任务 `tid` 的退出，任务退出时向请求的任务发送一次 TaskDestroyedMsg
+typedef struct WatchTasksRequest {
+    uintptr_t tid;
+} WatchTasksRequest;
+
+#endif // SYSCALL_CONSTS_H
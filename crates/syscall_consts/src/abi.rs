@@ -0,0 +1,262 @@
+//! 固定布局的消息 ABI，类型定义由 build.rs 从 messages.idl 生成
+//! IPC 系统调用实际传递的是 [RawMessage]，[Message] 在发送前转换为 [RawMessage]，接收后再转换回来
+
+use alloc::boxed::Box;
+use core::mem::size_of;
+
+use crate::{ExitStatus, Message, MessageContent, Notify, PageFaultReason, SysCallError};
+
+include!(concat!(env!("OUT_DIR"), "/abi.rs"));
+
+/// 可以作为 [RawMessage] 负载的结构
+///
+/// # Safety
+///
+/// 实现这个 trait 的类型必须是 `#[repr(C)]` 的，并且任意字节都是合法的值
+/// IDL 中的 `payload struct` 会自动生成实现
+pub unsafe trait Payload: Copy {}
+
+impl RawMessage {
+    /// 创建空消息
+    pub const fn blank() -> Self {
+        RawMessage {
+            header: MessageHeader {
+                msg_type: MessageType::NoneMsg as usize,
+                len: 0,
+                source: 0,
+            },
+            payload: [0; MAX_PAYLOAD_LEN],
+        }
+    }
+
+    /// 创建没有负载的消息
+    pub fn empty(msg_type: MessageType) -> Self {
+        let mut raw = Self::blank();
+        raw.header.msg_type = msg_type.into();
+        raw
+    }
+
+    /// 创建携带 `payload` 的消息
+    pub fn new<T: Payload>(msg_type: MessageType, payload: T) -> Self {
+        assert!(size_of::<T>() <= MAX_PAYLOAD_LEN, "payload is too large");
+        let mut raw = Self::empty(msg_type);
+        raw.header.len = size_of::<T>();
+        unsafe { (raw.payload.as_mut_ptr() as *mut T).write_unaligned(payload) };
+        raw
+    }
+
+    /// 获取消息类型，类型无效时返回 None
+    pub fn msg_type(&self) -> Option<MessageType> {
+        MessageType::try_from(self.header.msg_type).ok()
+    }
+
+    /// 将负载解释为 `T`，负载长度不匹配时返回 None
+    pub fn payload<T: Payload>(&self) -> Option<T> {
+        if self.header.len != size_of::<T>() || size_of::<T>() > MAX_PAYLOAD_LEN {
+            return None;
+        }
+        Some(unsafe { (self.payload.as_ptr() as *const T).read_unaligned() })
+    }
+
+    /// 没有负载的消息，负载长度不为 0 时返回 None
+    fn no_payload(&self, content: MessageContent) -> Option<MessageContent> {
+        (self.header.len == 0).then_some(content)
+    }
+
+    /// 转换为消息内容，消息类型无效或者负载不匹配时返回 None
+    /// 没有对应 [MessageContent] 的消息类型转换为 [MessageContent::Raw]
+    pub fn content(&self) -> Option<MessageContent> {
+        let content = match self.msg_type()? {
+            MessageType::NoneMsg => self.no_payload(MessageContent::None)?,
+            MessageType::PageFaultMsg => {
+                let payload: PageFaultPayload = self.payload()?;
+                MessageContent::PageFault {
+                    tid: payload.tid,
+                    uaddr: payload.uaddr,
+                    ip: payload.ip,
+                    fault: PageFaultReason::from_bits(payload.fault)?,
+                }
+            }
+            MessageType::PageFaultReplyMsg => self.no_payload(MessageContent::PageFaultReply)?,
+            MessageType::ExceptionMsg => {
+                let payload: ExceptionPayload = self.payload()?;
+                MessageContent::ExceptionMsg {
+                    tid: payload.tid,
+                    exception: ExceptionType::try_from(payload.exception).ok()?,
+                    uaddr: payload.uaddr,
+                    ip: payload.ip,
+                }
+            }
+            MessageType::NotifyMsg => MessageContent::NotifyField {
+                notications: Notify::from_bits(self.payload::<NotifyPayload>()?.notifications),
+            },
+            MessageType::NotifyIrqMsg => self.no_payload(MessageContent::NotifyIRQ)?,
+            MessageType::NotifyTimerMsg => self.no_payload(MessageContent::NotifyTimer)?,
+            MessageType::ServiceRegisterMsg => MessageContent::ServiceRegisterMsg {
+                name_buffer: self.payload::<NamePayload>()?.name,
+            },
+            MessageType::ServiceRegisterReplyMsg => {
+                self.no_payload(MessageContent::ServiceRegisterReplyMsg)?
+            }
+            MessageType::ServiceLookupMsg => MessageContent::ServiceLookupMsg {
+                name_buffer: self.payload::<NamePayload>()?.name,
+            },
+            MessageType::ServiceLookupReplyMsg => {
+                MessageContent::ServiceLookupReplyMsg(self.payload::<ValuePayload>()?.value)
+            }
+            MessageType::TaskDestroyedMsg => {
+                let payload: TaskDestroyedPayload = self.payload()?;
+                let status = match ExceptionType::try_from(payload.exception).ok()? {
+                    ExceptionType::GraceExit => ExitStatus::Exited(payload.exit_code),
+                    exception => ExitStatus::Killed(exception),
+                };
+                MessageContent::TaskDestroyedMsg {
+                    tid: payload.tid,
+                    status,
+                }
+            }
+            MessageType::VmAllocPhysicalMsg => MessageContent::VmAllocPhysicalMsg {
+                size: self.payload::<VmAllocPayload>()?.size,
+            },
+            MessageType::VmAllocPhysicalReplyMsg => {
+                let payload: VmAllocReplyPayload = self.payload()?;
+                MessageContent::VmAllocPhysicalReplyMsg {
+                    uaddr: payload.uaddr,
+                    paddr: payload.paddr,
+                }
+            }
+            MessageType::VmMapPhysicalMsg => {
+                let payload: VmMapPayload = self.payload()?;
+                MessageContent::VmMapPhysicalMsg {
+                    paddr: payload.paddr,
+                    size: payload.size,
+                    map_flags: payload.map_flags,
+                }
+            }
+            MessageType::VmMapPhysicalReplyMsg => MessageContent::VmMapPhysicalReplyMsg {
+                uaddr: self.payload::<VmMapReplyPayload>()?.uaddr,
+            },
+            // 其他消息由接收者自己解析负载
+            _ => MessageContent::raw(*self),
+        };
+        Some(content)
+    }
+}
+
+impl MessageContent {
+    /// 不解析负载，直接作为 [MessageContent::Raw] 保存
+    pub fn raw(raw: RawMessage) -> Self {
+        MessageContent::Raw(Box::new(raw))
+    }
+}
+
+impl From<&Message> for RawMessage {
+    fn from(message: &Message) -> Self {
+        let value = |value| ValuePayload { value };
+        let mut raw = match message.content {
+            MessageContent::PageFault {
+                tid,
+                uaddr,
+                ip,
+                fault,
+            } => RawMessage::new(
+                MessageType::PageFaultMsg,
+                PageFaultPayload {
+                    tid,
+                    uaddr,
+                    ip,
+                    fault: fault.bits(),
+                },
+            ),
+            MessageContent::PageFaultReply => RawMessage::empty(MessageType::PageFaultReplyMsg),
+            MessageContent::ExceptionMsg {
+                tid,
+                exception,
+                uaddr,
+                ip,
+            } => RawMessage::new(
+                MessageType::ExceptionMsg,
+                ExceptionPayload {
+                    tid,
+                    exception: exception.into(),
+                    uaddr,
+                    ip,
+                },
+            ),
+            MessageContent::NotifyField { notications } => RawMessage::new(
+                MessageType::NotifyMsg,
+                NotifyPayload {
+                    notifications: notications.bits(),
+                },
+            ),
+            MessageContent::NotifyIRQ => RawMessage::empty(MessageType::NotifyIrqMsg),
+            MessageContent::NotifyTimer => RawMessage::empty(MessageType::NotifyTimerMsg),
+            MessageContent::ServiceRegisterMsg { name_buffer } => RawMessage::new(
+                MessageType::ServiceRegisterMsg,
+                NamePayload { name: name_buffer },
+            ),
+            MessageContent::ServiceRegisterReplyMsg => {
+                RawMessage::empty(MessageType::ServiceRegisterReplyMsg)
+            }
+            MessageContent::ServiceLookupMsg { name_buffer } => RawMessage::new(
+                MessageType::ServiceLookupMsg,
+                NamePayload { name: name_buffer },
+            ),
+            MessageContent::ServiceLookupReplyMsg(tid) => {
+                RawMessage::new(MessageType::ServiceLookupReplyMsg, value(tid))
+            }
+            MessageContent::TaskDestroyedMsg { tid, status } => {
+                let (exit_code, exception) = match status {
+                    ExitStatus::Exited(exit_code) => (exit_code, ExceptionType::GraceExit),
+                    ExitStatus::Killed(exception) => (0, exception),
+                };
+                RawMessage::new(
+                    MessageType::TaskDestroyedMsg,
+                    TaskDestroyedPayload {
+                        tid,
+                        exit_code,
+                        exception: exception.into(),
+                    },
+                )
+            }
+            MessageContent::VmAllocPhysicalMsg { size } => {
+                RawMessage::new(MessageType::VmAllocPhysicalMsg, VmAllocPayload { size })
+            }
+            MessageContent::VmAllocPhysicalReplyMsg { uaddr, paddr } => RawMessage::new(
+                MessageType::VmAllocPhysicalReplyMsg,
+                VmAllocReplyPayload { uaddr, paddr },
+            ),
+            MessageContent::VmMapPhysicalMsg {
+                paddr,
+                size,
+                map_flags,
+            } => RawMessage::new(
+                MessageType::VmMapPhysicalMsg,
+                VmMapPayload {
+                    paddr,
+                    size,
+                    map_flags,
+                },
+            ),
+            MessageContent::VmMapPhysicalReplyMsg { uaddr } => RawMessage::new(
+                MessageType::VmMapPhysicalReplyMsg,
+                VmMapReplyPayload { uaddr },
+            ),
+            MessageContent::Raw(ref raw) => **raw,
+            MessageContent::None => RawMessage::blank(),
+        };
+        raw.header.source = message.source;
+        raw
+    }
+}
+
+impl TryFrom<&RawMessage> for Message {
+    type Error = SysCallError;
+
+    fn try_from(raw: &RawMessage) -> Result<Self, Self::Error> {
+        Ok(Message {
+            source: raw.header.source,
+            content: raw.content().ok_or(SysCallError::InvalidArg)?,
+        })
+    }
+}
@@ -0,0 +1,99 @@
+//! 启动镜像 (boot image) 的格式
+//! 启动镜像是 ustar 格式的归档文件，包含 root server、其他服务的 ELF 文件和启动清单 [BOOT_MANIFEST]。
+//! 内核从镜像中找到 [ROOT_SERVER_NAME] 作为 root server 运行，并将整个镜像只读映射到 root server 的地址空间，
+//! 映射的地址和大小通过 root server 启动信息中的环境变量 [BOOT_IMAGE_ADDR_ENV] 和 [BOOT_IMAGE_SIZE_ENV] 传递
+
+/// 启动镜像中 root server 的文件名
+pub const ROOT_SERVER_NAME: &str = "vm";
+
+/// 启动镜像中启动清单的文件名
+pub const BOOT_MANIFEST: &str = "manifest";
+
+/// 保存启动镜像地址的环境变量，值为 `0x` 开头的十六进制数
+pub const BOOT_IMAGE_ADDR_ENV: &str = "boot_image_addr";
+
+/// 保存启动镜像大小的环境变量，值为 `0x` 开头的十六进制数
+pub const BOOT_IMAGE_SIZE_ENV: &str = "boot_image_size";
+
+/// ustar 的块大小，文件头和文件内容都按照块对齐
+const BLOCK_SIZE: usize = 512;
+
+/// 启动镜像中的文件
+#[derive(Debug, Clone, Copy)]
+pub struct BootFile<'a> {
+    /// 文件名，不包含开头的 `./`
+    pub name: &'a str,
+    /// 文件内容
+    pub data: &'a [u8],
+}
+
+/// 启动镜像，只读取其中的普通文件
+#[derive(Debug, Clone, Copy)]
+pub struct BootImage<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BootImage<'a> {
+    /// 使用启动镜像的内容创建
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// 遍历启动镜像中的文件
+    pub fn files(&self) -> BootFiles<'a> {
+        BootFiles {
+            data: self.data,
+            offset: 0,
+        }
+    }
+
+    /// 查找名称为 `name` 的文件
+    pub fn find(&self, name: &str) -> Option<&'a [u8]> {
+        self.files().find(|x| x.name == name).map(|x| x.data)
+    }
+}
+
+/// 启动镜像中文件的迭代器，遇到结束块或者格式错误的文件头时结束
+pub struct BootFiles<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+/// 读取以 `\0` 结尾的字符串
+fn header_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|x| *x == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// 读取八进制的数字，允许以空格或者 `\0` 结尾
+fn header_octal(bytes: &[u8]) -> Option<usize> {
+    let digits = header_str(bytes)?.trim_matches(' ');
+    match digits.is_empty() {
+        true => Some(0),
+        false => usize::from_str_radix(digits, 8).ok(),
+    }
+}
+
+impl<'a> Iterator for BootFiles<'a> {
+    type Item = BootFile<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self.data.get(self.offset..self.offset + BLOCK_SIZE)?;
+            // 全 0 的块表示归档结束，magic 不正确时不再继续读取
+            if header[0] == 0 || &header[257..262] != b"ustar" {
+                return None;
+            }
+            let name = header_str(&header[0..100])?;
+            let size = header_octal(&header[124..136])?;
+            let start = self.offset + BLOCK_SIZE;
+            let data = self.data.get(start..start + size)?;
+            self.offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+            // 只返回普通文件，跳过目录和链接等
+            if matches!(header[156], b'0' | 0) {
+                let name = name.strip_prefix("./").unwrap_or(name);
+                return Some(BootFile { name, data });
+            }
+        }
+    }
+}
@@ -3,64 +3,30 @@
 
 extern crate alloc;
 
+mod abi;
+mod boot_image;
+
+pub use abi::*;
+pub use boot_image::*;
+
+use alloc::boxed::Box;
 use core::{
+    fmt,
     mem::size_of,
     ops::{BitOr, BitOrAssign},
 };
 
 use bitflags::bitflags;
-use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
+use num_enum::{FromPrimitive, IntoPrimitive};
 
 /// 一个宏，根据参数来表示 bit 位
 pub macro bit($x:expr) {
     (1 << ($x))
 }
 
-/// 系统调用编号
-#[derive(Debug, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
-#[repr(usize)]
-pub enum SysCall {
-    /// IPC
-    IPC = 1,
-    /// 通知
-    Notify = 2,
-    /// 串口输出
-    SerialWrite = 3,
-    /// 串口读取
-    SerialRead = 4,
-    /// 创建任务
-    TaskCreate = 5,
-    /// 销毁任务
-    TaskDestory = 6,
-    /// 退出任务
-    TaskExit = 7,
-    /// 获取当前任务
-    TaskSelf = 8,
-    /// 分配物理内存
-    PMAlloc = 9,
-    /// 映射虚拟内存
-    VMMap = 10,
-    /// 取消映射虚拟内存
-    VMUnmap = 11,
-    /// 监听中断
-    IrqListen = 12,
-    /// 取消监听中断
-    IrqUnlisten = 13,
-    /// 定时器
-    Time = 14,
-    /// 获取当前系统时间
-    UPTime = 15,
-    /// HinaVM
-    HinaVM = 16,
-    /// 关闭系统
-    Shutdown = 17,
-    /// 翻译虚拟页表
-    TransVAddr = 18,
-}
-
 /// 系统调用的错误
 #[repr(isize)]
-#[derive(Debug, Clone, Copy, IntoPrimitive, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, FromPrimitive)]
 pub enum SysCallError {
     NoMemory = -1,        // 内存不足
     NoResources = -2,     // 没有足够的资源
@@ -89,76 +55,79 @@ pub enum SysCallError {
     NotAFile = -25,       // 不是一个文件
     NotADir = -26,        // 不是目录
     EOF = -27,            // 文件数据结束
-    END = -28,            // 必须是最后一个错误码
+    TimedOut = -28,       // 等待超时
+    END = -29,            // 必须是最后一个错误码
     #[default]
-    Others = -29,
+    Others = -30,
 }
 
-/// 消息类型，暂时用不上
-/// Rust 可以用 enum 来同时表示消息类型和内容
-pub enum MessageType {
-    ExceptionMsg = 1,
-    PageFaultMsg = 2,
-    PageFaultReplyMsg = 3,
-    NotifyMsg = 4,
-    NotifyIrqMsg = 5,
-    NotifyTimerMsg = 6,
-    AsyncRecvMsg = 7,
-    AsyncRecvReplyMsg = 8,
-    PingMsg = 9,
-    PingReplyMsg = 10,
-    SpawnTaskMsg = 11,
-    SpawnTaskReplyMsg = 12,
-    DestroyTaskMsg = 13,
-    DestroyTaskReplyMsg = 14,
-    ServiceLookupMsg = 15,
-    ServiceLookupReplyMsg = 16,
-    ServiceRegisterMsg = 17,
-    ServiceRegisterReplyMsg = 18,
-    WatchTasksMsg = 19,
-    WatchTasksReplyMsg = 20,
-    TaskDestroyedMsg = 21,
-    VmMapPhysicalMsg = 22,
-    VmMapPhysicalReplyMsg = 23,
-    VmAllocPhysicalMsg = 24,
-    VmAllocPhysicalReplyMsg = 25,
-    BlkReadMsg = 26,
-    BlkReadReplyMsg = 27,
-    BlkWriteMsg = 28,
-    BlkWriteReplyMsg = 29,
-    NetOpenMsg = 30,
-    NetOpenReplyMsg = 31,
-    NetRecvMsg = 32,
-    NetSendMsg = 33,
-    NetSendReplyMsg = 34,
-    FsOpenMsg = 35,
-    FsOpenReplyMsg = 36,
-    FsCloseMsg = 37,
-    FsCloseReplyMsg = 38,
-    FsReadMsg = 39,
-    FsReadReplyMsg = 40,
-    FsWriteMsg = 41,
-    FsWriteReplyMsg = 42,
-    FsReaddirMsg = 43,
-    FsReaddirReplyMsg = 44,
-    FsMkfileMsg = 45,
-    FsMkfileReplyMsg = 46,
-    FsMkdirMsg = 47,
-    FsMkdirReplyMsg = 48,
-    FsDeleteMsg = 49,
-    FsDeleteReplyMsg = 50,
-    TcpipConnectMsg = 51,
-    TcpipConnectReplyMsg = 52,
-    TcpipCloseMsg = 53,
-    TcpipCloseReplyMsg = 54,
-    TcpipWriteMsg = 55,
-    TcpipWriteReplyMsg = 56,
-    TcpipReadMsg = 57,
-    TcpipReadReplyMsg = 58,
-    TcpipDnsResolveMsg = 59,
-    TcpipDnsResolveReplyMsg = 60,
-    TcpipDataMsg = 61,
-    TcpipClosedmsg = 62,
+impl SysCallError {
+    /// 错误的描述信息
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            SysCallError::NoMemory => "out of memory",
+            SysCallError::NoResources => "no resources available",
+            SysCallError::AlreadyExists => "already exists",
+            SysCallError::AlreadyUsed => "already in use",
+            SysCallError::AlreadyDone => "already done",
+            SysCallError::StillUsed => "still in use",
+            SysCallError::NotFound => "not found",
+            SysCallError::NotAllowed => "not allowed",
+            SysCallError::NotSupported => "not supported",
+            SysCallError::Unexpected => "unexpected input or state",
+            SysCallError::InvalidArg => "invalid argument",
+            SysCallError::InvalidTask => "invalid task id",
+            SysCallError::InvalidSyscall => "invalid syscall number",
+            SysCallError::InvalidPaddr => "invalid physical address",
+            SysCallError::InvalidUaddr => "invalid user address",
+            SysCallError::TooManyTasks => "too many tasks",
+            SysCallError::TooLarge => "too large",
+            SysCallError::TooSmall => "too small",
+            SysCallError::WouldBlock => "operation would block",
+            SysCallError::TryAgain => "temporarily unavailable, try again",
+            SysCallError::Aborted => "aborted",
+            SysCallError::Empty => "empty",
+            SysCallError::NotEmpty => "not empty",
+            SysCallError::DeadLock => "deadlock detected",
+            SysCallError::NotAFile => "not a file",
+            SysCallError::NotADir => "not a directory",
+            SysCallError::EOF => "end of file",
+            SysCallError::TimedOut => "timed out",
+            SysCallError::END => "end of error codes",
+            SysCallError::Others => "unknown error",
+        }
+    }
+}
+
+/// 任务的退出状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// 任务主动退出，携带退出码
+    Exited(usize),
+    /// 任务因为异常被销毁
+    Killed(ExceptionType),
+}
+
+impl ExitStatus {
+    /// 任务是否以退出码 0 正常退出
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Exited(0)
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(exit_code) => write!(f, "exited with code {:#x}", exit_code),
+            ExitStatus::Killed(exception) => write!(f, "killed by exception {:?}", exception),
+        }
+    }
+}
+
+impl fmt::Display for SysCallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.as_str(), *self as isize)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -186,7 +155,8 @@ impl Notify {
                     0 => return Some(NotifyEnum::TIMER),
                     1 => return Some(NotifyEnum::IRQ),
                     2 => return Some(NotifyEnum::ABORTED),
-                    _ => return Some(NotifyEnum::ASYNC(i as u8 - 3)),
+                    3 => return Some(NotifyEnum::ASYNC),
+                    _ => continue,
                 }
             }
         }
@@ -200,7 +170,7 @@ impl Notify {
             NotifyEnum::TIMER => 0,
             NotifyEnum::IRQ => 1,
             NotifyEnum::ABORTED => 2,
-            NotifyEnum::ASYNC(tid) => 3 + tid as usize,
+            NotifyEnum::ASYNC => 3,
         };
         match self.0 & bit!(index) != 0 {
             // 含有特定的 Notification
@@ -216,6 +186,16 @@ impl Notify {
     pub fn is_empty(&self) -> bool {
         self.0 == 0
     }
+
+    /// 获取通知集合的 bitset
+    pub const fn bits(&self) -> usize {
+        self.0
+    }
+
+    /// 从 bitset 创建通知集合
+    pub const fn from_bits(bits: usize) -> Self {
+        Notify(bits)
+    }
 }
 
 /// 重载 | 运算符
@@ -244,7 +224,8 @@ pub enum NotifyEnum {
     /// 中断通知
     IRQ,
     ABORTED,
-    ASYNC(u8),
+    /// 有任务在等待当前任务取回异步消息，通过 [SysCall::AsyncSender] 逐个获取这些任务
+    ASYNC,
 }
 
 impl From<NotifyEnum> for Notify {
@@ -253,22 +234,37 @@ impl From<NotifyEnum> for Notify {
             NotifyEnum::TIMER => Notify(bit!(0)),
             NotifyEnum::IRQ => Notify(bit!(1)),
             NotifyEnum::ABORTED => Notify(bit!(2)),
-            NotifyEnum::ASYNC(tid) => Notify(bit!(3 + tid as usize)),
+            NotifyEnum::ASYNC => Notify(bit!(3)),
         }
     }
 }
 
-/// 一般用在 [SysCall::IPC] 的参数，表示接收任一 user app 发送的 IPC 消息
-pub const IPC_ANY: usize = 0;
-
 /// 一般用在 [Message::source] 表示从内核发送的任务
 pub const FROM_KERNEL: usize = usize::MAX;
 
-/// 指 ROOT SERVER
-pub const VM_SERVER: usize = 1;
+/// 2MB 大页的大小
+pub const HUGE_PAGE_2M: usize = 0x20_0000;
 
-/// 存储 Service Name 的字符串长度
-pub const NAME_LEN: usize = 64;
+/// 1GB 大页的大小
+pub const HUGE_PAGE_1G: usize = 0x4000_0000;
+
+/// 栈下方保护区域的大小，这块区域不会被映射，访问时产生 [ExceptionType::StackOverflow]
+pub const STACK_GUARD_SIZE: usize = 0x1_0000;
+
+/// 栈区域的大小，一个地址空间中所有线程的栈都位于栈顶下方的这块区域中
+pub const STACK_AREA_SIZE: usize = 0x1000_0000;
+
+/// 用户地址空间的上限，x86_64 使用 4 级页表的低半部分
+#[cfg(target_arch = "x86_64")]
+pub const USER_SPACE_END: usize = 0x8000_0000_0000;
+
+/// 用户地址空间的上限，aarch64 的用户地址空间为 48 位
+#[cfg(target_arch = "aarch64")]
+pub const USER_SPACE_END: usize = 0x1_0000_0000_0000;
+
+/// 用户地址空间的上限，riscv64 (sv39) 和 loongarch64 使用 3 级页表
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub const USER_SPACE_END: usize = 0x40_0000_0000;
 
 /// 消息内容，这是一个 Rust 的 enum 结构
 /// 后续可以在这个里面添加消息结构以增加消息的类型。
@@ -283,14 +279,18 @@ pub enum MessageContent {
     },
     /// 页错误回复消息
     PageFaultReply,
+    /// 异常消息，由内核发送给 pager，发送后任务会被销毁
+    /// 任务主动退出时异常类型为 [ExceptionType::GraceExit]，`uaddr` 为退出码
+    ExceptionMsg {
+        tid: usize,
+        exception: ExceptionType,
+        uaddr: usize,
+        ip: usize,
+    },
     /// 通知消息
     NotifyField {
         notications: Notify,
     },
-    /// Ping
-    PingMsg(usize),
-    /// pong
-    PingReplyMsg(usize),
     /// 中断
     NotifyIRQ,
     /// 定时器
@@ -307,6 +307,11 @@ pub enum MessageContent {
     },
     /// 服务注册消息回复，携带任务 id
     ServiceLookupReplyMsg(usize),
+    /// 监控的任务退出，携带任务 id 和退出码
+    TaskDestroyedMsg {
+        tid: usize,
+        status: ExitStatus,
+    },
     /// 申请内存
     VmAllocPhysicalMsg {
         size: usize,
@@ -326,37 +331,9 @@ pub enum MessageContent {
     VmMapPhysicalReplyMsg {
         uaddr: usize,
     },
-    /// 读取块消息
-    ReadBlockMsg {
-        block_index: usize,
-    },
-    /// 读取块回复消息
-    ReadBlockReplyMsg {
-        buffer: [u8; 0x200],
-    },
-    /// 写块消息
-    WriteBlockMsg {
-        block_index: usize,
-        buffer: [u8; 0x200],
-    },
-    /// 写块回复消息
-    WriteBlockReplyMsg,
-    /// 获取块设备大小
-    GetBlockCapacity,
-    /// 获取块设备大小回复消息, 单位 BLOCK_SIZE
-    GetBlockCapacityReplyMsg(usize),
-    /// 读取文件目录消息
-    FSReadDirMsg {
-        path: [u8; 2 * NAME_LEN],
-        /// 已经读取的数量
-        index: usize,
-    },
-    /// 读取文件目录回复消息
-    FSReadDirReplyMsg {
-        buffer: [u8; 2 * NAME_LEN],
-        /// 本次读取成功的数量
-        num: usize,
-    },
+    /// 没有对应结构的消息，例如服务接口中的 rpc 请求和回复，由服务自己解析
+    /// RawMessage 比其他消息大很多，放在堆上避免每个 [Message] 都占用完整的负载空间
+    Raw(Box<RawMessage>),
     None,
 }
 
@@ -379,14 +356,83 @@ impl Message {
     }
 }
 
+/// 短消息，标签和负载都通过寄存器传递，不需要访问用户内存
+/// 负载不超过 [SHORT_MSG_WORDS] 个字的 [RawMessage] 都可以用短消息表示
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShortMessage {
+    pub source: usize,
+    /// 消息类型，见 [MessageType]
+    pub label: usize,
+    /// 负载的长度 (单位: 字节)
+    pub len: usize,
+    pub words: [usize; SHORT_MSG_WORDS],
+}
+
+impl ShortMessage {
+    /// 消息类型和负载长度合并成的标签，消息类型位于低 16 位
+    pub fn tag(&self) -> usize {
+        self.label | self.len << 16
+    }
+
+    /// 根据 [ShortMessage::tag] 得到的标签和负载创建短消息
+    pub fn from_tag(source: usize, tag: usize, words: [usize; SHORT_MSG_WORDS]) -> Self {
+        ShortMessage {
+            source,
+            label: tag & 0xffff,
+            len: tag >> 16,
+            words,
+        }
+    }
+
+    /// 将 [RawMessage] 转换为短消息，负载太长时返回 None
+    pub fn from_raw(raw: &RawMessage) -> Option<Self> {
+        if raw.header.len > size_of::<[usize; SHORT_MSG_WORDS]>() || raw.header.msg_type > 0xffff {
+            return None;
+        }
+        let mut words = [0; SHORT_MSG_WORDS];
+        words.iter_mut().enumerate().for_each(|(i, word)| {
+            let offset = i * size_of::<usize>();
+            *word = usize::from_ne_bytes(
+                raw.payload[offset..offset + size_of::<usize>()]
+                    .try_into()
+                    .unwrap(),
+            );
+        });
+        Some(ShortMessage {
+            source: raw.header.source,
+            label: raw.header.msg_type,
+            len: raw.header.len,
+            words,
+        })
+    }
+
+    /// 转换为 [RawMessage]，负载长度超过 [SHORT_MSG_WORDS] 个字时返回 None
+    pub fn to_raw(&self) -> Option<RawMessage> {
+        if self.len > size_of::<[usize; SHORT_MSG_WORDS]>() {
+            return None;
+        }
+        let mut raw = RawMessage::blank();
+        raw.header.msg_type = self.label;
+        raw.header.len = self.len;
+        raw.header.source = self.source;
+        self.words.iter().enumerate().for_each(|(i, word)| {
+            let offset = i * size_of::<usize>();
+            raw.payload[offset..offset + size_of::<usize>()].copy_from_slice(&word.to_ne_bytes());
+        });
+        Some(raw)
+    }
+}
+
 bitflags! {
     /// IPC 标志位
     #[derive(Debug, Clone, Copy)]
     pub struct IPCFlags: usize {
-        const SEND      =  bit!(16);
-        const RECV      =  bit!(17);
-        const NON_BLOCK =  bit!(18);
-        const KERNEL    =  bit!(19);
+        const SEND      =  IPC_SEND;
+        const RECV      =  IPC_RECV;
+        const NON_BLOCK =  IPC_NON_BLOCK;
+        const KERNEL    =  IPC_KERNEL;
+        /// 不使用快速路径，消息总是经过目的任务的消息暂存区转发，用于测试对比
+        const NO_FAST_PATH = IPC_NO_FAST_PATH;
         const CALL      = Self::SEND.bits() | Self::RECV.bits();
     }
 
@@ -405,15 +451,62 @@ bitflags! {
     pub struct PMAllocFlags: usize {
         const UNINITIALIZED = bit!(0);
         const ZEROD         = bit!(1);
+        /// 物理地址按照申请的大小对齐，最大对齐到 [HUGE_PAGE_1G]，便于使用大页映射
         const ALIGNED       = bit!(2);
     }
+
+    /// 映射内存 Flags，至少需要设置 READ、WRITE 和 EXEC 中的一个
+    #[derive(Debug, Clone, Copy)]
+    pub struct VMMapFlags: usize {
+        /// 可读
+        const READ      = bit!(0);
+        /// 可写，可写的页同时也是可读的
+        const WRITE     = bit!(1);
+        /// 可执行
+        const EXEC      = bit!(2);
+        /// 使用 2MB 大页映射，虚拟地址和物理地址都需要按照 [HUGE_PAGE_2M] 对齐
+        const HUGE_2M   = bit!(8);
+        /// 使用 1GB 大页映射，虚拟地址和物理地址都需要按照 [HUGE_PAGE_1G] 对齐
+        const HUGE_1G   = bit!(9);
+    }
 }
 
-/// 异常类型
-#[derive(Debug, Clone, Copy)]
-pub enum ExceptionType {
-    GraceExit,
-    InvalidAddr,
-    InvalidPagerReply,
-    IllegalException,
+/// 任务信息，由 [SysCall::TaskInfo] 填充
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaskInfo {
+    /// 任务 ID
+    pub tid: usize,
+    /// 页表代理任务 ID，没有 pager 时为 0
+    pub pager: usize,
+    /// 内存配额 (单位: 页)，0 表示不限制，只有 root server 没有限制
+    pub mem_quota: usize,
+    /// 已经使用的物理页数量，包含页表占用的页和分给子任务的配额
+    pub mem_used: usize,
+    /// 页表占用的物理页数量
+    pub pt_pages: usize,
+    /// 栈顶地址
+    pub stack_top: usize,
+    /// 栈的最大大小，栈区域为 `[stack_top - stack_size, stack_top)`
+    pub stack_size: usize,
+    /// 拥有地址空间的任务 ID，线程的 owner 为创建它的任务的主线程
+    pub owner: usize,
+}
+
+/// 任务启动信息的最大大小
+pub const STARTUP_MAX_SIZE: usize = 0x1000;
+
+/// 任务启动信息头，由 [SysCall::TaskCreate] 的最后一个参数传入
+/// 启动信息的布局为: 信息头 | `capc` 个任务 ID | `argc` 个参数 | `envc` 个 `key=value` 环境变量
+/// 参数和环境变量都是以 `\0` 结尾的字符串，内核只根据 `size` 复制整个启动信息
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StartupHeader {
+    /// 启动信息的总大小，包含信息头
+    pub size: usize,
+    /// 参数数量
+    pub argc: usize,
+    /// 环境变量数量
+    pub envc: usize,
+    /// 初始能力 (任务 ID) 数量
+    pub capc: usize,
 }
@@ -17,7 +17,11 @@ fn main() {
     // println!("cargo:rerun-if-env-changed=CARGO_CFG_KERNEL_BASE");
     println!("cargo:rerun-if-env-changed=CARGO_CFG_BOARD");
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=../users/target/riscv64gc-unknown-none-elf/release/vm");
+    // 启动镜像通过 incbin 引入，镜像变化时需要重新编译
+    println!(
+        "cargo:rerun-if-changed=../users/target/{}/release/boot.tar",
+        env::var("TARGET").unwrap()
+    );
     println!("cargo:rerun-if-changed=linker.lds.S");
 }
 
@@ -0,0 +1,448 @@
+use core::{
+    cmp,
+    mem::ManuallyDrop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use executor::TaskId;
+use polyhal::{
+    addr::{PhysPage, VirtAddr, VirtPage},
+    pagetable::{MappingFlags, MappingSize, PageTable, PageTableWrapper, TLB},
+    PAGE_SIZE,
+};
+use spin::Mutex;
+use syscall_consts::{
+    PMAllocFlags, SysCallError, VMMapFlags, HUGE_PAGE_1G, HUGE_PAGE_2M, STACK_AREA_SIZE,
+    STACK_GUARD_SIZE,
+};
+
+use crate::{
+    consts::{USER_STACK_RANDOM_SIZE, USER_STACK_TOP_ADDR},
+    frame::{frame_alloc, with_pt_account, FrameTracker},
+    random::random_below,
+    utils::align_up,
+};
+
+/// 将用户传入的映射权限转换为页表标志，没有设置任何权限时返回 [SysCallError::InvalidArg]
+pub fn mapping_flags(flags: VMMapFlags) -> Result<MappingFlags, SysCallError> {
+    if !flags.intersects(VMMapFlags::READ | VMMapFlags::WRITE | VMMapFlags::EXEC) {
+        return Err(SysCallError::InvalidArg);
+    }
+    let mut ret = MappingFlags::U;
+    if flags.intersects(VMMapFlags::READ | VMMapFlags::WRITE) {
+        ret |= MappingFlags::R;
+    }
+    if flags.contains(VMMapFlags::WRITE) {
+        ret |= MappingFlags::W;
+    }
+    if flags.contains(VMMapFlags::EXEC) {
+        ret |= MappingFlags::X;
+    }
+    Ok(ret)
+}
+
+/// 页表中的一个映射
+#[derive(Debug, Clone, Copy)]
+pub struct Leaf {
+    /// 映射到的物理地址
+    pub paddr: usize,
+    /// 页的大小
+    pub size: usize,
+}
+
+/// 地址空间，同一个任务中的所有线程共享同一个地址空间
+/// 包括页表、申请的物理页以及内存配额，最后一个线程退出后释放
+pub struct AddrSpace {
+    /// 页表，在 [Drop] 中记账后释放
+    pub page_table: ManuallyDrop<PageTableWrapper>,
+    /// 拥有此地址空间的任务 ID，即主线程的任务 ID
+    pub owner: TaskId,
+    /// 当前地址空间拥有的 pages
+    pub pages: Mutex<Vec<FrameTracker>>,
+    /// 内存配额 (单位: 页)，0 表示不限制
+    pub mem_quota: usize,
+    /// 分给还没有退出的子任务的内存配额 (单位: 页)，计入当前地址空间使用的内存
+    granted: AtomicUsize,
+    /// 创建者的地址空间，释放时把分到的内存配额还给创建者，root server 没有创建者
+    parent: Option<Weak<AddrSpace>>,
+    /// 页表占用的物理页数量，创建时包含根页表，由页表页分配器直接记账
+    pub pt_pages: AtomicUsize,
+    /// 通过 [AddrSpace::map_page_sized] 建立的所有映射，按照起始虚拟地址排序
+    /// polyhal 不提供映射的大小，取消映射时根据这里的记录整个取消大页
+    leaves: Mutex<BTreeMap<usize, Leaf>>,
+    /// 共享此地址空间并且还没有退出的线程
+    pub threads: Mutex<Vec<TaskId>>,
+    /// 以此地址空间中的线程为 pager 的任务的地址空间，pager 可以映射这些地址空间拥有的物理页
+    paged: Mutex<Vec<Weak<AddrSpace>>>,
+    /// 栈区域的顶部，在 [USER_STACK_TOP_ADDR] 下方随机选择
+    stack_area_top: usize,
+    /// 所有线程的栈，键为栈顶地址，线程退出时释放
+    stacks: Mutex<BTreeMap<usize, Stack>>,
+}
+
+/// 线程的栈 `[top - size, top)`，下方 [STACK_GUARD_SIZE] 大小的区域为保护区域
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    /// 使用这个栈的线程
+    pub tid: TaskId,
+    /// 栈的最大大小
+    pub size: usize,
+}
+
+impl AddrSpace {
+    /// 创建新的地址空间，`mem_quota` 为内存配额 (单位: 页)，0 表示不限制
+    pub fn new(owner: TaskId, mem_quota: usize) -> Self {
+        let stack_area_top =
+            USER_STACK_TOP_ADDR - random_below(USER_STACK_RANDOM_SIZE / PAGE_SIZE) * PAGE_SIZE;
+        AddrSpace {
+            page_table: ManuallyDrop::new(PageTableWrapper::alloc()),
+            owner,
+            pages: Mutex::new(Vec::new()),
+            mem_quota,
+            granted: AtomicUsize::new(0),
+            parent: None,
+            pt_pages: AtomicUsize::new(1),
+            leaves: Mutex::new(BTreeMap::new()),
+            threads: Mutex::new(Vec::new()),
+            paged: Mutex::new(Vec::new()),
+            stack_area_top,
+            stacks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 为 `parent` 创建的任务创建新的地址空间，`mem_quota` 从创建者剩余的配额中扣除，
+    /// 新的地址空间释放时归还。`mem_quota` 为 0 时返回 [SysCallError::InvalidArg]，
+    /// 超出创建者剩余的配额时返回 [SysCallError::NoResources]
+    pub fn with_parent(
+        owner: TaskId,
+        parent: &Arc<AddrSpace>,
+        mem_quota: usize,
+    ) -> Result<Self, SysCallError> {
+        if mem_quota == 0 {
+            return Err(SysCallError::InvalidArg);
+        }
+        if parent.mem_quota != 0 && parent.mem_used() + mem_quota > parent.mem_quota {
+            return Err(SysCallError::NoResources);
+        }
+        parent.granted.fetch_add(mem_quota, Ordering::Relaxed);
+        let mut addr_space = Self::new(owner, mem_quota);
+        addr_space.parent = Some(Arc::downgrade(parent));
+        Ok(addr_space)
+    }
+
+    /// 记录以此地址空间中的线程为 pager 的任务的地址空间，同时清理已经释放的地址空间
+    pub fn add_paged(&self, addr_space: &Arc<AddrSpace>) {
+        let mut paged = self.paged.lock();
+        paged.retain(|x| x.strong_count() != 0);
+        paged.push(Arc::downgrade(addr_space));
+    }
+
+    /// 物理内存 `[paddr, paddr + size)` 中的每一页是否都是通过 [AddrSpace::alloc_memory] 申请的
+    pub fn owns_frames(&self, paddr: usize, size: usize) -> bool {
+        let start = PhysPage::from_addr(paddr).as_num();
+        let end = start + align_up(size, PAGE_SIZE) / PAGE_SIZE;
+        let owned = self
+            .pages
+            .lock()
+            .iter()
+            .filter(|x| (start..end).contains(&x.0.as_num()))
+            .count();
+        owned == end - start
+    }
+
+    /// 物理内存 `[paddr, paddr + size)` 是否属于此地址空间或者以此地址空间为 pager 的任务
+    pub fn can_map_frames(&self, paddr: usize, size: usize) -> bool {
+        self.owns_frames(paddr, size)
+            || self
+                .paged
+                .lock()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .any(|x| x.owns_frames(paddr, size))
+    }
+
+    /// 获取 PageTable
+    pub fn page_table(&self) -> PageTable {
+        self.page_table.0
+    }
+
+    /// 为线程 `tid` 分配栈区域，返回栈顶地址，栈下方会保留 [STACK_GUARD_SIZE] 大小的保护区域
+    /// 所有的栈都位于 [STACK_AREA_SIZE] 大小的栈区域中，从顶部开始找到第一个能放下的空隙，
+    /// 已经退出的线程的栈会被复用，栈区域用完时返回 [SysCallError::NoResources]
+    pub fn alloc_stack(&self, tid: TaskId, stack_size: usize) -> Result<usize, SysCallError> {
+        // 栈和保护区域占用的大小，`stack_size` 来自用户，所有的计算都需要检查溢出
+        let needed = stack_size
+            .checked_add(STACK_GUARD_SIZE)
+            .ok_or(SysCallError::InvalidArg)?;
+        let area_bottom = self.stack_area_top - STACK_AREA_SIZE;
+        let mut stacks = self.stacks.lock();
+        let mut stack_top = self.stack_area_top;
+        for (top, stack) in stacks.iter().rev() {
+            if stack_top.checked_sub(needed).is_some_and(|x| x >= *top) {
+                break;
+            }
+            stack_top = top
+                .checked_sub(stack.size + STACK_GUARD_SIZE)
+                .ok_or(SysCallError::NoResources)?;
+        }
+        if !stack_top
+            .checked_sub(needed)
+            .is_some_and(|x| x >= area_bottom)
+        {
+            return Err(SysCallError::NoResources);
+        }
+        stacks.insert(
+            stack_top,
+            Stack {
+                tid,
+                size: stack_size,
+            },
+        );
+        Ok(stack_top)
+    }
+
+    /// 释放栈顶为 `stack_top` 的栈，取消栈中所有的映射并归还只在栈中映射的物理页
+    pub fn free_stack(&self, stack_top: usize) {
+        let Some(stack) = self.stacks.lock().remove(&stack_top) else {
+            return;
+        };
+        let stack_bottom = stack_top - stack.size;
+        let mut leaves = self.leaves.lock();
+        let mapped: Vec<_> = leaves
+            .range(stack_bottom..stack_top)
+            .map(|(vaddr, leaf)| (*vaddr, *leaf))
+            .collect();
+        mapped
+            .iter()
+            .for_each(|(vaddr, _)| self.unmap_leaf(&mut leaves, *vaddr));
+        // 同一个物理页可能还被映射在其他位置，这样的页保留到地址空间释放
+        let freed: Vec<_> = mapped
+            .into_iter()
+            .filter(|(_, leaf)| {
+                !leaves
+                    .values()
+                    .any(|x| x.paddr < leaf.paddr + leaf.size && leaf.paddr < x.paddr + x.size)
+            })
+            .map(|(_, leaf)| leaf)
+            .collect();
+        drop(leaves);
+        // 移除 FrameTracker 时会归还物理页
+        self.pages.lock().retain(|x| {
+            !freed
+                .iter()
+                .any(|leaf| (leaf.paddr..leaf.paddr + leaf.size).contains(&x.0.to_addr()))
+        });
+    }
+
+    /// 判断 `vaddr` 是否位于某个线程的栈下方的保护区域
+    pub fn in_stack_guard(&self, vaddr: usize) -> bool {
+        self.stacks
+            .lock()
+            .range(vaddr..)
+            .next()
+            .is_some_and(|(top, stack)| {
+                let stack_bottom = top - stack.size;
+                vaddr < stack_bottom && vaddr >= stack_bottom - STACK_GUARD_SIZE
+            })
+    }
+
+    /// 获取当前地址空间使用的物理页数量，包含页表占用的页和分给子任务的配额
+    pub fn mem_used(&self) -> usize {
+        self.pages.lock().len()
+            + self.pt_pages.load(Ordering::Relaxed)
+            + self.granted.load(Ordering::Relaxed)
+    }
+
+    /// 检查映射时最多申请 `pt_pages` 个页表页是否会超出内存配额，超出时返回 [SysCallError::NoResources]
+    pub fn check_pt_quota(&self, pt_pages: usize) -> Result<(), SysCallError> {
+        if self.mem_quota != 0 && self.mem_used() + pt_pages > self.mem_quota {
+            return Err(SysCallError::NoResources);
+        }
+        Ok(())
+    }
+
+    /// 申请物理内存，超出内存配额时返回 [SysCallError::NoResources]
+    pub fn alloc_memory(&self, size: usize, flags: PMAllocFlags) -> Result<usize, SysCallError> {
+        let count = align_up(size, PAGE_SIZE) / PAGE_SIZE;
+        if count == 0 {
+            return Err(SysCallError::InvalidArg);
+        }
+        // 检查内存配额
+        if self.mem_quota != 0 && self.mem_used() + count > self.mem_quota {
+            log::warn!(
+                "task {} exceeds memory quota: {} + {} > {}",
+                self.owner,
+                self.mem_used(),
+                count,
+                self.mem_quota
+            );
+            return Err(SysCallError::NoResources);
+        }
+        let pages = frame_alloc(count);
+        if pages.is_empty() {
+            return Err(SysCallError::NoMemory);
+        }
+        let start = pages[0].0;
+        // 伙伴分配器申请到的内存已经按照大小对齐，这里再检查一次
+        if flags.contains(PMAllocFlags::ALIGNED) {
+            let align = cmp::min(count.next_power_of_two() * PAGE_SIZE, HUGE_PAGE_1G);
+            if start.to_addr() % align != 0 {
+                return Err(SysCallError::NoMemory);
+            }
+        }
+        // 清空所有页表
+        if flags.contains(PMAllocFlags::ZEROD) {
+            pages.iter().for_each(|x| x.0.drop_clear());
+        }
+        self.pages.lock().extend(pages);
+        Ok(start.to_addr())
+    }
+
+    /// 释放通过 [AddrSpace::alloc_memory] 申请的物理内存 `[paddr, paddr + size)`
+    /// 释放前需要取消所有的映射，区域中有不属于当前地址空间的页或者还有页被映射时返回 [SysCallError::InvalidArg]
+    pub fn free_memory(&self, paddr: usize, size: usize) -> Result<usize, SysCallError> {
+        if paddr % PAGE_SIZE != 0 || size == 0 {
+            return Err(SysCallError::InvalidArg);
+        }
+        if self.maps_paddr(paddr, align_up(size, PAGE_SIZE)) {
+            log::warn!(
+                "task {} frees mapped memory {:#x} - {:#x}",
+                self.owner,
+                paddr,
+                paddr + size
+            );
+            return Err(SysCallError::InvalidArg);
+        }
+        let start = PhysPage::from_addr(paddr).as_num();
+        let end = start + align_up(size, PAGE_SIZE) / PAGE_SIZE;
+        let mut pages = self.pages.lock();
+        let owned = pages
+            .iter()
+            .filter(|x| (start..end).contains(&x.0.as_num()))
+            .count();
+        if owned != end - start {
+            return Err(SysCallError::InvalidArg);
+        }
+        // 移除 FrameTracker 时会归还物理页
+        pages.retain(|x| !(start..end).contains(&x.0.as_num()));
+        Ok(0)
+    }
+
+    /// 映射内存
+    pub fn map_page(&self, vpn: VirtPage, ppn: PhysPage) {
+        self.map_page_sized(vpn, ppn, MappingFlags::URWX, MappingSize::Page4KB);
+    }
+
+    /// 按照 `size` 映射内存，映射过程中申请的页表页会记入当前地址空间的内存使用量
+    pub fn map_page_sized(
+        &self,
+        vpn: VirtPage,
+        ppn: PhysPage,
+        flags: MappingFlags,
+        size: MappingSize,
+    ) {
+        log::debug!("map {:?} -> {:?} {:?} size: {:?}", vpn, ppn, flags, size);
+        // 页表页在 polyhal 内部申请，分配器把申请的页记入当前地址空间
+        with_pt_account(&self.pt_pages, || {
+            self.page_table().map_page(vpn, ppn, flags, size)
+        });
+        let page_size = match size {
+            MappingSize::Page4KB => PAGE_SIZE,
+            MappingSize::Page2MB => HUGE_PAGE_2M,
+            MappingSize::Page1GB => HUGE_PAGE_1G,
+        };
+        self.leaves.lock().insert(
+            vpn.to_addr(),
+            Leaf {
+                paddr: ppn.to_addr(),
+                size: page_size,
+            },
+        );
+    }
+
+    /// 获取包含 `vaddr` 的映射的起始地址和映射，没有映射时返回 None
+    pub fn leaf_of(&self, vaddr: usize) -> Option<(usize, Leaf)> {
+        self.leaves
+            .lock()
+            .range(..=vaddr)
+            .next_back()
+            .filter(|(start, leaf)| vaddr < *start + leaf.size)
+            .map(|(start, leaf)| (*start, *leaf))
+    }
+
+    /// 判断 `[uaddr, uaddr + size)` 中是否有已经映射的页
+    pub fn is_mapped_range(&self, uaddr: usize, size: usize) -> bool {
+        self.leaves
+            .lock()
+            .range(uaddr.saturating_sub(HUGE_PAGE_1G - 1)..uaddr + size)
+            .any(|(start, leaf)| *start + leaf.size > uaddr)
+    }
+
+    /// 判断物理内存 `[paddr, paddr + size)` 中是否有页还映射在当前地址空间中，需要遍历所有的映射
+    pub fn maps_paddr(&self, paddr: usize, size: usize) -> bool {
+        self.leaves
+            .lock()
+            .values()
+            .any(|leaf| leaf.paddr < paddr + size && paddr < leaf.paddr + leaf.size)
+    }
+
+    /// 取消一个映射并刷新 TLB，`vaddr` 为映射的起始地址，释放的页表页会从当前地址空间的计数中减去
+    fn unmap_leaf(&self, leaves: &mut BTreeMap<usize, Leaf>, vaddr: usize) {
+        leaves.remove(&vaddr);
+        with_pt_account(&self.pt_pages, || {
+            self.page_table().unmap_page(VirtPage::from_addr(vaddr))
+        });
+        TLB::flush_vaddr(VirtAddr::new(vaddr));
+    }
+
+    /// 取消 `vaddr` 所在的映射，大页会被整个取消，返回取消的映射的起始地址和大小
+    pub fn unmap_page(&self, vaddr: usize) -> Option<(usize, usize)> {
+        let (start, leaf) = self.leaf_of(vaddr)?;
+        self.unmap_leaf(&mut self.leaves.lock(), start);
+        Some((start, leaf.size))
+    }
+
+    /// 取消映射 `[uaddr, uaddr + size)`，未映射的页会被跳过
+    /// 区域只覆盖大页的一部分时返回 [SysCallError::InvalidArg]，不会取消任何映射
+    pub fn unmap_range(&self, uaddr: usize, size: usize) -> Result<(), SysCallError> {
+        let end = uaddr + size;
+        let mut leaves = self.leaves.lock();
+        // 映射最大为 1GB，所以只有从 `uaddr - HUGE_PAGE_1G` 开始的映射可能和区域重叠
+        let overlapped: Vec<_> = leaves
+            .range(uaddr.saturating_sub(HUGE_PAGE_1G - 1)..end)
+            .filter(|(start, leaf)| *start + leaf.size > uaddr)
+            .map(|(start, leaf)| (*start, leaf.size))
+            .collect();
+        if overlapped
+            .iter()
+            .any(|(start, size)| *start < uaddr || start + size > end)
+        {
+            return Err(SysCallError::InvalidArg);
+        }
+        overlapped
+            .into_iter()
+            .for_each(|(start, _)| self.unmap_leaf(&mut leaves, start));
+        Ok(())
+    }
+}
+
+impl Drop for AddrSpace {
+    fn drop(&mut self) {
+        // 释放页表时分配器从计数中减去释放的页表页，包括根页表
+        let page_table = &mut self.page_table;
+        with_pt_account(&self.pt_pages, || unsafe { ManuallyDrop::drop(page_table) });
+        let leaked = self.pt_pages.load(Ordering::Relaxed);
+        if leaked != 0 {
+            log::warn!("task {} leaks {} page table pages", self.owner, leaked);
+        }
+        // 创建者已经退出时不需要归还配额
+        if let Some(parent) = self.parent.as_ref().and_then(Weak::upgrade) {
+            parent.granted.fetch_sub(self.mem_quota, Ordering::Relaxed);
+        }
+    }
+}
@@ -6,7 +6,10 @@ use core::{
 
 use polyhal::time::Time;
 
-use crate::task::{MicroKernelTask, TaskState};
+use crate::{
+    futex::futex_waiting,
+    task::{MicroKernelTask, TaskState},
+};
 
 /// 等待特定的 time, 单位 ms
 pub struct NextTime(pub usize);
@@ -46,7 +49,7 @@ impl<'a> Future for WaitIPC<'a> {
     }
 }
 
-/// 等待系统恢复为 [TaskState::Runable] 状态
+/// 等待系统恢复为 [TaskState::Runable] 状态，任务被销毁时也会结束等待
 pub struct WaitResume<'a>(pub &'a MicroKernelTask);
 
 /// 为 [WaitResume] 实现 Future
@@ -55,10 +58,30 @@ impl<'a> Future for WaitResume<'a> {
 
     fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
         self.0.check_timeout();
-        match *self.0.state.lock() == TaskState::Runable {
+        match *self.0.state.lock() == TaskState::Runable || *self.0.destoryed.lock() {
             // 任务可以运行了
             true => Poll::Ready(()),
             false => Poll::Pending,
         }
     }
 }
+
+/// 等待 futex 被唤醒，任务被销毁或者超过 `deadline` (单位: ms，0 表示不会超时) 时也会结束等待
+pub struct WaitFutex<'a> {
+    pub task: &'a MicroKernelTask,
+    pub paddr: usize,
+    pub deadline: usize,
+}
+
+/// 为 [WaitFutex] 实现 Future
+impl<'a> Future for WaitFutex<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let timeout = self.deadline != 0 && Time::now().to_msec() >= self.deadline;
+        match !futex_waiting(self.paddr, self.task.tid) || *self.task.destoryed.lock() || timeout {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    }
+}
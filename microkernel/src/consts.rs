@@ -1,5 +1,20 @@
-/// 默认用户程序的栈大小为 20 * PAGE_SIZE = 800KB;
-pub const USER_STACK_PAGES: usize = 20;
+/// 默认用户程序的栈大小为 20 * PAGE_SIZE = 80KB
+pub const USER_STACK_SIZE: usize = 20 * 0x1000;
+
+/// 用户程序栈的最大大小，8MB
+pub const USER_STACK_MAX_SIZE: usize = 0x80_0000;
 
 /// 默认的用户程序栈顶地址
 pub const USER_STACK_TOP_ADDR: usize = 0xF000_0000;
+
+/// 栈区域的顶部在 [USER_STACK_TOP_ADDR] 下方随机选择，这是随机下移的最大距离
+pub const USER_STACK_RANDOM_SIZE: usize = 0x1000_0000;
+
+/// 当前架构是否支持 1GB 大页映射
+pub const HUGE_1G_SUPPORTED: bool = cfg!(not(target_arch = "loongarch64"));
+
+/// 启动镜像在 root server 地址空间中的映射地址，位于 root server 的堆之上
+pub const BOOT_IMAGE_ADDR: usize = 0x20_0000_0000;
+
+/// 支持的最大核心数量，用于按核心保存的状态
+pub const MAX_HARTS: usize = 8;
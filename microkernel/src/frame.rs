@@ -1,14 +1,42 @@
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
 use alloc::vec::Vec;
 use buddy_system_allocator::LockedFrameAllocator;
 use log::info;
-use polyhal::{addr::PhysPage, PAGE_SIZE, VIRT_ADDR_START};
+use polyhal::{addr::PhysPage, hart_id, PAGE_SIZE, VIRT_ADDR_START};
 use spin::Lazy;
 
-use crate::utils::align_up;
+use crate::{consts::MAX_HARTS, utils::align_up};
 
 static LOCK_FRAME_ALLOCATOR: Lazy<LockedFrameAllocator<32>> =
     Lazy::new(|| LockedFrameAllocator::new());
 
+/// 每个核心当前记账的页表页计数，polyhal 在这个核心上申请和释放页表页时记入这个计数
+/// 映射是同步执行的，期间不会切换任务，所以每个核心同时只有一个计数
+static PT_ACCOUNTS: [AtomicPtr<AtomicUsize>; MAX_HARTS] = [NO_ACCOUNT; MAX_HARTS];
+
+/// 没有记账的核心，只用于初始化 [PT_ACCOUNTS]
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ACCOUNT: AtomicPtr<AtomicUsize> = AtomicPtr::new(null_mut());
+
+/// 在 `f` 执行期间把当前核心上申请和释放的页表页记入 `account`
+pub fn with_pt_account<R>(account: &AtomicUsize, f: impl FnOnce() -> R) -> R {
+    let slot = &PT_ACCOUNTS[hart_id()];
+    let prev = slot.swap(account as *const _ as *mut _, Ordering::Relaxed);
+    let ret = f();
+    slot.store(prev, Ordering::Relaxed);
+    ret
+}
+
+/// 获取当前核心的页表页计数，没有记账时返回 None
+fn pt_account() -> Option<&'static AtomicUsize> {
+    // 计数只在 [with_pt_account] 执行期间有效，这期间一直被借用
+    unsafe { PT_ACCOUNTS[hart_id()].load(Ordering::Relaxed).as_ref() }
+}
+
 pub fn add_frame_range(mm_start: usize, mm_end: usize) {
     extern "C" {
         fn end();
@@ -25,23 +53,42 @@ pub fn add_frame_range(mm_start: usize, mm_end: usize) {
         .add_frame(frame_start, frame_end);
 }
 
+/// 申请页表页，记入当前核心的页表页计数
 pub fn frame_alloc_persist() -> PhysPage {
-    LOCK_FRAME_ALLOCATOR
+    let page = LOCK_FRAME_ALLOCATOR
         .lock()
         .alloc(1)
         .map(PhysPage::new)
         .inspect(|x| x.drop_clear())
-        .expect("can't find memory page")
+        .expect("can't find memory page");
+    if let Some(account) = pt_account() {
+        account.fetch_add(1, Ordering::Relaxed);
+    }
+    page
+}
+
+/// 释放页表页，从当前核心的页表页计数中减去
+pub fn frame_dealloc_persist(ppn: PhysPage) {
+    if let Some(account) = pt_account() {
+        account.fetch_sub(1, Ordering::Relaxed);
+    }
+    frame_dealloc(ppn)
 }
 
 /// 申请页表
+/// 伙伴分配器按照 2 的幂申请，所以申请到的物理页按照 `pages.next_power_of_two()` 对齐
 pub fn frame_alloc(pages: usize) -> Vec<FrameTracker> {
     let mut ret = Vec::new();
-    LOCK_FRAME_ALLOCATOR.lock().alloc(pages).inspect(|x| {
+    let mut allocator = LOCK_FRAME_ALLOCATOR.lock();
+    if let Some(start) = allocator.alloc(pages) {
         for i in 0..pages {
-            ret.push(FrameTracker(PhysPage::new(*x + i)))
+            ret.push(FrameTracker(PhysPage::new(start + i)))
+        }
+        // 归还多申请的页，否则这些页将永远无法被回收
+        for i in pages..pages.next_power_of_two() {
+            allocator.dealloc(start + i, 1);
         }
-    });
+    }
     ret
 }
 
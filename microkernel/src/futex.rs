@@ -0,0 +1,53 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use executor::TaskId;
+use spin::Mutex;
+
+/// 等待队列，使用物理地址作为键，这样共享同一块物理内存的任务可以互相唤醒
+static FUTEX_QUEUES: Mutex<BTreeMap<usize, Vec<TaskId>>> = Mutex::new(BTreeMap::new());
+
+/// 将任务加入 `paddr` 的等待队列，`check` 返回 false 时不会加入队列
+/// 检查和加入队列在同一个锁中完成，避免在检查之后丢失唤醒
+pub fn futex_enqueue(paddr: usize, tid: TaskId, check: impl FnOnce() -> bool) -> bool {
+    let mut queues = FUTEX_QUEUES.lock();
+    if !check() {
+        return false;
+    }
+    queues.entry(paddr).or_default().push(tid);
+    true
+}
+
+/// 判断任务是否仍在 `paddr` 的等待队列中
+pub fn futex_waiting(paddr: usize, tid: TaskId) -> bool {
+    FUTEX_QUEUES
+        .lock()
+        .get(&paddr)
+        .map_or(false, |queue| queue.contains(&tid))
+}
+
+/// 将任务从 `paddr` 的等待队列中移除，返回任务是否还在等待队列中
+pub fn futex_remove(paddr: usize, tid: TaskId) -> bool {
+    let mut queues = FUTEX_QUEUES.lock();
+    let Some(queue) = queues.get_mut(&paddr) else {
+        return false;
+    };
+    let len = queue.len();
+    queue.retain(|x| *x != tid);
+    let removed = queue.len() != len;
+    if queue.is_empty() {
+        queues.remove(&paddr);
+    }
+    removed
+}
+
+/// 按照等待的顺序唤醒 `paddr` 上最多 `count` 个任务，返回被唤醒的任务
+pub fn futex_wake(paddr: usize, count: usize) -> Vec<TaskId> {
+    let mut queues = FUTEX_QUEUES.lock();
+    let Some(queue) = queues.get_mut(&paddr) else {
+        return Vec::new();
+    };
+    let woken = queue.drain(..count.min(queue.len())).collect();
+    if queue.is_empty() {
+        queues.remove(&paddr);
+    }
+    woken
+}
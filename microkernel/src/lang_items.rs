@@ -88,7 +88,7 @@ impl PageAlloc for PageAlloImpl {
     }
 
     fn dealloc(&self, ppn: PhysPage) {
-        frame::frame_dealloc(ppn)
+        frame::frame_dealloc_persist(ppn)
     }
 }
 
@@ -13,11 +13,14 @@ use spin::Mutex;
 use syscall_consts::PageFaultReason;
 use task::{current_microkernel_task, MicroKernelTask};
 
+mod addr_space;
 pub mod async_ops;
 pub mod consts;
 mod frame;
+mod futex;
 #[macro_use]
 mod lang_items;
+mod random;
 mod syscall;
 mod task;
 mod utils;
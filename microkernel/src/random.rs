@@ -0,0 +1,40 @@
+//! 内核的随机数来源
+//! 四个架构上没有统一可用的硬件随机数，所以使用 SplitMix64 生成伪随机数，
+//! 每次生成时都会混入当前的纳秒时间，时间受中断和调度的影响，可以提供少量的熵。
+//! 生成的随机数用于地址空间布局随机化，不能用于密码学用途
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use polyhal::time::Time;
+
+/// SplitMix64 的增量
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// 随机数生成器的状态
+static STATE: AtomicU64 = AtomicU64::new(GOLDEN_GAMMA);
+
+/// 生成一个 64 位的随机数
+pub fn random() -> u64 {
+    let time = Time::now().to_nsec() as u64;
+    let mut z = STATE
+        .fetch_add(GOLDEN_GAMMA ^ time, Ordering::Relaxed)
+        .wrapping_add(GOLDEN_GAMMA ^ time);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// 生成 `[0, bound)` 中的随机数，`bound` 为 0 时返回 0
+pub fn random_below(bound: usize) -> usize {
+    match bound {
+        0 => 0,
+        _ => (random() % bound as u64) as usize,
+    }
+}
+
+/// 使用随机数填充 `buffer`
+pub fn fill_random(buffer: &mut [u8]) {
+    buffer
+        .chunks_mut(8)
+        .for_each(|chunk| chunk.copy_from_slice(&random().to_ne_bytes()[..chunk.len()]));
+}
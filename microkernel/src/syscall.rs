@@ -1,27 +1,43 @@
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
 use executor::{tid2task, yield_now, AsyncTask};
 use log::info;
 use polyhal::{
     addr::{PhysPage, VirtAddr, VirtPage},
     debug::DebugConsole,
-    pagetable::PageTable,
+    pagetable::{MappingSize, PageTable},
     shutdown,
     time::Time,
+    TrapFrame, TrapFrameArgs, PAGE_SIZE, VIRT_ADDR_START,
 };
 
 use syscall_consts::{
-    IPCFlags, Message, MessageContent, NotifyEnum, PMAllocFlags, SysCall, SysCallError,
-    FROM_KERNEL, IPC_ANY,
+    ExceptionType, IPCFlags, Message, MessageContent, NotifyEnum, PMAllocFlags, RawMessage,
+    ShortMessage, StartupHeader, SysCall, SysCallError, TaskInfo, VMMapFlags, FROM_KERNEL,
+    HUGE_PAGE_1G, HUGE_PAGE_2M, IPC_ANY, PATH_LEN, SHORT_MSG_WORDS, SHORT_TAG_SHIFT,
+    STARTUP_MAX_SIZE, VM_SERVER,
 };
 
 use crate::{
-    async_ops::WaitResume,
+    addr_space::mapping_flags,
+    async_ops::{WaitFutex, WaitResume},
+    consts::{HUGE_1G_SUPPORTED, USER_STACK_MAX_SIZE, USER_STACK_SIZE},
+    futex::{futex_enqueue, futex_remove, futex_wake},
     lang_items::puts,
+    random::fill_random,
     task::{MicroKernelTask, TaskState},
-    utils::UserBuffer,
+    utils::{align_up, UserBuffer},
 };
 
 type SysResult = Result<usize, SysCallError>;
 
+/// 映射一个页时最多需要申请的页表页数量，用于在映射前检查内存配额
+const MAP_PT_PAGES: usize = 3;
+
 impl MicroKernelTask {
     /// 串口输出
     pub async fn sys_serial_write(
@@ -29,7 +45,7 @@ impl MicroKernelTask {
         buf: UserBuffer<u8>,
         buf_len: usize,
     ) -> Result<usize, SysCallError> {
-        let bytes = buf.slice_mut_with_len(buf_len, self).await;
+        let bytes = buf.slice_with_len(buf_len, self).await?;
         puts(bytes);
         Ok(bytes.len())
     }
@@ -40,7 +56,7 @@ impl MicroKernelTask {
         buf: UserBuffer<u8>,
         buf_len: usize,
     ) -> Result<usize, SysCallError> {
-        let bytes = buf.slice_mut_with_len(buf_len, self).await;
+        let bytes = buf.slice_mut_with_len(buf_len, self).await?;
         assert!(bytes.len() > 0, "buffer is not a valid buffer");
         // 读取串口数据 直到有输出
         loop {
@@ -53,24 +69,108 @@ impl MicroKernelTask {
         Ok(1)
     }
 
-    /// 退出当前任务
-    pub fn sys_task_exit(&self) -> SysResult {
-        *self.destoryed.lock() = true;
+    /// 退出当前任务，退出码通过 [ExceptionType::GraceExit] 异常消息发送给 pager
+    /// 主线程退出时会销毁共享地址空间的所有线程
+    pub async fn sys_task_exit(&self, exit_code: usize) -> SysResult {
+        if self.tid == self.addr_space.owner {
+            let threads = self.addr_space.threads.lock().clone();
+            threads
+                .into_iter()
+                .filter(|tid| *tid != self.tid)
+                .filter_map(|tid| tid2task(tid)?.downcast_arc::<MicroKernelTask>().ok())
+                .for_each(|thread| thread.destroy());
+        }
+        self.exit_with_exception(ExceptionType::GraceExit, exit_code, 0)
+            .await;
+        Ok(0)
+    }
+
+    /// 销毁任务，只能销毁当前任务或者以当前任务为 pager 的任务
+    /// 销毁主线程时会销毁共享地址空间的所有线程
+    pub fn sys_task_destory(&self, tid: usize) -> SysResult {
+        let task = self.memory_target(tid)?;
+        if task.tid != task.addr_space.owner {
+            task.destroy();
+            return Ok(0);
+        }
+        let threads = task.addr_space.threads.lock().clone();
+        threads
+            .into_iter()
+            .filter_map(|tid| tid2task(tid)?.downcast_arc::<MicroKernelTask>().ok())
+            .for_each(|thread| thread.destroy());
         Ok(0)
     }
 
-    /// 休眠 ms
+    /// 创建共享当前地址空间的线程，`stack_size` 为 0 时使用默认大小 [USER_STACK_SIZE]
+    pub fn sys_thread_create(
+        &self,
+        entry_point: usize,
+        arg: usize,
+        stack_size: usize,
+    ) -> SysResult {
+        // 先检查大小再对齐，避免对齐时溢出
+        let stack_size = match stack_size {
+            0 => USER_STACK_SIZE,
+            _ if stack_size > USER_STACK_MAX_SIZE => return Err(SysCallError::TooLarge),
+            _ => align_up(stack_size, PAGE_SIZE),
+        };
+        self.new_thread(entry_point, arg, stack_size)
+    }
+
+    /// 休眠 ms，会覆盖之前设置的定时器，ms 为 0 时取消定时器
     pub fn sys_time(&self, ms: usize) -> SysResult {
         log::trace!("syscall timeout: {}, ms: {}", *self.timeout.lock(), ms);
-        *self.timeout.lock() += Time::now().to_nsec() + ms * 1000_000;
+        *self.timeout.lock() = match ms {
+            0 => 0,
+            _ => Time::now().to_nsec() + ms * 1000_000,
+        };
+        Ok(0)
+    }
+
+    /// 通知 `dst` 当前任务有等待它取回的异步消息，当前任务会加入 `dst` 的待取回队列
+    pub fn sys_notify(&self, dst: usize) -> SysResult {
+        let dst = tid2task(dst)
+            .ok_or(SysCallError::InvalidTask)?
+            .downcast_arc::<MicroKernelTask>()
+            .map_err(|_| SysCallError::InvalidTask)?;
+        {
+            let mut senders = dst.async_senders.lock();
+            if !senders.contains(&self.tid) {
+                senders.push_back(self.tid);
+            }
+        }
+        dst.notify(NotifyEnum::ASYNC.into());
         Ok(0)
     }
 
+    /// 取出待取回队列中下一个有异步消息的任务，已经退出的任务会被跳过
+    /// 队列中还有其他任务时重新设置 [NotifyEnum::ASYNC]，下一次接收消息时继续处理
+    pub fn sys_async_sender(&self) -> SysResult {
+        let mut senders = self.async_senders.lock();
+        while let Some(tid) = senders.pop_front() {
+            if tid2task(tid).is_none() {
+                continue;
+            }
+            if !senders.is_empty() {
+                *self.notifications.lock() |= NotifyEnum::ASYNC.into();
+            }
+            return Ok(tid);
+        }
+        Err(SysCallError::NotFound)
+    }
+
     /// 获取开机到现在的时间，单位: ms
     pub fn sys_uptime(&self) -> SysResult {
         Ok(Time::now().to_msec())
     }
 
+    /// 使用随机数填充 `buf`
+    pub async fn sys_get_random(&self, buf: UserBuffer<u8>, buf_len: usize) -> SysResult {
+        let bytes = buf.slice_mut_with_len(buf_len, self).await?;
+        fill_random(bytes);
+        Ok(buf_len)
+    }
+
     /// 关闭计算机
     pub fn sys_shutdown(&self) -> ! {
         shutdown();
@@ -131,6 +231,12 @@ impl MicroKernelTask {
             // 等待当前任务恢复
             WaitResume(self).await;
 
+            // 当前任务在等待时被销毁
+            if *self.destoryed.lock() {
+                dst.senders.lock().retain(|x| *x != self.tid);
+                return Err(SysCallError::Aborted);
+            }
+
             // 如果目标任务已经完成
             if self
                 .notifications
@@ -147,6 +253,20 @@ impl MicroKernelTask {
         } else {
             self.tid
         };
+        // 快速路径: 目的任务已经在用户态等待接收，直接将消息写入目的任务的接收缓冲区
+        if !flags.contains(IPCFlags::NO_FAST_PATH) && dst.deliver(source, message) {
+            dst.resume();
+            // 这里不会把时间片直接交给目的任务: executor 按照队列轮流 poll 任务的 future，
+            // 没有指定下一个运行的任务的接口，目的任务要等到执行器 poll 到它时才会运行。
+            // 快速路径只省去了经过 `message` 中转的一次复制，调度的延迟和普通路径相同，
+            // shell 的 bench 命令分别测量了 CALL 和 SEND 之后让出 CPU 的往返延迟。
+            // 不需要等待回复时让出 CPU，避免当前任务继续占用 CPU，
+            // 需要等待回复时当前任务会在接收时阻塞，不需要再让出
+            if !flags.contains(IPCFlags::RECV) {
+                yield_now().await;
+            }
+            return Ok(0);
+        }
         *dst.message.lock() = Some(Message {
             source,
             content: message.content.clone(),
@@ -156,20 +276,34 @@ impl MicroKernelTask {
         Ok(0)
     }
 
-    /// 接收 IPC 信息
+    /// 接收 IPC 信息，`user_buffer` 为用户态接收消息的 [RawMessage] 缓冲区，内核接收消息时为 None
+    /// 发送方可以把消息直接写入 `user_buffer`，此时返回 true 并且不会修改 `message`
     pub async fn recv_message(
         &self,
         src: usize,
         message: &mut Message,
+        user_buffer: Option<usize>,
         flags: IPCFlags,
-    ) -> SysResult {
+    ) -> Result<bool, SysCallError> {
         // 如果当前 IPC 是 IPC_ANY 且当前的等待通知集不为空，处理通知
         if src == IPC_ANY && !self.notifications.lock().is_empty() {
             message.source = FROM_KERNEL;
             message.content = MessageContent::NotifyField {
                 notications: self.notifications.lock().pop_all(),
             };
-            return Ok(0);
+            return Ok(false);
+        }
+
+        // 短消息 IPC 没有取走的消息
+        {
+            let mut unclaimed = self.unclaimed.lock();
+            if unclaimed
+                .as_ref()
+                .is_some_and(|x| src == IPC_ANY || x.source == src)
+            {
+                *message = unclaimed.take().unwrap();
+                return Ok(false);
+            }
         }
 
         // 如果 IPC 含有 NON_BLOCK 标志位，则直接返回
@@ -177,6 +311,9 @@ impl MicroKernelTask {
             return Err(SysCallError::WouldBlock);
         }
 
+        // 设置用户态的接收缓冲区，发送方可以直接将消息写入缓冲区中
+        *self.recv_buffer.lock() = user_buffer;
+
         // 查找目标任务
         let target_tid = self
             .senders
@@ -206,20 +343,43 @@ impl MicroKernelTask {
 
         // 清空等待状态
         *self.wait_for.lock() = None;
+        let delivered = user_buffer.is_some() && self.recv_buffer.lock().take().is_none();
+
+        // 当前任务在等待时被销毁
+        if *self.destoryed.lock() {
+            return Err(SysCallError::Aborted);
+        }
+
+        // 消息已经由发送方直接写入用户缓冲区中
+        if delivered {
+            return Ok(true);
+        }
+
+        // 等待的任务已经被销毁，不会再收到它的消息
+        if self
+            .notifications
+            .lock()
+            .pop_specify(NotifyEnum::ABORTED)
+            .is_some()
+        {
+            return Err(SysCallError::Aborted);
+        }
 
         // 复制消息
         *message = self.message.lock().clone().unwrap();
-        Ok(0)
+        Ok(false)
     }
 
-    /// 进行 IPC 通信
+    /// 进行 IPC 通信，`user_buffer` 见 [MicroKernelTask::recv_message]
+    /// 接收到的消息已经直接写入 `user_buffer` 时返回 true
     pub async fn ipc(
         &self,
         dst: usize,
         src: usize,
         message: &mut Message,
+        user_buffer: Option<usize>,
         flags: IPCFlags,
-    ) -> SysResult {
+    ) -> Result<bool, SysCallError> {
         // 发送 IPC 消息
         if flags.contains(IPCFlags::SEND) {
             self.send_message(dst, message, flags).await?;
@@ -227,10 +387,10 @@ impl MicroKernelTask {
 
         // 接收 IPC 消息
         if flags.contains(IPCFlags::RECV) {
-            self.recv_message(src, message, flags).await?;
+            return self.recv_message(src, message, user_buffer, flags).await;
         }
 
-        Ok(0)
+        Ok(false)
     }
 
     /// 处理 IPC 请求
@@ -238,7 +398,7 @@ impl MicroKernelTask {
         &self,
         dst: usize,
         src: usize,
-        buffer: UserBuffer<Message>,
+        buffer: UserBuffer<RawMessage>,
         flags: usize,
     ) -> SysResult {
         log::trace!("ipc: {:?}, {:?}, {:?}, {:?}", dst, src, buffer, flags);
@@ -256,26 +416,154 @@ impl MicroKernelTask {
             return Err(SysCallError::InvalidArg);
         }
 
-        self.ipc(dst, src, buffer.get_mut(self).await, flags).await
+        // 用户传递的是固定布局的 RawMessage，内核中使用 Message 处理
+        // 接收时发送方会把 RawMessage 直接写入用户的缓冲区，只有经过消息暂存区时才在这里转换
+        let raw = buffer.get_mut(self).await?;
+        let mut message = match flags.contains(IPCFlags::SEND) {
+            true => Message::try_from(&*raw)?,
+            false => Message::blank(),
+        };
+        let delivered = self
+            .ipc(dst, src, &mut message, Some(buffer.addr()), flags)
+            .await?;
+        if flags.contains(IPCFlags::RECV) && !delivered {
+            *raw = RawMessage::from(&message);
+        }
+        Ok(0)
+    }
+
+    /// 处理短消息 IPC 请求，消息的标签和负载通过寄存器传递，不访问用户内存
+    /// `flags` 的高位为发送的消息的标签，见 [SHORT_TAG_SHIFT]，`words` 为发送的负载
+    /// 接收到消息时返回消息的标签，负载写入 ARG1 到 ARG3 寄存器，消息来源写入 ARG4 寄存器
+    /// 接收到的消息不能用短消息表示时返回 [SysCallError::TooLarge]，消息会保留到下一次接收
+    pub async fn sys_ipc_short(
+        &self,
+        dst: usize,
+        src: usize,
+        flags: usize,
+        words: [usize; SHORT_MSG_WORDS],
+    ) -> SysResult {
+        let tag = flags >> SHORT_TAG_SHIFT;
+        let flags = IPCFlags::from_bits(flags & ((1 << SHORT_TAG_SHIFT) - 1))
+            .ok_or(SysCallError::InvalidArg)?;
+        info!(
+            "[task {}] short ipc dst: {} src: {} flags: {:?}",
+            self.tid, dst, src, flags
+        );
+        if flags.contains(IPCFlags::KERNEL) {
+            return Err(SysCallError::InvalidArg);
+        }
+
+        // 确保拥有一个有效的 IPC 请求。
+        if src != IPC_ANY && tid2task(src).is_none() {
+            return Err(SysCallError::InvalidArg);
+        }
+
+        // 在内核中构建完整的消息，接收方仍然可以使用普通的 IPC 接收
+        let mut message = Message::blank();
+        if flags.contains(IPCFlags::SEND) {
+            let raw = ShortMessage::from_tag(self.tid, tag, words)
+                .to_raw()
+                .ok_or(SysCallError::InvalidArg)?;
+            message = Message::try_from(&raw)?;
+        }
+        self.ipc(dst, src, &mut message, None, flags).await?;
+
+        if !flags.contains(IPCFlags::RECV) {
+            return Ok(0);
+        }
+        let Some(reply) = ShortMessage::from_raw(&RawMessage::from(&message)) else {
+            // 消息已经从发送方取出，保留下来等待通过普通 IPC 接收
+            *self.unclaimed.lock() = Some(message);
+            return Err(SysCallError::TooLarge);
+        };
+        let tf = self.get_trap_frame();
+        set_syscall_arg(tf, 1, reply.words[0]);
+        set_syscall_arg(tf, 2, reply.words[1]);
+        set_syscall_arg(tf, 3, reply.words[2]);
+        set_syscall_arg(tf, 4, message.source);
+        Ok(reply.tag())
     }
 
-    /// 创建新的任务
+    /// 创建新的任务，`mem_quota` 为新任务的内存配额 (单位: 页)，不能为 0，并且不能超过当前任务剩余的配额，
+    /// 新任务的配额计入当前任务使用的内存，新任务退出后归还
+    /// `pager` 负责处理新任务的缺页和异常，必须是当前地址空间中的任务，0 表示交给 root server 处理
+    /// `stack_size` 为新任务栈的最大大小，0 表示使用默认大小 [USER_STACK_SIZE]
+    /// `startup_buf` 指向新任务的启动信息，为 0 时表示没有启动信息
     pub async fn sys_task_create(
         &self,
         name_buf: UserBuffer<u8>,
         entry_point: usize,
         pager: usize,
+        mem_quota: usize,
+        stack_size: usize,
+        startup_buf: UserBuffer<u8>,
     ) -> SysResult {
-        let name = name_buf.get_str(self).await.map_err(|err| {
-            log::error!("{:#x?}", err);
-            SysCallError::InvalidArg
-        })?;
+        // 先检查大小再对齐，避免对齐时溢出
+        let stack_size = match stack_size {
+            0 => USER_STACK_SIZE,
+            _ if stack_size > USER_STACK_MAX_SIZE => return Err(SysCallError::TooLarge),
+            _ => align_up(stack_size, PAGE_SIZE),
+        };
 
-        let pager = tid2task(pager)
-            .map(|x| x.downcast_arc::<MicroKernelTask>().ok())
-            .flatten();
+        // 任务名称可能是 ELF 文件的路径，最长为 PATH_LEN
+        let name = name_buf.get_str(self, PATH_LEN).await?;
+
+        // 读取启动信息，启动信息的第一个字段为整个启动信息的大小
+        let startup = match startup_buf.addr() {
+            0 => Vec::new(),
+            _ => {
+                let header = startup_buf.slice_with_len(size_of::<usize>(), self).await?;
+                let size = usize::from_ne_bytes(header.try_into().unwrap());
+                if size < size_of::<StartupHeader>() {
+                    return Err(SysCallError::InvalidArg);
+                }
+                if size > STARTUP_MAX_SIZE || size > stack_size {
+                    return Err(SysCallError::TooLarge);
+                }
+                startup_buf.slice_with_len(size, self).await?.to_vec()
+            }
+        };
 
-        Ok(Self::new(&name, entry_point, pager))
+        // pager 为 0 时缺页和异常交给 root server，否则 pager 必须是当前任务或者同一个地址空间中的线程
+        let pager = match pager {
+            0 => None,
+            _ => {
+                let pager = tid2task(pager)
+                    .ok_or(SysCallError::InvalidTask)?
+                    .downcast_arc::<MicroKernelTask>()
+                    .map_err(|_| SysCallError::InvalidTask)?;
+                if pager.addr_space.owner != self.addr_space.owner {
+                    return Err(SysCallError::NotAllowed);
+                }
+                Some(pager)
+            }
+        };
+
+        Self::new(
+            &name,
+            entry_point,
+            pager,
+            &self.addr_space,
+            mem_quota,
+            stack_size,
+            &startup,
+        )
+    }
+
+    /// 获取任务信息，包括内存配额和使用情况
+    pub async fn sys_task_info(&self, tid: usize, buffer: UserBuffer<TaskInfo>) -> SysResult {
+        let info = if tid == self.tid {
+            self.task_info()
+        } else {
+            tid2task(tid)
+                .ok_or(SysCallError::InvalidTask)?
+                .downcast_arc::<MicroKernelTask>()
+                .map_err(|_| SysCallError::InvalidTask)?
+                .task_info()
+        };
+        *buffer.get_mut(self).await? = info;
+        Ok(0)
     }
 
     /// 申请物理内存
@@ -286,7 +574,7 @@ impl MicroKernelTask {
         // 如果需要申请页表的任务就是当前任务
         // 直接处理
         if dst == self.tid {
-            return Ok(self.alloc_memory(size, flags));
+            return self.addr_space.alloc_memory(size, flags);
         }
 
         // 获取申请内存的任务
@@ -301,64 +589,252 @@ impl MicroKernelTask {
         }
 
         // 为 dst 任务申请页表
-        Ok(dst.alloc_memory(size, flags))
+        dst.addr_space.alloc_memory(size, flags)
     }
 
-    /// 映射虚拟内存
-    /// TODO: use flags to control page privilege
-    pub fn sys_vm_map(&self, dst: usize, uaddr: usize, paddr: usize, _flags: usize) -> SysResult {
-        // 如果需要申请页表的任务就是当前任务
-        // 直接处理
-        let vpn = VirtPage::from_addr(uaddr);
-        let ppn = PhysPage::from_addr(paddr);
-        if dst == self.tid {
-            // 映射内存
-            self.map_page(vpn, ppn);
-            return Ok(0);
-        }
-
-        // 获取申请内存的任务
+    /// 释放物理内存，只有 `dst` 的 pager 可以释放，没有 pager 的 root server 释放自己的内存
+    /// `dst` 和当前任务都需要先取消这段内存的所有映射，否则释放后还能访问已经分配给其他任务的页
+    pub fn sys_pm_free(&self, dst: usize, paddr: usize, size: usize) -> SysResult {
         let dst = tid2task(dst)
             .ok_or(SysCallError::InvalidTask)?
             .downcast_arc::<MicroKernelTask>()
             .map_err(|_| SysCallError::InvalidTask)?;
-
-        // 如果 dst 任务和当前任务不存在联系
-        if dst.pager.as_ref().ok_or(SysCallError::InvalidTask)?.tid != self.tid {
-            return Err(SysCallError::InvalidTask);
+        match &dst.pager {
+            Some(pager) if pager.tid == self.tid => {}
+            None if dst.addr_space.owner == VM_SERVER && self.addr_space.owner == VM_SERVER => {}
+            _ => return Err(SysCallError::NotAllowed),
         }
+        if self.addr_space.maps_paddr(paddr, align_up(size, PAGE_SIZE)) {
+            return Err(SysCallError::InvalidArg);
+        }
+        dst.addr_space.free_memory(paddr, size)
+    }
 
-        dst.map_page(vpn, ppn);
-
+    /// 映射虚拟内存，`flags` 中含有大页标志时使用大页映射，权限标志控制页的访问权限
+    /// 物理页需要属于当前任务或者以当前任务为 pager 的任务，只有 root server 可以映射任意物理内存
+    pub fn sys_vm_map(&self, dst: usize, uaddr: usize, paddr: usize, flags: usize) -> SysResult {
+        let flags = VMMapFlags::from_bits(flags).ok_or(SysCallError::InvalidArg)?;
+        let map_flags = mapping_flags(flags)?;
+        // 根据 flags 获取映射的大小
+        let (size, page_size) = if flags.contains(VMMapFlags::HUGE_1G) {
+            if !HUGE_1G_SUPPORTED {
+                return Err(SysCallError::NotSupported);
+            }
+            (MappingSize::Page1GB, HUGE_PAGE_1G)
+        } else if flags.contains(VMMapFlags::HUGE_2M) {
+            (MappingSize::Page2MB, HUGE_PAGE_2M)
+        } else {
+            (MappingSize::Page4KB, PAGE_SIZE)
+        };
+        // 大页映射需要虚拟地址和物理地址都对齐，4KB 映射会自动向下对齐
+        if page_size > PAGE_SIZE && (uaddr % page_size != 0 || paddr % page_size != 0) {
+            return Err(SysCallError::InvalidArg);
+        }
+        let vaddr = uaddr / page_size * page_size;
+        if vaddr
+            .checked_add(page_size)
+            .map_or(true, |end| end > VIRT_ADDR_START)
+        {
+            return Err(SysCallError::InvalidUaddr);
+        }
+        let paddr = paddr / page_size * page_size;
+        self.check_frames(paddr, page_size)?;
+
+        let dst = self.memory_target(dst)?;
+        // 同一个地址已经有不同大小的映射时，polyhal 无法正确地覆盖
+        if let Some((start, leaf)) = dst.addr_space.leaf_of(vaddr) {
+            if start != vaddr || leaf.size != page_size {
+                return Err(SysCallError::AlreadyUsed);
+            }
+        }
+        // 检查内存配额，页表页按照最坏的情况估算
+        dst.addr_space.check_pt_quota(MAP_PT_PAGES)?;
+        dst.addr_space.map_page_sized(
+            VirtPage::from_addr(vaddr),
+            PhysPage::from_addr(paddr),
+            map_flags,
+            size,
+        );
         Ok(0)
     }
 
-    /// 取消映射虚拟内存
+    /// 取消映射 `uaddr` 所在的页，大页会被整个取消映射
     pub fn sys_vm_unmap(&self, dst: usize, uaddr: usize) -> SysResult {
-        // 如果需要申请页表的任务就是当前任务
-        // 直接处理
-        let vpn = VirtPage::from_addr(uaddr);
-        if dst == self.tid {
-            // 映射内存
-            self.page_table().unmap_page(vpn);
-            return Ok(0);
+        self.memory_target(dst)?.addr_space.unmap_page(uaddr);
+        Ok(0)
+    }
+
+    /// 检查当前任务是否可以映射物理内存 `[paddr, paddr + size)`，不能映射时返回 [SysCallError::NotAllowed]
+    /// root server 负责映射设备内存，可以映射任意物理内存，其他任务只能映射自己和以自己为 pager 的任务申请的物理页
+    fn check_frames(&self, paddr: usize, size: usize) -> Result<(), SysCallError> {
+        if self.addr_space.owner == VM_SERVER || self.addr_space.can_map_frames(paddr, size) {
+            return Ok(());
         }
+        Err(SysCallError::NotAllowed)
+    }
 
-        // 获取申请内存的任务
-        let dst = tid2task(dst)
+    /// 获取需要操作内存的目标任务，目标任务必须是当前任务或者以当前任务为 pager
+    /// pager 已经退出的任务的缺页和异常交给 root server，所以 root server 也可以操作这些任务，例如销毁它们
+    fn memory_target(&self, dst: usize) -> Result<Arc<MicroKernelTask>, SysCallError> {
+        let task = tid2task(dst)
             .ok_or(SysCallError::InvalidTask)?
             .downcast_arc::<MicroKernelTask>()
             .map_err(|_| SysCallError::InvalidTask)?;
 
         // 如果 dst 任务和当前任务不存在联系
-        if dst.pager.as_ref().ok_or(SysCallError::InvalidTask)?.tid != self.tid {
-            return Err(SysCallError::InvalidTask);
+        if dst != self.tid {
+            let pager = task.pager.as_ref().ok_or(SysCallError::InvalidTask)?;
+            let orphan = *pager.destoryed.lock() && self.addr_space.owner == VM_SERVER;
+            if pager.tid != self.tid && !orphan {
+                return Err(SysCallError::InvalidTask);
+            }
+        }
+        Ok(task)
+    }
+
+    /// 映射一段连续的虚拟内存 `[uaddr, uaddr + size)` 到物理内存 `[paddr, paddr + size)`
+    /// `flags` 中的大页标志表示允许使用的最大的页，地址对齐时会自动使用大页映射，权限标志控制页的访问权限
+    /// 映射是原子的，只要有一个页无法映射，就不会映射任何页，物理内存的限制和 [Self::sys_vm_map] 相同
+    pub fn sys_vm_map_range(
+        &self,
+        dst: usize,
+        uaddr: usize,
+        paddr: usize,
+        size: usize,
+        flags: usize,
+    ) -> SysResult {
+        let flags = VMMapFlags::from_bits(flags).ok_or(SysCallError::InvalidArg)?;
+        let map_flags = mapping_flags(flags)?;
+        // 地址和大小都需要按页对齐
+        if size == 0 || uaddr % PAGE_SIZE != 0 || paddr % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(SysCallError::InvalidArg);
+        }
+        if uaddr.checked_add(size).is_none() || uaddr + size > VIRT_ADDR_START {
+            return Err(SysCallError::InvalidUaddr);
+        }
+        self.check_frames(paddr, size)?;
+        let dst = self.memory_target(dst)?;
+
+        // 允许使用的页大小，按照从大到小的顺序排列
+        let mut page_sizes = Vec::new();
+        if flags.contains(VMMapFlags::HUGE_1G) && HUGE_1G_SUPPORTED {
+            page_sizes.push((HUGE_PAGE_1G, MappingSize::Page1GB));
+        }
+        if flags.intersects(VMMapFlags::HUGE_1G | VMMapFlags::HUGE_2M) {
+            page_sizes.push((HUGE_PAGE_2M, MappingSize::Page2MB));
+        }
+        page_sizes.push((PAGE_SIZE, MappingSize::Page4KB));
+
+        // 确保区域内没有已经映射的页
+        if dst.addr_space.is_mapped_range(uaddr, size) {
+            return Err(SysCallError::AlreadyUsed);
+        }
+
+        // 计算映射方案，尽可能使用大页
+        let mut mappings = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let (vaddr, paddr) = (uaddr + offset, paddr + offset);
+            let &(page_size, mapping_size) = page_sizes
+                .iter()
+                .find(|(page_size, _)| {
+                    size - offset >= *page_size && vaddr % page_size == 0 && paddr % page_size == 0
+                })
+                .unwrap();
+            mappings.push((vaddr, paddr, mapping_size));
+            offset += page_size;
+        }
+
+        // 检查内存配额，页表页按照最坏的情况估算
+        let addr_space = &dst.addr_space;
+        addr_space.check_pt_quota(size / HUGE_PAGE_2M + size / HUGE_PAGE_1G + MAP_PT_PAGES)?;
+
+        // 映射内存
+        mappings
+            .into_iter()
+            .for_each(|(vaddr, paddr, mapping_size)| {
+                addr_space.map_page_sized(
+                    VirtPage::from_addr(vaddr),
+                    PhysPage::from_addr(paddr),
+                    map_flags,
+                    mapping_size,
+                )
+            });
+        Ok(0)
+    }
+
+    /// 取消映射一段连续的虚拟内存 `[uaddr, uaddr + size)`，未映射的页会被跳过
+    /// 大页只能整个取消映射，区域只覆盖大页的一部分时返回 [SysCallError::InvalidArg]
+    pub fn sys_vm_unmap_range(&self, dst: usize, uaddr: usize, size: usize) -> SysResult {
+        if uaddr % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(SysCallError::InvalidArg);
+        }
+        if uaddr.checked_add(size).is_none() || uaddr + size > VIRT_ADDR_START {
+            return Err(SysCallError::InvalidUaddr);
+        }
+        self.memory_target(dst)?
+            .addr_space
+            .unmap_range(uaddr, size)?;
+        Ok(0)
+    }
+
+    /// 获取用户地址 `uaddr` 对应的 futex 物理地址，`uaddr` 需要按照 4 字节对齐
+    async fn futex_paddr(&self, uaddr: usize) -> SysResult {
+        if uaddr % size_of::<u32>() != 0 {
+            return Err(SysCallError::InvalidArg);
+        }
+        // 确保地址已经被映射
+        UserBuffer::<AtomicU32>::from(uaddr).get_ref(self).await?;
+        Ok(self
+            .page_table()
+            .translate(VirtAddr::new(uaddr))
+            .ok_or(SysCallError::InvalidUaddr)?
+            .0
+            .addr())
+    }
+
+    /// 如果 `uaddr` 中的值等于 `expected`，那么阻塞当前任务直到被唤醒或者超时
+    /// `timeout` 的单位为 ms，0 表示不会超时
+    pub async fn sys_futex_wait(&self, uaddr: usize, expected: usize, timeout: usize) -> SysResult {
+        let paddr = self.futex_paddr(uaddr).await?;
+        let value = UserBuffer::<AtomicU32>::from(uaddr).get_ref(self).await?;
+
+        // 值已经改变，不需要等待
+        if !futex_enqueue(paddr, self.tid, || {
+            value.load(Ordering::SeqCst) == expected as u32
+        }) {
+            return Err(SysCallError::TryAgain);
         }
 
-        dst.page_table().unmap_page(vpn);
+        let deadline = match timeout {
+            0 => 0,
+            _ => Time::now().to_msec() + timeout,
+        };
+        self.block();
+        WaitFutex {
+            task: self,
+            paddr,
+            deadline,
+        }
+        .await;
+        self.resume();
+
+        // 任务仍在等待队列中，说明是超时或者任务被销毁
+        if futex_remove(paddr, self.tid) {
+            return match *self.destoryed.lock() {
+                true => Err(SysCallError::Aborted),
+                false => Err(SysCallError::TimedOut),
+            };
+        }
         Ok(0)
     }
 
+    /// 唤醒在 `uaddr` 上等待的最多 `count` 个任务，返回唤醒的任务数量
+    pub async fn sys_futex_wake(&self, uaddr: usize, count: usize) -> SysResult {
+        let paddr = self.futex_paddr(uaddr).await?;
+        Ok(futex_wake(paddr, count).len())
+    }
+
     /// 翻译虚拟地址
     pub fn sys_trans_paddr(&self, uaddr: usize) -> SysResult {
         Ok(PageTable::current()
@@ -377,16 +853,27 @@ impl MicroKernelTask {
                 self.sys_ipc(args[0], args[1], args[2].into(), args[3])
                     .await
             }
-            SysCall::Notify => todo!(),
+            SysCall::Notify => self.sys_notify(args[0]),
             // 串口输出
             SysCall::SerialWrite => self.sys_serial_write(args[0].into(), args[1]).await,
             // 串口输入
             SysCall::SerialRead => self.sys_serial_read(args[0].into(), args[1]).await,
             // 创建任务
-            SysCall::TaskCreate => self.sys_task_create(args[0].into(), args[1], args[2]).await,
-            SysCall::TaskDestory => todo!(),
+            SysCall::TaskCreate => {
+                self.sys_task_create(
+                    args[0].into(),
+                    args[1],
+                    args[2],
+                    args[3],
+                    args[4],
+                    args[5].into(),
+                )
+                .await
+            }
+            // 销毁任务
+            SysCall::TaskDestory => self.sys_task_destory(args[0]),
             // 退出任务
-            SysCall::TaskExit => self.sys_task_exit(),
+            SysCall::TaskExit => self.sys_task_exit(args[0]).await,
             // 获取当前任务 id
             SysCall::TaskSelf => Ok(self.get_task_id()),
             // 申请物理内存
@@ -406,6 +893,53 @@ impl MicroKernelTask {
             SysCall::Shutdown => self.sys_shutdown(),
             // 翻译虚拟地址
             SysCall::TransVAddr => self.sys_trans_paddr(args[0]),
+            // 获取任务信息
+            SysCall::TaskInfo => self.sys_task_info(args[0], args[1].into()).await,
+            // 映射一段连续的内存
+            SysCall::VMMapRange => {
+                self.sys_vm_map_range(args[0], args[1], args[2], args[3], args[4])
+            }
+            // 取消映射一段连续的内存
+            SysCall::VMUnmapRange => self.sys_vm_unmap_range(args[0], args[1], args[2]),
+            // 创建线程
+            SysCall::ThreadCreate => self.sys_thread_create(args[0], args[1], args[2]),
+            // 等待 futex
+            SysCall::FutexWait => self.sys_futex_wait(args[0], args[1], args[2]).await,
+            // 唤醒 futex
+            SysCall::FutexWake => self.sys_futex_wake(args[0], args[1]).await,
+            // 释放物理内存
+            SysCall::PMFree => self.sys_pm_free(args[0], args[1], args[2]),
+            // 短消息 IPC 请求
+            SysCall::IPCShort => {
+                self.sys_ipc_short(args[0], args[1], args[2], [args[3], args[4], args[5]])
+                    .await
+            }
+            // 获取随机数
+            SysCall::GetRandom => self.sys_get_random(args[0].into(), args[1]).await,
+            // 取出下一个有异步消息的任务
+            SysCall::AsyncSender => self.sys_async_sender(),
         }
     }
 }
+
+/// 设置系统调用返回时第 `index` 个参数寄存器的值
+/// polyhal 只提供了前三个参数寄存器的访问，其他的寄存器根据架构直接写入
+fn set_syscall_arg(tf: &mut TrapFrame, index: usize, value: usize) {
+    match index {
+        1 => tf[TrapFrameArgs::ARG1] = value,
+        2 => tf[TrapFrameArgs::ARG2] = value,
+        // a0 为 x10
+        #[cfg(target_arch = "riscv64")]
+        3 | 4 => tf.x[10 + index] = value,
+        #[cfg(target_arch = "aarch64")]
+        3 | 4 => tf.regs[index] = value,
+        #[cfg(target_arch = "x86_64")]
+        3 => tf.r10 = value,
+        #[cfg(target_arch = "x86_64")]
+        4 => tf.r8 = value,
+        // a0 为 r4
+        #[cfg(target_arch = "loongarch64")]
+        3 | 4 => tf.regs[4 + index] = value,
+        _ => unreachable!("invalid syscall argument register {}", index),
+    }
+}
@@ -1,40 +1,43 @@
-use core::arch::global_asm;
+use core::{arch::global_asm, cmp, mem::size_of, sync::atomic::Ordering};
 
-use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec::Vec};
 use executor::{
-    current_task, task::TaskType, task_id_alloc, thread::spawn, yield_now, AsyncTask, TaskId,
+    current_task, task::TaskType, task_id_alloc, thread::spawn, tid2task, yield_now, AsyncTask,
+    TaskId, TASK_MAP,
 };
 use log::info;
 use polyhal::{
     addr::{PhysPage, VirtAddr, VirtPage},
-    pagetable::{MappingFlags, MappingSize, PageTable, PageTableWrapper},
+    pagetable::{MappingFlags, MappingSize, PageTable},
     run_user_task,
     time::Time,
     TrapFrame, TrapFrameArgs, PAGE_SIZE, VIRT_ADDR_START,
 };
 use spin::mutex::Mutex;
 use syscall_consts::{
-    ExceptionType, IPCFlags, Message, MessageContent, Notify, NotifyEnum, PMAllocFlags,
-    PageFaultReason, IPC_ANY,
+    BootImage, ExceptionType, IPCFlags, Message, MessageContent, Notify, NotifyEnum,
+    PageFaultReason, RawMessage, StartupHeader, SysCallError, TaskInfo, BOOT_IMAGE_ADDR_ENV,
+    BOOT_IMAGE_SIZE_ENV, IPC_ANY, ROOT_SERVER_NAME, VM_SERVER,
 };
 use xmas_elf::program::Type;
 
 use crate::{
-    consts::{USER_STACK_PAGES, USER_STACK_TOP_ADDR},
-    frame::{frame_alloc, FrameTracker},
-    utils::align_up,
+    addr_space::AddrSpace,
+    consts::{BOOT_IMAGE_ADDR, USER_STACK_SIZE},
+    frame::frame_alloc,
+    utils::{copy_to_user, is_mapped_in},
 };
 
-// 包含 vm elf 文件，vm server 将作为 root server 运行。
-// static ROOT_SERVER_BIN: &'static [u8] = include_bytes!("../../users/target/riscv64gc-unknown-none-elf/release/vm");
+// 包含启动镜像，镜像由 users 的 Makefile 打包，包含 root server 和其他服务，格式见 [BootImage]
+// 镜像按页对齐，这样可以直接映射到 root server 的地址空间
 
 #[cfg(target_arch = "riscv64")]
 global_asm!(
     r#"
     .p2align 12
-    ROOT_SERVER_BIN:
-    .incbin "users/target/riscv64gc-unknown-none-elf/release/vm"
-    ROOT_SERVER_END:
+    BOOT_IMAGE_START:
+    .incbin "users/target/riscv64gc-unknown-none-elf/release/boot.tar"
+    BOOT_IMAGE_END:
 "#
 );
 
@@ -42,9 +45,9 @@ global_asm!(
 global_asm!(
     r#"
     .p2align 12
-    ROOT_SERVER_BIN:
-    .incbin "users/target/aarch64-unknown-none-softfloat/release/vm"
-    ROOT_SERVER_END:
+    BOOT_IMAGE_START:
+    .incbin "users/target/aarch64-unknown-none-softfloat/release/boot.tar"
+    BOOT_IMAGE_END:
 "#
 );
 
@@ -52,9 +55,9 @@ global_asm!(
 global_asm!(
     r#"
     .p2align 12
-    ROOT_SERVER_BIN:
-    .incbin "users/target/x86_64-unknown-none/release/vm"
-    ROOT_SERVER_END:
+    BOOT_IMAGE_START:
+    .incbin "users/target/x86_64-unknown-none/release/boot.tar"
+    BOOT_IMAGE_END:
 "#
 );
 
@@ -62,9 +65,9 @@ global_asm!(
 global_asm!(
     r#"
     .p2align 12
-    ROOT_SERVER_BIN:
-    .incbin "users/target/loongarch64-unknown-none/release/vm"
-    ROOT_SERVER_END:
+    BOOT_IMAGE_START:
+    .incbin "users/target/loongarch64-unknown-none/release/boot.tar"
+    BOOT_IMAGE_END:
 "#
 );
 
@@ -82,8 +85,8 @@ pub enum TaskState {
 pub struct MicroKernelTask {
     /// 任务中断上下文
     pub trap_frame: TrapFrame,
-    /// 任务页表
-    pub page_table: PageTableWrapper,
+    /// 任务的地址空间，同一个任务中的线程共享同一个地址空间
+    pub addr_space: Arc<AddrSpace>,
     /// 页表代理任务
     pub pager: Option<Arc<MicroKernelTask>>,
     /// 任务 ID
@@ -98,15 +101,24 @@ pub struct MicroKernelTask {
     pub timeout: Mutex<usize>,
     /// 当前等待处理的通知
     pub notifications: Mutex<Notify>,
+    /// 有异步消息等待此 `TASK` 取回的任务 ID 队列，由 [NotifyEnum::ASYNC] 通知
+    pub async_senders: Mutex<VecDeque<usize>>,
     /// 等待向此 `TASK` 发送消息的任务 ID 队列
     pub senders: Mutex<Vec<TaskId>>,
     /// 可以向此 `TASK` 发送消息的任务 ID
     pub wait_for: Mutex<Option<TaskId>>,
-    /// 当前任务拥有的 pages
-    pub pages: Mutex<Vec<FrameTracker>>,
+    /// 栈顶地址
+    pub stack_top: usize,
+    /// 栈的最大大小，栈下方的保护区域由 [AddrSpace] 记录
+    pub stack_size: usize,
     /// 消息暂存区，因为同时只有一个任务可以向此任务发送消息
     /// 所以可以只需要一个 message 即可，而不需要一个队列
     pub message: Mutex<Option<Message>>,
+    /// 通过短消息 IPC 接收到但是不能用短消息表示的消息，下一次接收消息时返回
+    pub unclaimed: Mutex<Option<Message>>,
+    /// 用户态接收消息的 [RawMessage] 缓冲区地址，任务在 IPC 系统调用中阻塞等待消息时设置
+    /// 发送方可以直接将消息写入这里，而不需要经过 `message` 中转，写入完成后清空
+    pub recv_buffer: Mutex<Option<usize>>,
     /// 页表错误，由于采用异步形式，但是发生错误的时候需要发送并等待 IPC
     /// 所以发生错误的时候可以存在在这个结构中，进入 async 函数后处理
     pub fault: Mutex<Option<(usize, usize, PageFaultReason)>>,
@@ -121,7 +133,7 @@ impl AsyncTask for MicroKernelTask {
     /// 会在任务执行前被调用
     /// 这里会执行切换页表的操作
     fn before_run(&self) {
-        self.page_table.0.change();
+        self.addr_space.page_table.change();
     }
 
     /// 获取任务类型，这里恒返回 [TaskType::MicroTask]
@@ -144,37 +156,64 @@ impl AsyncTask for MicroKernelTask {
     }
 }
 
+/// 生成 ROOT_SERVER 的启动信息，布局见 [StartupHeader]
+/// 第一个参数为任务名称，环境变量中是启动镜像的地址和大小
+fn root_server_startup(image_size: usize) -> Vec<u8> {
+    let args = [ROOT_SERVER_NAME];
+    let envs = [
+        format!("{}={:#x}", BOOT_IMAGE_ADDR_ENV, BOOT_IMAGE_ADDR),
+        format!("{}={:#x}", BOOT_IMAGE_SIZE_ENV, image_size),
+    ];
+    let mut startup = Vec::new();
+    let header = StartupHeader {
+        size: 0,
+        argc: args.len(),
+        envc: envs.len(),
+        capc: 0,
+    };
+    [header.size, header.argc, header.envc, header.capc]
+        .iter()
+        .for_each(|word| startup.extend_from_slice(&word.to_ne_bytes()));
+    args.iter()
+        .map(|x| x.as_bytes())
+        .chain(envs.iter().map(|x| x.as_bytes()))
+        .for_each(|x| {
+            startup.extend_from_slice(x);
+            startup.push(0);
+        });
+    // 第一个字段为启动信息的总大小
+    let size = startup.len();
+    startup[..size_of::<usize>()].copy_from_slice(&size.to_ne_bytes());
+    startup
+}
+
 /// 将 ROOT_SERVER 任务添加到调度器中
 pub fn add_root_server() {
     // 创建 ROOT_SERVER 任务
-    let mut root_server = MicroKernelTask {
-        trap_frame: TrapFrame::new(),
-        page_table: PageTableWrapper::alloc(),
-        pager: None,
-        tid: task_id_alloc(),
-        name: String::from("VM"),
-        state: Mutex::new(TaskState::Runable),
-        destoryed: Mutex::new(false),
-        timeout: Mutex::new(0),
-        notifications: Mutex::new(Notify::new()),
-        senders: Mutex::new(Vec::new()),
-        wait_for: Mutex::new(None),
-        pages: Mutex::new(Vec::new()),
-        message: Mutex::new(None),
-        fault: Mutex::new(None),
-    };
+    let tid = task_id_alloc();
+    let addr_space = Arc::new(AddrSpace::new(tid, 0));
+    let stack_top = addr_space
+        .alloc_stack(tid, USER_STACK_SIZE)
+        .expect("can't allocate stack for root server");
+    let mut root_server =
+        MicroKernelTask::with_addr_space(tid, "VM", addr_space, None, stack_top, USER_STACK_SIZE);
+    root_server.resume();
     // 切换到 ROOT_SERVER 的页表，方便进行内存复制和切换，以及映射新的内存
-    root_server.page_table.change();
+    root_server.addr_space.page_table.change();
 
     extern "C" {
-        fn ROOT_SERVER_BIN();
-        fn ROOT_SERVER_END();
+        fn BOOT_IMAGE_START();
+        fn BOOT_IMAGE_END();
     }
-    let start = ROOT_SERVER_BIN as usize;
-    let end = ROOT_SERVER_END as usize;
-    info!("root server memory area: {:#x} - {:#x}", start, end);
-    // 将 ROOT_SERVER 的地址转换为数组
-    let root_server_elf = unsafe { core::slice::from_raw_parts(start as *const u8, end - start) };
+    let start = BOOT_IMAGE_START as usize;
+    let end = BOOT_IMAGE_END as usize;
+    info!("boot image memory area: {:#x} - {:#x}", start, end);
+    let boot_image =
+        BootImage::new(unsafe { core::slice::from_raw_parts(start as *const u8, end - start) });
+    // 从启动镜像中找到 ROOT_SERVER
+    let root_server_elf = boot_image
+        .find(ROOT_SERVER_NAME)
+        .expect("can't find root server in boot image");
     // 获取 ROOT_SERVER 头信息
     let elf_header = xmas_elf::ElfFile::new(root_server_elf)
         .expect("can't get a correct elf file as root server");
@@ -191,52 +230,59 @@ pub fn add_root_server() {
             x.mem_size(),
             x.offset()
         );
+        // 镜像中的文件只按照 512 字节对齐，所以需要把段复制到新申请的页中，超出文件大小的部分填 0
+        let vaddr = x.virtual_addr() as usize;
+        let file_end = vaddr + x.file_size() as usize;
+        let data = &root_server_elf[x.offset() as usize..][..x.file_size() as usize];
         // 当前段的虚拟页表号
-        let vpn = VirtPage::from_addr(x.virtual_addr() as usize);
-        // 当前段的物理页表号
-        let ppn = PhysPage::from_addr(start - VIRT_ADDR_START + x.offset() as usize);
+        let vpn = VirtPage::from_addr(vaddr);
         // 当前段需要的页表数量
-        let pages = (x.mem_size() as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+        let pages = (vaddr % PAGE_SIZE + x.mem_size() as usize + PAGE_SIZE - 1) / PAGE_SIZE;
 
-        // 映射当前内存
         for i in 0..pages {
-            root_server.page_table.map_page(
-                vpn + i,
-                ppn + i,
-                MappingFlags::URWX,
-                MappingSize::Page4KB,
-            );
+            let page = frame_alloc(1);
+            assert!(page.len() > 0, "can't allocate page for root server");
+            let buffer = page[0].0.get_buffer();
+            buffer.fill(0);
+            // 当前页和段的文件内容重叠的部分
+            let page_start = (vpn + i).to_addr();
+            let copy_start = cmp::max(page_start, vaddr);
+            let copy_end = cmp::min(page_start + PAGE_SIZE, file_end);
+            if copy_start < copy_end {
+                buffer[copy_start - page_start..copy_end - page_start]
+                    .copy_from_slice(&data[copy_start - vaddr..copy_end - vaddr]);
+            }
+            root_server.addr_space.map_page(vpn + i, page[0].0);
+            root_server.addr_space.pages.lock().extend(page);
         }
     });
 
-    // 申请新的栈页表, 4KB * 20 = 800KB
-    for i in 0..USER_STACK_PAGES {
-        let page = frame_alloc(1);
-        assert!(
-            page.len() > 0,
-            "can't allocate page for root server at boot stage."
-        );
-
-        // 获取栈地址
-        let stack_addr = VirtPage::from_addr(USER_STACK_TOP_ADDR - i * PAGE_SIZE);
-
-        // 映射栈内存
-        root_server.page_table.map_page(
-            stack_addr,
-            page[0].0,
-            MappingFlags::URWX,
+    // 将整个启动镜像只读映射到 ROOT_SERVER，由 ROOT_SERVER 启动其他服务
+    let image_pages = (end - start + PAGE_SIZE - 1) / PAGE_SIZE;
+    let image_vpn = VirtPage::from_addr(BOOT_IMAGE_ADDR);
+    let image_ppn = PhysPage::from_addr(start - VIRT_ADDR_START);
+    for i in 0..image_pages {
+        root_server.addr_space.map_page_sized(
+            image_vpn + i,
+            image_ppn + i,
+            MappingFlags::U | MappingFlags::R,
             MappingSize::Page4KB,
         );
-        root_server.pages.lock().extend(page);
     }
+
+    // ROOT_SERVER 没有 pager，需要直接映射整个栈
+    root_server.map_stack();
     info!(
         "Root server entry point: {:#x}",
         elf_header.header.pt2.entry_point()
     );
 
-    // 设置 ROOT_SERVER 的中断上下文，包括入口和栈
+    // 设置 ROOT_SERVER 的中断上下文，包括入口、栈和启动信息
+    let startup = root_server_startup(end - start);
+    let startup_addr = root_server.push_startup(&startup);
     root_server.trap_frame[TrapFrameArgs::SEPC] = elf_header.header.pt2.entry_point() as _;
-    root_server.trap_frame[TrapFrameArgs::SP] = USER_STACK_TOP_ADDR;
+    root_server.trap_frame[TrapFrameArgs::SP] = startup_addr;
+    root_server.trap_frame[TrapFrameArgs::ARG0] = startup_addr;
 
     // 将 ROOT_SERVER 加入到调度器中
     let root_server = Arc::new(root_server);
@@ -244,50 +290,81 @@ pub fn add_root_server() {
 }
 
 impl MicroKernelTask {
-    /// 创建新的任务
-    pub fn new(name: &str, entry_point: usize, pager: Option<Arc<MicroKernelTask>>) -> TaskId {
-        // 申请新的任务 ID
-        let new_tid = task_id_alloc();
-
-        // 创建新的任务
-        let mut new_task = MicroKernelTask {
+    /// 使用 `addr_space` 创建任务结构，任务的初始状态为 [TaskState::UnUsed]
+    fn with_addr_space(
+        tid: TaskId,
+        name: &str,
+        addr_space: Arc<AddrSpace>,
+        pager: Option<Arc<MicroKernelTask>>,
+        stack_top: usize,
+        stack_size: usize,
+    ) -> Self {
+        addr_space.threads.lock().push(tid);
+        MicroKernelTask {
             trap_frame: TrapFrame::new(),
-            page_table: PageTableWrapper::alloc(),
+            addr_space,
             pager,
-            tid: new_tid,
+            tid,
             name: String::from(name),
             state: Mutex::new(TaskState::UnUsed),
             destoryed: Mutex::new(false),
             timeout: Mutex::new(0),
             notifications: Mutex::new(Notify::new()),
+            async_senders: Mutex::new(VecDeque::new()),
             senders: Mutex::new(Vec::new()),
             wait_for: Mutex::new(None),
-            pages: Mutex::new(Vec::new()),
+            stack_top,
+            stack_size,
             message: Mutex::new(None),
+            unclaimed: Mutex::new(None),
+            recv_buffer: Mutex::new(None),
             fault: Mutex::new(None),
-        };
+        }
+    }
+
+    /// 创建新的任务，`mem_quota` 为任务的内存配额 (单位: 页)，从创建者 `parent` 的配额中扣除
+    /// `stack_size` 为栈的最大大小，栈内存会在访问时由 pager 映射
+    /// `startup` 为任务的启动信息，会被复制到栈顶，并作为第一个参数传递给任务
+    /// 栈区域放不下 `stack_size` 大小的栈时返回错误
+    pub fn new(
+        name: &str,
+        entry_point: usize,
+        pager: Option<Arc<MicroKernelTask>>,
+        parent: &Arc<AddrSpace>,
+        mem_quota: usize,
+        stack_size: usize,
+        startup: &[u8],
+    ) -> Result<TaskId, SysCallError> {
+        // 申请新的任务 ID
+        let new_tid = task_id_alloc();
+
+        // 创建新的地址空间，主线程的栈位于栈区域的顶部
+        let addr_space = Arc::new(AddrSpace::with_parent(new_tid, parent, mem_quota)?);
+        let stack_top = addr_space.alloc_stack(new_tid, stack_size)?;
+        // pager 需要映射它为新任务申请的物理页
+        if let Some(pager) = &pager {
+            pager.addr_space.add_paged(&addr_space);
+        }
+
+        // 创建新的任务
+        let mut new_task = MicroKernelTask::with_addr_space(
+            new_tid, name, addr_space, pager, stack_top, stack_size,
+        );
 
         // 设置任务上下文
         new_task.trap_frame[TrapFrameArgs::SEPC] = entry_point;
-        new_task.trap_frame[TrapFrameArgs::SP] = USER_STACK_TOP_ADDR;
+        new_task.trap_frame[TrapFrameArgs::SP] = new_task.stack_top;
 
-        // 申请新的栈页表, 4KB * 20 = 800KB
-        for i in 0..USER_STACK_PAGES {
-            let page = frame_alloc(1);
-            assert!(
-                page.len() > 0,
-                "can't allocate page for root server at boot stage."
-            );
-            // 获取栈地址
-            let stack_addr = VirtPage::from_addr(USER_STACK_TOP_ADDR - i * PAGE_SIZE);
-            // 映射栈内存
-            new_task.page_table.map_page(
-                stack_addr,
-                page[0].0,
-                MappingFlags::URWX,
-                MappingSize::Page4KB,
-            );
-            new_task.pages.lock().extend(page);
+        // 栈内存在发生缺页时由 pager 映射，没有 pager 的任务无法处理缺页，直接映射
+        if new_task.pager.is_none() {
+            new_task.map_stack();
+        }
+
+        // 复制启动信息，没有启动信息时第一个参数为 0
+        if !startup.is_empty() {
+            let startup_addr = new_task.push_startup(startup);
+            new_task.trap_frame[TrapFrameArgs::SP] = startup_addr;
+            new_task.trap_frame[TrapFrameArgs::ARG0] = startup_addr;
         }
 
         // 将新的任务加入到调度器中
@@ -296,7 +373,46 @@ impl MicroKernelTask {
         new_task.resume();
         // 将任务加入到任务队列中
         spawn(new_task.clone(), new_task.run());
-        new_tid
+        Ok(new_tid)
+    }
+
+    /// 创建共享当前任务地址空间和 pager 的线程，线程从 `entry_point` 开始运行，
+    /// `arg` 作为线程的第一个参数，`stack_size` 为线程栈的最大大小
+    pub fn new_thread(
+        &self,
+        entry_point: usize,
+        arg: usize,
+        stack_size: usize,
+    ) -> Result<TaskId, SysCallError> {
+        // 在地址空间中为线程分配栈
+        let tid = task_id_alloc();
+        let stack_top = self.addr_space.alloc_stack(tid, stack_size)?;
+
+        // 创建新的线程
+        let mut thread = MicroKernelTask::with_addr_space(
+            tid,
+            &self.name,
+            self.addr_space.clone(),
+            self.pager.clone(),
+            stack_top,
+            stack_size,
+        );
+
+        // 设置线程上下文
+        thread.trap_frame[TrapFrameArgs::SEPC] = entry_point;
+        thread.trap_frame[TrapFrameArgs::SP] = stack_top;
+        thread.trap_frame[TrapFrameArgs::ARG0] = arg;
+
+        // 没有 pager 的任务无法处理缺页，直接映射栈
+        if thread.pager.is_none() {
+            thread.map_stack();
+        }
+
+        // 将新的线程加入到调度器中
+        let thread = Arc::new(thread);
+        thread.resume();
+        spawn(thread.clone(), thread.run());
+        Ok(tid)
     }
 
     /// 设置 fault field 以便后面处理
@@ -314,6 +430,107 @@ impl MicroKernelTask {
         *self.fault.lock() = Some((vaddr, sepc, reason));
     }
 
+    /// 映射整个栈，用于没有 pager 的任务
+    fn map_stack(&self) {
+        for i in 1..=self.stack_size / PAGE_SIZE {
+            let page = frame_alloc(1);
+            assert!(page.len() > 0, "can't allocate page for task stack");
+            // 映射栈内存
+            self.addr_space.map_page(
+                VirtPage::from_addr(self.stack_top - i * PAGE_SIZE),
+                page[0].0,
+            );
+            self.addr_space.pages.lock().extend(page);
+        }
+    }
+
+    /// 将启动信息复制到栈顶，返回启动信息的地址，地址按照 16 字节对齐
+    fn push_startup(&self, startup: &[u8]) -> usize {
+        let top_page = VirtAddr::new(self.stack_top - PAGE_SIZE);
+        // 栈顶的页需要提前映射，以便写入启动信息
+        if !is_mapped_in(self.page_table(), top_page) {
+            let page = frame_alloc(1);
+            assert!(page.len() > 0, "can't allocate page for task startup info");
+            self.addr_space
+                .map_page(VirtPage::from_addr(top_page.addr()), page[0].0);
+            self.addr_space.pages.lock().extend(page);
+        }
+        let (paddr, _) = self.page_table().translate(top_page).unwrap();
+        let addr = (self.stack_top - startup.len()) / 16 * 16;
+        let offset = addr - top_page.addr();
+        PhysPage::from_addr(paddr.addr()).get_buffer()[offset..offset + startup.len()]
+            .copy_from_slice(startup);
+        addr
+    }
+
+    /// 销毁当前任务，并通知正在等待向此任务发送消息和等待接收此任务消息的任务
+    /// 线程的栈在这里释放，其他线程可以复用这个栈区域
+    pub fn destroy(&self) {
+        *self.destoryed.lock() = true;
+        self.addr_space.free_stack(self.stack_top);
+        let senders = core::mem::take(&mut *self.senders.lock());
+        // 等待接收此任务消息的任务通常是在等待请求的回复，不通知的话会一直阻塞
+        let receivers: Vec<_> = TASK_MAP
+            .lock()
+            .values()
+            .filter_map(|x| x.upgrade()?.downcast_arc::<MicroKernelTask>().ok())
+            .filter(|x| *x.wait_for.lock() == Some(self.tid))
+            .collect();
+        senders
+            .into_iter()
+            .filter_map(|tid| tid2task(tid)?.downcast_arc::<MicroKernelTask>().ok())
+            .chain(receivers)
+            .for_each(|task| {
+                *task.notifications.lock() |= NotifyEnum::ABORTED.into();
+                task.resume();
+            });
+    }
+
+    /// 因为异常而销毁当前任务，会把异常发送给 pager，没有 pager 时发送给 root server
+    /// 主动退出时异常类型为 [ExceptionType::GraceExit]，`uaddr` 为退出码
+    pub async fn exit_with_exception(&self, exception: ExceptionType, uaddr: usize, ip: usize) {
+        match exception {
+            ExceptionType::GraceExit => log::info!("task {} exit with code {}", self.tid, uaddr),
+            _ => log::warn!(
+                "task {} exit with exception: {:?}, {:#x} @ {:#x}",
+                self.tid,
+                exception,
+                uaddr,
+                ip
+            ),
+        }
+        if let Some(pager) = self.fault_handler() {
+            let mut message = Message::blank();
+            message.content = MessageContent::ExceptionMsg {
+                tid: self.tid,
+                exception,
+                uaddr,
+                ip,
+            };
+            let _ = self
+                .ipc(
+                    pager.tid,
+                    pager.tid,
+                    &mut message,
+                    None,
+                    IPCFlags::SEND | IPCFlags::KERNEL,
+                )
+                .await;
+        }
+        self.destroy();
+    }
+
+    /// 获取处理缺页和异常的任务
+    /// 没有 pager 或者 pager 已经退出时交给 root server，root server 和它的线程没有可以交给的任务
+    /// root server 不会接管 pager 已经退出的任务，而是在它们缺页时销毁它们
+    fn fault_handler(&self) -> Option<Arc<MicroKernelTask>> {
+        self.pager
+            .clone()
+            .filter(|x| !*x.destoryed.lock())
+            .or_else(|| tid2task(VM_SERVER)?.downcast_arc::<MicroKernelTask>().ok())
+            .filter(|x| x.addr_space.owner != self.addr_space.owner)
+    }
+
     /// 页表错误处理程序
     pub async fn handle_page_fault(&self) {
         let mut fault = self.fault.lock();
@@ -322,11 +539,17 @@ impl MicroKernelTask {
             if sepc >= VIRT_ADDR_START {
                 panic!("can't trigger page fault in kernel {vaddr} @ {sepc}");
             }
-            // 获取当前 pager
-            if self.pager.is_none() {
-                panic!("unexpected page fault in user task {}, it don't have a pager {vaddr:#x} @ {sepc:#x}", self.tid);
+            // 访问任意一个线程的栈的保护区域，说明发生了栈溢出
+            if self.addr_space.in_stack_guard(vaddr) {
+                self.exit_with_exception(ExceptionType::StackOverflow, vaddr, sepc)
+                    .await;
+                *fault = None;
+                return;
             }
-            let pager = self.pager.clone().unwrap();
+            // 获取处理缺页的 pager，只有 root server 的地址空间中没有 pager
+            let Some(pager) = self.fault_handler() else {
+                panic!("unexpected page fault in user task {}, it don't have a pager {vaddr:#x} @ {sepc:#x}", self.tid);
+            };
             // 设置 message
             let mut message = Message::blank();
             message.content = MessageContent::PageFault {
@@ -341,15 +564,18 @@ impl MicroKernelTask {
                     pager.tid,
                     pager.tid,
                     &mut message,
+                    None,
                     IPCFlags::CALL | IPCFlags::KERNEL,
                 )
                 .await;
+            // 任务在等待 pager 处理时被销毁
+            if *self.destoryed.lock() {
+                *fault = None;
+                return;
+            }
             if ret.is_err() || message.content != MessageContent::PageFaultReply {
-                // *self.exit(exit_code)
-                panic!(
-                    "task exit with exception: {:?}",
-                    ExceptionType::InvalidPagerReply
-                );
+                self.exit_with_exception(ExceptionType::InvalidPagerReply, vaddr, sepc)
+                    .await;
             }
             // Page Fault 处理完毕
             *fault = None;
@@ -358,7 +584,7 @@ impl MicroKernelTask {
 
     /// 获取 PageTable
     pub fn page_table(&self) -> PageTable {
-        self.page_table.0
+        self.addr_space.page_table()
     }
 
     /// 获取 TrapFrame mutable reference
@@ -370,6 +596,28 @@ impl MicroKernelTask {
         }
     }
 
+    /// 将消息转换为 [RawMessage] 后直接写入当前任务在用户态的接收缓冲区中，`source` 为消息来源
+    /// 当前任务没有在用户态等待接收消息或者缓冲区无法访问时返回 false，此时需要通过 `message` 中转
+    pub fn deliver(&self, source: usize, message: &Message) -> bool {
+        let mut recv_buffer = self.recv_buffer.lock();
+        let Some(buffer) = *recv_buffer else {
+            return false;
+        };
+        let mut raw = RawMessage::from(message);
+        raw.header.source = source;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &raw as *const RawMessage as *const u8,
+                size_of::<RawMessage>(),
+            )
+        };
+        if !copy_to_user(self.page_table(), buffer, bytes) {
+            return false;
+        }
+        *recv_buffer = None;
+        true
+    }
+
     /// 给当前任务发送 Notification.
     pub fn notify(&self, notification: Notify) {
         // 如果当前任务正在等待 IPC, 那么直接通知给任务
@@ -411,6 +659,8 @@ impl MicroKernelTask {
             }
             self.handle_page_fault().await;
         }
+        // 线程退出，最后一个线程退出后地址空间会被释放
+        self.addr_space.threads.lock().retain(|x| *x != self.tid);
         log::trace!("task {} exited successfully", self.get_task_id());
     }
 
@@ -434,23 +684,18 @@ impl MicroKernelTask {
         *self.state.lock() = TaskState::Runable;
     }
 
-    /// 申请物理内存
-    pub fn alloc_memory(&self, size: usize, flags: PMAllocFlags) -> usize {
-        let pages = frame_alloc(align_up(size, PAGE_SIZE) / PAGE_SIZE);
-        let start = pages[0].0;
-        // 清空所有页表
-        if flags.contains(PMAllocFlags::ZEROD) {
-            pages.iter().for_each(|x| x.0.drop_clear());
+    /// 获取当前任务的信息
+    pub fn task_info(&self) -> TaskInfo {
+        TaskInfo {
+            tid: self.tid,
+            pager: self.pager.as_ref().map_or(0, |x| x.tid),
+            mem_quota: self.addr_space.mem_quota,
+            mem_used: self.addr_space.mem_used(),
+            pt_pages: self.addr_space.pt_pages.load(Ordering::Relaxed),
+            stack_top: self.stack_top,
+            stack_size: self.stack_size,
+            owner: self.addr_space.owner,
         }
-        self.pages.lock().extend(pages);
-        start.to_addr()
-    }
-
-    /// 映射内存
-    pub fn map_page(&self, vpn: VirtPage, ppn: PhysPage) {
-        log::debug!("map {:?} -> {:?}", vpn, ppn);
-        self.page_table()
-            .map_page(vpn, ppn, MappingFlags::URWX, MappingSize::Page4KB);
     }
 }
 
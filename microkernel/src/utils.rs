@@ -1,11 +1,12 @@
-use core::{marker::PhantomData, str::Utf8Error};
+use core::{marker::PhantomData, mem::size_of};
 
 use alloc::string::{String, ToString};
 use polyhal::{
     addr::VirtAddr,
     pagetable::{MappingFlags, PageTable},
+    PAGE_SIZE, VIRT_ADDR_START,
 };
-use syscall_consts::PageFaultReason;
+use syscall_consts::{PageFaultReason, SysCallError};
 
 use crate::task::MicroKernelTask;
 
@@ -33,21 +34,93 @@ impl<T> From<usize> for UserBuffer<T> {
 /// 判断虚拟地址在当前页表中是否被映射
 #[inline]
 pub fn is_mapped(vaddr: VirtAddr) -> bool {
-    PageTable::current()
+    is_mapped_in(PageTable::current(), vaddr)
+}
+
+/// 判断虚拟地址在 `page_table` 中是否被映射
+#[inline]
+pub fn is_mapped_in(page_table: PageTable, vaddr: VirtAddr) -> bool {
+    page_table
         .translate(vaddr)
         .map(|(_paddr, flags)| flags != MappingFlags::empty())
         .unwrap_or(false)
 }
 
-/// 处理页表错误
-pub async fn handle_page_fault(vaddr: VirtAddr, task: &MicroKernelTask) {
-    if !is_mapped(vaddr) {
-        task.set_fault(vaddr.addr(), PageFaultReason::USER | PageFaultReason::WRITE);
-        task.handle_page_fault().await;
+/// 将 `bytes` 复制到 `page_table` 中的用户地址 `vaddr`，通过页表转换为物理地址后写入
+/// `vaddr` 由用户传入，区域不在用户地址空间中或者有一页没有被映射时不会写入任何数据并返回 false
+pub fn copy_to_user(page_table: PageTable, vaddr: usize, bytes: &[u8]) -> bool {
+    if !vaddr
+        .checked_add(bytes.len())
+        .is_some_and(|end| end <= VIRT_ADDR_START)
+    {
+        return false;
+    }
+    // 按照页拆分，返回每一段的起始偏移和长度
+    let chunks = || {
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            if offset >= bytes.len() {
+                return None;
+            }
+            let addr = vaddr + offset;
+            let len = (PAGE_SIZE - addr % PAGE_SIZE).min(bytes.len() - offset);
+            offset += len;
+            Some((offset - len, len))
+        })
+    };
+    if !chunks().all(|(offset, _)| is_mapped_in(page_table, VirtAddr::new(vaddr + offset))) {
+        return false;
+    }
+    for (offset, len) in chunks() {
+        let (paddr, _) = page_table
+            .translate(VirtAddr::new(vaddr + offset))
+            .expect("can't translate mapped address");
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[offset..].as_ptr(),
+                paddr.get_mut_ptr::<u8>(),
+                len,
+            );
+        }
+    }
+    true
+}
+
+/// 判断虚拟地址在当前页表中是否可写
+#[inline]
+fn is_writable(vaddr: VirtAddr) -> bool {
+    PageTable::current()
+        .translate(vaddr)
+        .map(|(_paddr, flags)| flags.contains(MappingFlags::W))
+        .unwrap_or(false)
+}
+
+/// 处理 `[vaddr, vaddr + size)` 中所有页的页表错误，`reason` 为内核访问这段内存的方式
+/// 任务在处理过程中被销毁、页仍然没有被映射或者需要写入只读的页时返回 [SysCallError::InvalidUaddr]
+pub async fn handle_page_fault(
+    vaddr: VirtAddr,
+    size: usize,
+    task: &MicroKernelTask,
+    reason: PageFaultReason,
+) -> Result<(), SysCallError> {
+    let start = vaddr.addr() / PAGE_SIZE * PAGE_SIZE;
+    let end = vaddr.addr() + size.max(1);
+    for addr in (start..end).step_by(PAGE_SIZE) {
+        let addr = VirtAddr::new(addr.max(vaddr.addr()));
+        if !is_mapped(addr) {
+            task.set_fault(addr.addr(), PageFaultReason::USER | reason);
+            task.handle_page_fault().await;
+        }
+        if *task.destoryed.lock() || !is_mapped(addr) {
+            return Err(SysCallError::InvalidUaddr);
+        }
+        if reason.contains(PageFaultReason::WRITE) && !is_writable(addr) {
+            return Err(SysCallError::InvalidUaddr);
+        }
     }
+    Ok(())
 }
 
-// TODO: Check object cross more pages.
 #[allow(dead_code)]
 impl<T> UserBuffer<T> {
     #[inline]
@@ -55,44 +128,79 @@ impl<T> UserBuffer<T> {
         self.addr.addr()
     }
     #[inline]
-    pub async fn get_ref(&self, task: &MicroKernelTask) -> &'static T {
-        handle_page_fault(self.addr, task).await;
-        self.addr.get_ref::<T>()
+    pub async fn get_ref(&self, task: &MicroKernelTask) -> Result<&'static T, SysCallError> {
+        handle_page_fault(self.addr, size_of::<T>(), task, PageFaultReason::READ).await?;
+        Ok(self.addr.get_ref::<T>())
     }
 
     #[inline]
-    pub async fn get_mut(&self, task: &MicroKernelTask) -> &'static mut T {
-        handle_page_fault(self.addr, task).await;
-        self.addr.get_mut_ref::<T>()
+    pub async fn get_mut(&self, task: &MicroKernelTask) -> Result<&'static mut T, SysCallError> {
+        handle_page_fault(self.addr, size_of::<T>(), task, PageFaultReason::WRITE).await?;
+        Ok(self.addr.get_mut_ref::<T>())
     }
 
+    /// 获取只读的切片，只读的页也可以访问
     #[inline]
-    pub async fn slice_mut_with_len(&self, len: usize, task: &MicroKernelTask) -> &'static mut [T] {
-        handle_page_fault(self.addr, task).await;
-        self.addr.slice_mut_with_len(len)
+    pub async fn slice_with_len(
+        &self,
+        len: usize,
+        task: &MicroKernelTask,
+    ) -> Result<&'static [T], SysCallError> {
+        handle_page_fault(self.addr, len * size_of::<T>(), task, PageFaultReason::READ).await?;
+        Ok(self.addr.slice_with_len(len))
+    }
+
+    #[inline]
+    pub async fn slice_mut_with_len(
+        &self,
+        len: usize,
+        task: &MicroKernelTask,
+    ) -> Result<&'static mut [T], SysCallError> {
+        handle_page_fault(
+            self.addr,
+            len * size_of::<T>(),
+            task,
+            PageFaultReason::WRITE,
+        )
+        .await?;
+        Ok(self.addr.slice_mut_with_len(len))
     }
 }
 
 impl UserBuffer<u8> {
+    /// 读取以 `\0` 结尾的字符串，不包括 `\0`，`max_len` 个字节内没有 `\0` 时返回 [SysCallError::TooLarge]
     #[inline]
-    pub async fn slice_with_until_valid(&self, task: &MicroKernelTask) -> &'static [u8] {
-        handle_page_fault(self.addr, task).await;
+    pub async fn slice_with_until_valid(
+        &self,
+        task: &MicroKernelTask,
+        max_len: usize,
+    ) -> Result<&'static [u8], SysCallError> {
+        let start = self.addr.addr();
         let mut len = 0;
-        let ptr = self.addr.addr() as *const u8;
-        unsafe {
-            loop {
-                if *ptr.add(len) == 0 {
-                    break;
-                }
-                len += 1;
+        loop {
+            if len >= max_len {
+                return Err(SysCallError::TooLarge);
+            }
+            // 字符串可能跨越多个页，每进入一个新的页都需要处理页表错误
+            if len == 0 || (start + len) % PAGE_SIZE == 0 {
+                handle_page_fault(VirtAddr::new(start + len), 1, task, PageFaultReason::READ)
+                    .await?;
+            }
+            if unsafe { *((start + len) as *const u8) } == 0 {
+                break;
             }
-            core::slice::from_raw_parts(ptr, len)
+            len += 1;
         }
+        Ok(unsafe { core::slice::from_raw_parts(start as *const u8, len) })
     }
 
-    pub async fn get_str(&self, task: &MicroKernelTask) -> Result<String, Utf8Error> {
-        handle_page_fault(self.addr, task).await;
-        Ok(String::from_utf8_lossy(self.slice_with_until_valid(task).await).to_string())
+    pub async fn get_str(
+        &self,
+        task: &MicroKernelTask,
+        max_len: usize,
+    ) -> Result<String, SysCallError> {
+        let bytes = self.slice_with_until_valid(task, max_len).await?;
+        Ok(String::from_utf8_lossy(bytes).to_string())
     }
 }
 
@@ -8,6 +8,8 @@ macro_rules! display {
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=target/riscv64gc-unknown-none-elf/release/shell");
-    println!("cargo:rerun-if-changed=target/riscv64gc-unknown-none-elf/release/pong");
+    // vm 是 root server，由内核按照链接地址直接加载，不会被重定位
+    // 这些参数在 .cargo/config.toml 中的 -pie 之后传给链接器，覆盖服务使用的 static-pie
+    println!("cargo:rustc-link-arg-bins=-no-pie");
+    println!("cargo:rustc-link-arg-bins=--defsym=BASE_ADDRESS=0x10000");
 }
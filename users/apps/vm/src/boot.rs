@@ -0,0 +1,81 @@
+//! 启动镜像中的服务
+//! 内核将启动镜像只读映射到 root server 的地址空间，地址和大小通过启动信息中的环境变量传递。
+//! 需要启动的服务由启动清单 [BOOT_MANIFEST] 描述，格式见 [crate::manifest]，
+//! 镜像中没有启动清单时使用默认配置启动除了 root server 之外的所有文件。
+
+use alloc::{borrow::Cow, string::String, vec::Vec};
+use syscall_consts::{
+    BootImage, BOOT_IMAGE_ADDR_ENV, BOOT_IMAGE_SIZE_ENV, BOOT_MANIFEST, ROOT_SERVER_NAME,
+};
+use users::{
+    env::{self, StartupInfo},
+    UserError,
+};
+
+use crate::{
+    manifest::{self, ServiceConfig},
+    supervisor,
+    task::spawn_task,
+};
+
+/// 读取环境变量中 `0x` 开头的十六进制数
+fn env_hex(key: &str) -> Option<usize> {
+    let value = env::var(key)?;
+    usize::from_str_radix(value.strip_prefix("0x")?, 16).ok()
+}
+
+/// 获取内核映射的启动镜像，启动信息中没有启动镜像时返回 None
+pub fn boot_image() -> Option<BootImage<'static>> {
+    let addr = env_hex(BOOT_IMAGE_ADDR_ENV)?;
+    let size = env_hex(BOOT_IMAGE_SIZE_ENV)?;
+    // 启动镜像在 root server 运行期间一直保持映射
+    Some(BootImage::new(unsafe {
+        core::slice::from_raw_parts(addr as *const u8, size)
+    }))
+}
+
+/// 读取启动清单中的服务
+fn boot_services(image: &BootImage) -> Vec<ServiceConfig> {
+    let Some(text) = image.find(BOOT_MANIFEST) else {
+        return image
+            .files()
+            .filter(|x| x.name != ROOT_SERVER_NAME)
+            .map(|x| ServiceConfig::new(x.name))
+            .collect();
+    };
+    manifest::parse(&String::from_utf8_lossy(text)).unwrap_or_else(|err| {
+        println!("[error] {}", err);
+        Vec::new()
+    })
+}
+
+/// 根据配置创建服务的任务
+pub fn spawn_service(
+    image: &BootImage<'static>,
+    config: &ServiceConfig,
+) -> Result<usize, UserError> {
+    let file = image.find(&config.file).ok_or(UserError::NotFound)?;
+    // 第一个参数为服务名称
+    let startup = config
+        .args
+        .iter()
+        .fold(StartupInfo::new().arg(&config.name), |x, arg| x.arg(arg));
+    let startup = config
+        .envs
+        .iter()
+        .fold(startup, |x, (key, value)| x.env(key, value));
+    spawn_task(
+        &config.name,
+        Cow::Borrowed(file),
+        &startup,
+        config.mem_quota,
+    )
+}
+
+/// 启动 servers，由 [supervisor] 在依赖满足后启动并在退出后重启
+pub fn spawn_servers() {
+    match boot_image() {
+        Some(image) => supervisor::supervise(boot_services(&image)),
+        None => println!("[error] can't find boot image"),
+    }
+}
@@ -0,0 +1,50 @@
+//! root server 的堆扩展
+//! root server 不能向自己发送请求，所以直接申请物理内存并映射到自己的地址空间，
+//! 加载的 ELF 文件等大块数据都放在这里
+
+use syscall_consts::PMAllocFlags;
+use users::{
+    align_up,
+    heap::{self, MemorySource},
+    space::{huge_align, map_region},
+    sync::Mutex,
+    syscall::{sys_pm_alloc, sys_pm_free, sys_vm_unmap_range, task_self, translate_vaddr},
+    UserError, PAGE_SIZE,
+};
+
+/// 扩展堆使用的虚拟地址，位于 ELF 段和栈区域之上
+const HEAP_START: usize = 0x10_0000_0000;
+
+/// 堆的最大大小，需要能够容纳加载的 ELF 文件
+const HEAP_MAX_SIZE: usize = 0x1000_0000;
+
+/// 下一次申请使用的虚拟地址，释放的虚拟地址不会重复使用
+static HEAP_NEXT: Mutex<usize> = Mutex::new(HEAP_START);
+
+/// 申请物理内存并映射到 root server 的地址空间，线程和主线程共享地址空间，所以使用 task_self
+fn alloc(size: usize) -> Result<usize, UserError> {
+    let paddr = sys_pm_alloc(task_self(), size, PMAllocFlags::ALIGNED.bits())?;
+    let uaddr = {
+        let mut next = HEAP_NEXT.lock();
+        let uaddr = align_up(*next, huge_align(size));
+        *next = uaddr + size;
+        uaddr
+    };
+    map_region(task_self(), uaddr, paddr, size).inspect_err(|_| {
+        let _ = sys_pm_free(task_self(), paddr, size);
+    })?;
+    Ok(uaddr)
+}
+
+/// 取消映射并释放通过 [alloc] 申请的内存
+fn free(uaddr: usize, size: usize) -> Result<(), UserError> {
+    let size = align_up(size, PAGE_SIZE);
+    let paddr = translate_vaddr(uaddr)?;
+    sys_vm_unmap_range(task_self(), uaddr, size)?;
+    sys_pm_free(task_self(), paddr, size)
+}
+
+/// 允许 root server 扩展堆
+pub fn init() {
+    heap::set_source(MemorySource { alloc, free }, HEAP_MAX_SIZE);
+}
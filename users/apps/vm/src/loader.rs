@@ -0,0 +1,88 @@
+//! 从文件系统加载 ELF 文件并创建任务
+//! 读取文件需要向 fs 服务发送请求，而 fs 服务发生缺页时又需要 root server 处理，
+//! 所以文件在后台线程中读取，读取完成后唤醒主线程，由主线程创建任务并回复请求的任务
+
+use alloc::{borrow::Cow, string::String, vec::Vec};
+use syscall_consts::{
+    IPCFlags, Message, MessageContent, MessageType, RawMessage, SpawnTaskReply, PATH_LEN, VM_SERVER,
+};
+use users::{
+    env::StartupInfo,
+    get_string_from_slice, rpc,
+    sync::Mutex,
+    syscall::{fs_read_file, ipc_reply, sys_ipc},
+    thread, UserError,
+};
+
+use crate::{
+    service::find_service,
+    task::{spawn_task, DEFAULT_MEM_QUOTA},
+};
+
+/// 读取完成的文件
+struct Loaded {
+    /// 请求加载文件的任务
+    source: usize,
+    /// 文件路径
+    path: String,
+    /// 文件内容
+    file: Result<Vec<u8>, UserError>,
+}
+
+/// 读取完成，等待主线程创建任务的文件
+static LOADED: Mutex<Vec<Loaded>> = Mutex::new(Vec::new());
+
+/// 回复请求加载文件的任务
+fn reply(source: usize, result: Result<usize, UserError>) {
+    let raw = match result {
+        Ok(tid) => RawMessage::new(MessageType::SpawnTaskReplyMsg, SpawnTaskReply { tid }),
+        Err(err) => rpc::error_reply(err),
+    };
+    let mut message = Message {
+        source: VM_SERVER,
+        content: MessageContent::raw(raw),
+    };
+    ipc_reply(source, &mut message);
+}
+
+/// 处理 `source` 加载 `path` 的请求，文件读取完成后才会回复
+pub fn load(source: usize, path: [u8; PATH_LEN]) {
+    let path = get_string_from_slice(&path);
+    let Some(fs) = find_service("fs") else {
+        return reply(source, Err(UserError::NotFound));
+    };
+    let ret = thread::spawn(move || {
+        let file = fs_read_file(fs, &path);
+        LOADED.lock().push(Loaded { source, path, file });
+        // 空消息只用来唤醒主线程
+        if let Err(err) = sys_ipc(VM_SERVER, 0, &mut Message::blank(), IPCFlags::SEND) {
+            println!("[error] failed to wake up root server: {}", err);
+        }
+    });
+    if let Err(err) = ret {
+        reply(source, Err(err));
+    }
+}
+
+/// 由主线程为读取完成的文件创建任务并回复
+pub fn finish() {
+    let loaded: Vec<_> = LOADED.lock().drain(..).collect();
+    loaded
+        .into_iter()
+        .for_each(|Loaded { source, path, file }| {
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            let ret = file.and_then(|file| {
+                spawn_task(
+                    name,
+                    Cow::Owned(file),
+                    &StartupInfo::new().arg(&path),
+                    DEFAULT_MEM_QUOTA,
+                )
+            });
+            match ret {
+                Ok(tid) => println!("spawn task {} id {}", path, tid),
+                Err(err) => println!("can't spawn task {}: {}", path, err),
+            }
+            reply(source, ret);
+        });
+}
@@ -1,20 +1,31 @@
 #![no_std]
 #![no_main]
-#![feature(concat_idents)]
 
+mod boot;
+mod heap;
+mod loader;
+mod manifest;
+mod service;
+mod supervisor;
 mod task;
 
-use alloc::string::{String, ToString};
 use syscall_consts::{
-    Message,
+    ExitStatus, Message,
     MessageContent::{self, *},
-    IPC_ANY,
+    MessageType, PageFaultReason, SpawnTaskRequest, IPC_ANY, NAME_LEN,
 };
-use users::syscall::{
-    ipc_recv, ipc_reply, sys_pm_alloc, sys_time, sys_uptime, sys_vm_map, task_destory, task_self,
+use users::{
+    pager::{self, Pager},
+    rpc,
+    syscall::{ipc_recv, ipc_reply, sys_uptime, take_async_message, task_info, task_self},
+    UserError,
 };
 
-use crate::task::{register_service, spawn_servers, SERVICE_LIST, TASK_LIST};
+use crate::{
+    boot::spawn_servers,
+    service::{find_service, register_service, service_name, unregister_service, wait_for_service},
+    task::{owner_of, remove_task, watch_task, TASK_LIST},
+};
 
 #[macro_use]
 extern crate users;
@@ -24,8 +35,8 @@ extern crate alloc;
 fn main() {
     println!("Hello World!");
     println!("Root server id: {}", task_self());
-    // 设置定时器
-    sys_time(5000);
+    // 允许扩展堆，用于保存运行时加载的 ELF 文件
+    heap::init();
     // 输出系统时间
     println!("UPTIME: {}", sys_uptime());
     // 启动 servers
@@ -33,141 +44,74 @@ fn main() {
     loop {
         let mut message = Message::blank();
         // 等待并接收 IPC 消息
-        ipc_recv(IPC_ANY, &mut message);
+        if let Err(err) = ipc_recv(IPC_ANY, &mut message) {
+            println!("root server failed to receive message: {}", err);
+            continue;
+        }
+        // 缺页、任务退出和内存管理的消息
+        if pager::handle(&mut RootPager, &mut message) {
+            continue;
+        }
         match message.content {
             // 时钟消息
             NotifyTimer => {
                 println!("Notify Timer");
                 println!("UPTIME: {}", sys_uptime());
+                // 重新启动等待时间已经结束的服务，定时器由 supervisor 统一设置
+                supervisor::on_timer();
                 // shutdown();
             }
             // 服务注册消息
             ServiceRegisterMsg { name_buffer } => {
-                // 确保注册服务的来源是已经在任务列表中
-                assert!(TASK_LIST
-                    .lock()
-                    .iter()
-                    .find(|x| x.tid == message.source)
-                    .is_some());
                 // 获取需要注册的服务名称
-                let name = String::from_utf8_lossy(&name_buffer).to_string();
-
-                // 注册一个服务
-                register_service(message.source, name);
-
-                // 回复消息
-                message.content = MessageContent::ServiceRegisterReplyMsg;
+                let name = service_name(&name_buffer);
+                // 注册服务，名称已经被注册时回复错误
+                message.content = match register_service(message.source, name) {
+                    Ok(()) => MessageContent::ServiceRegisterReplyMsg,
+                    Err(err) => MessageContent::raw(rpc::error_reply(err)),
+                };
                 ipc_reply(message.source, &mut message);
             }
             // 服务查找消息
             ServiceLookupMsg { name_buffer } => {
                 // 获取需要搜索的服务名称
-                let name = String::from_utf8_lossy(&name_buffer).to_string();
-                // 在服务列表中查找服务
-                let service = SERVICE_LIST.lock().iter().find(|x| x.name == name).cloned();
-                // 如果服务已经注册了，直接处理
-                // 如果未注册，那么等待注册后唤醒
-                if let Some(service) = service {
-                    message.content = MessageContent::ServiceLookupReplyMsg(service.task_id);
+                let name = service_name(&name_buffer);
+                // 如果服务已经注册了，直接回复
+                // 如果未注册，那么等待注册后回复
+                if let Some(tid) = find_service(&name) {
+                    message.content = MessageContent::ServiceLookupReplyMsg(tid);
                     ipc_reply(message.source, &mut message);
                 } else {
-                    TASK_LIST
-                        .lock()
-                        .iter_mut()
-                        .find(|x| x.tid == message.source)
-                        .map(|x| {
-                            x.waiting_for = name;
-                        });
+                    wait_for_service(message.source, name);
                 }
             }
-            // 页错误
-            PageFault {
-                tid,
-                uaddr,
-                ip,
-                fault,
-            } => {
-                // println!(
-                //     "IPC page fault: {}, {:#x} @ {:#x} reason: {:?}",
-                //     tid, uaddr, ip, fault
-                // );
-
-                let ret = TASK_LIST
-                    .lock()
-                    .iter()
-                    .find(|x| x.tid == tid)
-                    .map(|x| x.handle_page_fault(uaddr, ip, fault))
-                    .expect("can't find page fault task in root server");
-
-                if ret.is_err() {
-                    println!("task fault: {:?}", ret.err());
-                    task_destory(tid);
+            // 通过服务接口定义的请求
+            // 加载文件的线程读取完成
+            MessageContent::None if owner_of(message.source) == task_self() => loader::finish(),
+            Raw(ref request) => {
+                // 加载文件需要等待 fs 服务，读取完成后再回复
+                if request.msg_type() == Some(MessageType::SpawnTaskMsg) {
+                    if let Some(payload) = request.payload::<SpawnTaskRequest>() {
+                        loader::load(message.source, payload.path);
+                    } else {
+                        message.content =
+                            MessageContent::raw(rpc::error_reply(UserError::InvalidArg));
+                        ipc_reply(message.source, &mut message);
+                    }
                     continue;
                 }
-
-                message.content = PageFaultReply;
-                ipc_reply(tid, &mut message);
-            }
-            // 申请物理内存消息
-            VmAllocPhysicalMsg { size } => {
-                // 确保发信消息在队列中
-                assert!(TASK_LIST
-                    .lock()
-                    .iter()
-                    .find(|x| x.tid == message.source)
-                    .is_some());
-
-                // TODO: use mapping attrs to improve security
-                let ret = sys_pm_alloc(message.source, size, 0);
-
-                // 如果申请失败，直接跳过了
-                if ret < 0 {
-                    break;
-                };
-
-                // 准备申请内存
-                let paddr = ret as usize;
-                let uaddr = TASK_LIST
-                    .lock()
-                    .iter_mut()
-                    .find(|x| x.tid == message.source)
-                    .map(|x| x.alloc_size(size))
-                    .unwrap();
-                message.content = VmAllocPhysicalReplyMsg { uaddr, paddr };
-
-                // 映射内存
-                assert!(
-                    sys_vm_map(message.source, uaddr, paddr, 0) >= 0,
-                    "can't map virtual address"
-                );
-                ipc_reply(message.source, &mut message);
-            }
-            VmMapPhysicalMsg {
-                paddr,
-                size,
-                map_flags: _,
-            } => {
-                // 确保发信消息在队列中
-                assert!(TASK_LIST
-                    .lock()
-                    .iter()
-                    .find(|x| x.tid == message.source)
-                    .is_some());
-
-                // 申请虚拟内存
-                let uaddr = TASK_LIST
-                    .lock()
-                    .iter_mut()
-                    .find(|x| x.tid == message.source)
-                    .map(|x| x.alloc_size(size))
-                    .unwrap();
-
-                // 映射内存
-                sys_vm_map(message.source, uaddr, paddr, 0);
-
-                // 回复消息
-                message.content = VmMapPhysicalReplyMsg { uaddr };
-                ipc_reply(message.source, &mut message);
+                // 任务取回之前发送失败的退出通知
+                if request.msg_type() == Some(MessageType::AsyncRecvMsg) {
+                    let reply = take_async_message(message.source)
+                        .unwrap_or_else(|| rpc::error_reply(UserError::NotFound));
+                    message.content = MessageContent::raw(reply);
+                    ipc_reply(message.source, &mut message);
+                    continue;
+                }
+                if let Some(reply) = rpc::vm::dispatch(&mut VmServer, request) {
+                    message.content = MessageContent::raw(reply);
+                    ipc_reply(message.source, &mut message);
+                }
             }
             _ => {
                 // println!("ipc message: {:#x?}", message);
@@ -175,3 +119,108 @@ fn main() {
         }
     }
 }
+
+/// root server 作为 pager 管理自己创建的任务，状态都保存在 [TASK_LIST] 中
+/// 线程和所属的任务共享地址空间，所以请求都由线程所属的任务处理
+struct RootPager;
+
+impl Pager for RootPager {
+    fn page_fault(
+        &mut self,
+        tid: usize,
+        uaddr: usize,
+        ip: usize,
+        fault: PageFaultReason,
+    ) -> Result<(), UserError> {
+        // pager 已经退出的任务的缺页也会发送到这里，它们不在 TASK_LIST 中，返回错误后会被销毁
+        let owner = owner_of(tid);
+        TASK_LIST
+            .lock()
+            .iter_mut()
+            .find(|x| x.space.tid == owner)
+            .ok_or(UserError::InvalidTask)?
+            .space
+            .handle_page_fault(tid, uaddr, ip, fault)
+    }
+
+    fn task_exit(&mut self, tid: usize, status: ExitStatus) {
+        remove_task(tid, status);
+    }
+
+    fn alloc_memory(&mut self, source: usize, size: usize) -> Result<(usize, usize), UserError> {
+        let owner = owner_of(source);
+        let ret = TASK_LIST
+            .lock()
+            .iter_mut()
+            .find(|x| x.space.tid == owner)
+            .ok_or(UserError::InvalidTask)
+            .and_then(|x| x.space.alloc_memory(source, size));
+        // 申请失败时输出任务的内存使用情况
+        if ret.is_err() {
+            println!("task {} memory usage: {:x?}", source, task_info(source));
+        }
+        ret
+    }
+
+    fn map_physical(
+        &mut self,
+        source: usize,
+        paddr: usize,
+        size: usize,
+    ) -> Result<usize, UserError> {
+        let owner = owner_of(source);
+        TASK_LIST
+            .lock()
+            .iter_mut()
+            .find(|x| x.space.tid == owner)
+            .ok_or(UserError::InvalidTask)?
+            .space
+            .map_physical(source, paddr, size)
+    }
+}
+
+impl rpc::pager::Server for RootPager {
+    fn free_memory(&mut self, source: usize, uaddr: usize, size: usize) -> Result<(), UserError> {
+        let owner = owner_of(source);
+        TASK_LIST
+            .lock()
+            .iter_mut()
+            .find(|x| x.space.tid == owner)
+            .ok_or(UserError::InvalidTask)?
+            .space
+            .free_memory(uaddr, size)
+    }
+
+    fn unmap_physical(
+        &mut self,
+        source: usize,
+        uaddr: usize,
+        size: usize,
+    ) -> Result<(), UserError> {
+        let owner = owner_of(source);
+        TASK_LIST
+            .lock()
+            .iter_mut()
+            .find(|x| x.space.tid == owner)
+            .ok_or(UserError::InvalidTask)?
+            .space
+            .unmap_physical(uaddr, size)
+    }
+}
+
+/// 处理服务接口中定义的请求，状态都保存在 [TASK_LIST] 中
+struct VmServer;
+
+impl rpc::vm::Server for VmServer {
+    fn find_service(&mut self, _source: usize, name: [u8; NAME_LEN]) -> Result<usize, UserError> {
+        find_service(&service_name(&name)).ok_or(UserError::NotFound)
+    }
+
+    fn watch_tasks(&mut self, source: usize, tid: usize) -> Result<(), UserError> {
+        watch_task(source, tid)
+    }
+
+    fn unregister_service(&mut self, source: usize, name: [u8; NAME_LEN]) -> Result<(), UserError> {
+        unregister_service(source, &service_name(&name))
+    }
+}
@@ -0,0 +1,230 @@
+//! 启动清单
+//! 启动清单是启动镜像中的文本文件，每个 `[name]` 段描述一个需要启动的服务，段中是 `key = value` 格式的配置:
+//!
+//! - `file`: 启动镜像中 ELF 文件的名称，默认为服务名称
+//! - `arg`: 追加的参数，第一个参数总是服务名称，可以出现多次
+//! - `env`: `key=value` 格式的环境变量，可以出现多次
+//! - `depends`: 依赖的服务名称，以空格或者 `,` 分隔，这些服务都注册后才会启动，
+//!   依赖的服务必须由清单中的服务提供，并且依赖之间不能有环
+//! - `restart`: 退出后的重启策略，可以是 `never`、`on-failure` 或者 `always`，默认为 `never`
+//! - `quota`: 内存配额 (单位: 页)，支持十进制和 `0x` 开头的十六进制，不能为 0
+//!
+//! 空行和 `#` 开头的行会被忽略，服务按照清单中的顺序启动
+
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+use syscall_consts::ExitStatus;
+
+use crate::task::DEFAULT_MEM_QUOTA;
+
+/// 服务退出后的重启策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// 不重启
+    Never,
+    /// 退出码不为 0 或者因为异常被销毁时重启
+    OnFailure,
+    /// 总是重启
+    Always,
+}
+
+impl RestartPolicy {
+    /// 服务以 `status` 退出后是否需要重启
+    pub fn should_restart(&self, status: ExitStatus) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// 启动清单中的一个服务
+#[derive(Debug, Clone)]
+pub struct ServiceConfig {
+    /// 服务名称，也是任务名称和第一个参数
+    pub name: String,
+    /// 启动镜像中 ELF 文件的名称
+    pub file: String,
+    /// 服务名称之后的参数
+    pub args: Vec<String>,
+    /// 环境变量
+    pub envs: Vec<(String, String)>,
+    /// 启动前需要注册的服务
+    pub depends: Vec<String>,
+    /// 重启策略
+    pub restart: RestartPolicy,
+    /// 内存配额 (单位: 页)
+    pub mem_quota: usize,
+    /// 服务在清单中所在的行号，不在清单中时为 0
+    pub line: usize,
+}
+
+impl ServiceConfig {
+    /// 使用默认配置创建服务，ELF 文件和服务名称相同
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            file: name.to_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            depends: Vec::new(),
+            restart: RestartPolicy::Never,
+            mem_quota: DEFAULT_MEM_QUOTA,
+            line: 0,
+        }
+    }
+
+    /// 服务注册的名称，服务使用环境变量 `service` 中的名称注册，没有时使用服务名称
+    pub fn provides(&self) -> &str {
+        self.envs
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "service")
+            .map_or(&self.name, |(_, value)| value)
+    }
+
+    /// 设置 `key` 对应的配置
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        match key {
+            "file" => self.file = value.to_string(),
+            "arg" => self.args.push(value.to_string()),
+            "env" => {
+                let (key, value) = value.split_once('=').ok_or("env must be key=value")?;
+                self.envs
+                    .push((key.trim().to_string(), value.trim().to_string()));
+            }
+            "depends" => self.depends.extend(
+                value
+                    .split(|x: char| x == ',' || x.is_whitespace())
+                    .filter(|x| !x.is_empty())
+                    .map(String::from),
+            ),
+            "restart" => {
+                self.restart = match value {
+                    "never" => RestartPolicy::Never,
+                    "on-failure" => RestartPolicy::OnFailure,
+                    "always" => RestartPolicy::Always,
+                    _ => return Err("unknown restart policy"),
+                }
+            }
+            "quota" => {
+                let quota = match value.strip_prefix("0x") {
+                    Some(hex) => usize::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                self.mem_quota = match quota {
+                    Ok(0) | Err(_) => return Err("invalid quota"),
+                    Ok(quota) => quota,
+                };
+            }
+            _ => return Err("unknown key"),
+        }
+        Ok(())
+    }
+}
+
+/// 解析启动清单时的错误
+#[derive(Debug, Clone, Copy)]
+pub struct ManifestError {
+    /// 出错的行号，从 1 开始
+    pub line: usize,
+    /// 错误原因
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "manifest line {}: {}", self.line, self.reason)
+    }
+}
+
+/// 解析启动清单
+pub fn parse(text: &str) -> Result<Vec<ServiceConfig>, ManifestError> {
+    let mut services: Vec<ServiceConfig> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |reason| ManifestError {
+            line: index + 1,
+            reason,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // 新的服务
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.strip_suffix(']').ok_or(error("missing ']'"))?.trim();
+            if name.is_empty() {
+                return Err(error("empty service name"));
+            }
+            if services.iter().any(|x| x.name == name) {
+                return Err(error("duplicate service"));
+            }
+            let mut service = ServiceConfig::new(name);
+            service.line = index + 1;
+            services.push(service);
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or(error("expect key = value"))?;
+        services
+            .last_mut()
+            .ok_or(error("key outside of service"))?
+            .set(key.trim(), value.trim())
+            .map_err(error)?;
+    }
+    check_depends(&services)?;
+    Ok(services)
+}
+
+/// 搜索依赖时服务的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    New,
+    /// 正在搜索这个服务的依赖
+    Active,
+    Done,
+}
+
+/// 检查依赖的服务都由清单中的服务提供，并且依赖之间没有环
+fn check_depends(services: &[ServiceConfig]) -> Result<(), ManifestError> {
+    // 每个服务依赖的服务在清单中的下标
+    let mut depends = Vec::new();
+    for service in services {
+        let indexes = service
+            .depends
+            .iter()
+            .map(|name| services.iter().position(|x| x.provides() == name))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(ManifestError {
+                line: service.line,
+                reason: "unknown dependency",
+            })?;
+        depends.push(indexes);
+    }
+    let mut visits = vec![Visit::New; services.len()];
+    for index in 0..services.len() {
+        visit(index, &depends, &mut visits).map_err(|index| ManifestError {
+            line: services[index].line,
+            reason: "dependency cycle",
+        })?;
+    }
+    Ok(())
+}
+
+/// 深度优先搜索服务 `index` 的依赖，再次遇到正在搜索的服务时存在环，返回这个服务的下标
+fn visit(index: usize, depends: &[Vec<usize>], visits: &mut [Visit]) -> Result<(), usize> {
+    match visits[index] {
+        Visit::Done => return Ok(()),
+        Visit::Active => return Err(index),
+        Visit::New => visits[index] = Visit::Active,
+    }
+    for &depend in &depends[index] {
+        visit(depend, depends, visits)?;
+    }
+    visits[index] = Visit::Done;
+    Ok(())
+}
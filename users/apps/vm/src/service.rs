@@ -0,0 +1,103 @@
+//! 服务注册表
+//! 服务名称在注册表中唯一，同一个名称只能由一个任务注册，任务退出时它注册的服务会被移除。
+//! 查找还没有注册的服务时，请求的任务会一直等待，直到服务注册后才回复
+
+use alloc::{string::String, vec::Vec};
+use syscall_consts::{Message, MessageContent};
+use users::{sync::Mutex, syscall::ipc_reply, UserError};
+
+use crate::{supervisor::start_pending, task::owner_of};
+
+/// 微内核服务
+#[derive(Debug, Clone)]
+pub struct Service {
+    /// 服务名称
+    pub name: String,
+    /// 服务对应的任务 ID，也就是注册服务的线程
+    pub task_id: usize,
+    /// 注册服务的线程所在的任务
+    pub owner: usize,
+}
+
+/// 服务列表
+pub static SERVICE_LIST: Mutex<Vec<Service>> = Mutex::new(Vec::new());
+
+/// 正在等待服务注册的任务和服务名称
+static LOOKUP_WAITERS: Mutex<Vec<(usize, String)>> = Mutex::new(Vec::new());
+
+/// 将定长缓冲区中以 `\0` 结尾的服务名称转换为字符串
+pub fn service_name(buffer: &[u8]) -> String {
+    let len = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into()
+}
+
+/// 注册一个服务，名称已经被其他任务注册时返回 [UserError::AlreadyExists]
+/// 同一个线程重复注册同一个名称不会出错
+pub fn register_service(tid: usize, name: String) -> Result<(), UserError> {
+    if name.is_empty() {
+        return Err(UserError::InvalidArg);
+    }
+    let mut services = SERVICE_LIST.lock();
+    if let Some(service) = services.iter().find(|x| x.name == name) {
+        return match service.task_id == tid {
+            true => Ok(()),
+            false => Err(UserError::AlreadyExists),
+        };
+    }
+    services.push(Service {
+        name: name.clone(),
+        task_id: tid,
+        owner: owner_of(tid),
+    });
+    drop(services);
+
+    // 回复正在等待该服务的任务
+    LOOKUP_WAITERS.lock().retain(|(waiter, waiting_for)| {
+        if *waiting_for != name {
+            return true;
+        }
+        let mut message = Message::blank();
+        message.content = MessageContent::ServiceLookupReplyMsg(tid);
+        ipc_reply(*waiter, &mut message);
+        false
+    });
+    // 启动依赖该服务的服务
+    start_pending();
+    Ok(())
+}
+
+/// 注销服务，只有注册服务的任务和它的线程可以注销
+pub fn unregister_service(tid: usize, name: &str) -> Result<(), UserError> {
+    let mut services = SERVICE_LIST.lock();
+    let index = services
+        .iter()
+        .position(|x| x.name == name)
+        .ok_or(UserError::NotFound)?;
+    if services[index].owner != owner_of(tid) {
+        return Err(UserError::NotAllowed);
+    }
+    services.remove(index);
+    Ok(())
+}
+
+/// 查找服务对应的任务 ID
+pub fn find_service(name: &str) -> Option<usize> {
+    SERVICE_LIST
+        .lock()
+        .iter()
+        .find(|x| x.name == name)
+        .map(|x| x.task_id)
+}
+
+/// `tid` 等待 `name` 服务注册，注册后回复服务的任务 ID
+pub fn wait_for_service(tid: usize, name: String) {
+    LOOKUP_WAITERS.lock().push((tid, name));
+}
+
+/// 移除已经销毁的任务注册的服务以及它的等待
+pub fn remove_services(tid: usize) {
+    SERVICE_LIST
+        .lock()
+        .retain(|x| x.task_id != tid && x.owner != tid);
+    LOOKUP_WAITERS.lock().retain(|x| x.0 != tid);
+}
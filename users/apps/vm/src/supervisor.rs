@@ -0,0 +1,206 @@
+//! 服务监督
+//! root server 是所有服务的 pager，服务主动退出、发生异常或者被销毁时都会经过 [crate::task::remove_task]，
+//! 启动清单中的服务退出后按照重启策略重新启动。
+//!
+//! 服务退出时它注册的服务名称会被移除，这期间查找服务的任务会等待，直到重新启动的服务再次注册。
+//! 连续重启之间的等待时间从 [BACKOFF_MIN] 开始每次翻倍，最多为 [BACKOFF_MAX]，
+//! 服务稳定运行 [STABLE_TIME] 之后再退出时重新从 [BACKOFF_MIN] 开始，创建任务失败时也按照同样的方式重试。
+//!
+//! 内核只为每个任务保留一个定时器，新的设置会覆盖之前的设置，所以 root server 的定时器只由这里设置，
+//! 到期时间为开机检查 [BOOT_TIMEOUT] 和等待重启的服务中最早的一个
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use syscall_consts::ExitStatus;
+use users::{
+    sync::Mutex,
+    syscall::{sys_time, sys_uptime},
+    UserError,
+};
+
+use crate::{
+    boot::{boot_image, spawn_service},
+    manifest::ServiceConfig,
+    service::find_service,
+};
+
+/// 重启前的最短等待时间 (单位: ms)
+const BACKOFF_MIN: usize = 100;
+
+/// 重启前的最长等待时间 (单位: ms)
+const BACKOFF_MAX: usize = 10_000;
+
+/// 服务运行超过这个时间 (单位: ms) 后认为已经稳定
+const STABLE_TIME: usize = 10_000;
+
+/// 开机后经过这个时间 (单位: ms) 还在等待依赖的服务会输出警告
+const BOOT_TIMEOUT: usize = 5000;
+
+/// 被监督的服务
+struct Supervised {
+    config: ServiceConfig,
+    /// 服务正在运行时的任务 ID
+    tid: Option<usize>,
+    /// 是否等待 (重新) 启动
+    pending: bool,
+    /// 最早的启动时间 (单位: ms)
+    start_at: usize,
+    /// 最近一次启动的时间 (单位: ms)
+    started_at: usize,
+    /// 上一次重启前的等待时间 (单位: ms)，0 表示还没有重启过
+    backoff: usize,
+    /// 重启的次数
+    restarts: usize,
+}
+
+impl Supervised {
+    /// 在等待一段时间之后重新启动服务，稳定运行一段时间之后退出时重新计算等待时间
+    fn schedule_restart(&mut self, now: usize) {
+        self.backoff = match self.backoff == 0 || now - self.started_at >= STABLE_TIME {
+            true => BACKOFF_MIN,
+            false => (self.backoff * 2).min(BACKOFF_MAX),
+        };
+        self.start_at = now + self.backoff;
+        self.pending = true;
+    }
+}
+
+/// 启动清单中的所有服务
+static SERVICES: Mutex<Vec<Supervised>> = Mutex::new(Vec::new());
+
+/// 是否已经检查过开机时还在等待依赖的服务
+static BOOT_CHECKED: AtomicBool = AtomicBool::new(false);
+
+/// 已经设置给内核的定时器的到期时间 (单位: ms)
+static ARMED: Mutex<Option<usize>> = Mutex::new(None);
+
+/// 监督启动清单中的服务，服务会在依赖满足后启动
+pub fn supervise(configs: Vec<ServiceConfig>) {
+    SERVICES
+        .lock()
+        .extend(configs.into_iter().map(|config| Supervised {
+            config,
+            tid: None,
+            pending: true,
+            start_at: 0,
+            started_at: 0,
+            backoff: 0,
+            restarts: 0,
+        }));
+    start_pending();
+}
+
+/// 启动等待时间已经结束并且依赖已经满足的服务，然后为下一个需要等待的服务设置定时器
+/// 在服务注册和定时器到期时调用
+pub fn start_pending() {
+    let Some(image) = boot_image() else {
+        return;
+    };
+    let now = sys_uptime();
+    let mut services = SERVICES.lock();
+    services
+        .iter_mut()
+        .filter(|x| x.pending && x.start_at <= now)
+        .filter(|x| x.config.depends.iter().all(|x| find_service(x).is_some()))
+        .for_each(|service| {
+            // 创建失败时输出原因，继续启动其他的 server
+            service.pending = false;
+            service.started_at = now;
+            match spawn_service(&image, &service.config) {
+                Ok(tid) => {
+                    println!("spawn task {} id {}", service.config.name, tid);
+                    service.tid = Some(tid);
+                }
+                // 启动镜像是只读的，找不到 ELF 文件时重试也不会成功
+                Err(UserError::NotFound) => println!(
+                    "task {} creation failed: {} not found in boot image",
+                    service.config.name, service.config.file
+                ),
+                Err(err) => {
+                    service.schedule_restart(now);
+                    println!(
+                        "task {} creation failed because: {}, retry in {} ms",
+                        service.config.name, err, service.backoff
+                    );
+                }
+            }
+        });
+
+    // 等待重启的服务在定时器到期后启动
+    let next = services
+        .iter()
+        .filter(|x| x.pending && x.start_at > now)
+        .map(|x| x.start_at)
+        .min();
+    drop(services);
+    arm_timer(now, next);
+}
+
+/// 将定时器设置为 `next` 和开机检查中较早的到期时间，`now` 为当前时间
+fn arm_timer(now: usize, next: Option<usize>) {
+    let boot = match BOOT_CHECKED.load(Ordering::Relaxed) {
+        true => None,
+        false => Some(BOOT_TIMEOUT),
+    };
+    let deadline = [next, boot].into_iter().flatten().min();
+    let mut armed = ARMED.lock();
+    // 已经设置的定时器还没有到期并且不晚于需要的到期时间时不需要重新设置
+    if armed.is_some_and(|x| x > now && deadline.map_or(false, |deadline| x <= deadline)) {
+        return;
+    }
+    // 0 表示取消定时器
+    let ms = deadline.map_or(0, |deadline| deadline.saturating_sub(now).max(1));
+    match sys_time(ms) {
+        Ok(()) => *armed = deadline,
+        Err(err) => println!("[error] failed to set timer: {}", err),
+    }
+}
+
+/// 定时器到期，检查开机时的服务并启动等待时间已经结束的服务
+pub fn on_timer() {
+    *ARMED.lock() = None;
+    check_boot();
+    start_pending();
+}
+
+/// 开机 [BOOT_TIMEOUT] 之后输出还在等待依赖的服务，只输出一次
+fn check_boot() {
+    if sys_uptime() < BOOT_TIMEOUT || BOOT_CHECKED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    for service in SERVICES.lock().iter().filter(|x| x.pending) {
+        let missing: Vec<_> = service
+            .config
+            .depends
+            .iter()
+            .filter(|x| find_service(x).is_none())
+            .collect();
+        if !missing.is_empty() {
+            println!(
+                "[warn] service {} is still pending, waiting for {:?}",
+                service.config.name, missing
+            );
+        }
+    }
+}
+
+/// 启动清单中的服务退出后根据重启策略安排重新启动
+pub fn on_task_exit(tid: usize, status: ExitStatus) {
+    let mut services = SERVICES.lock();
+    let Some(service) = services.iter_mut().find(|x| x.tid == Some(tid)) else {
+        return;
+    };
+    service.tid = None;
+    if !service.config.restart.should_restart(status) {
+        return println!("service {} (task {}) {}", service.config.name, tid, status);
+    }
+
+    service.schedule_restart(sys_uptime());
+    service.restarts += 1;
+    println!(
+        "service {} (task {}) {}, restart #{} in {} ms",
+        service.config.name, tid, status, service.restarts, service.backoff
+    );
+    drop(services);
+    start_pending();
+}
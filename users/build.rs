@@ -6,7 +6,19 @@ macro_rules! display {
     ($fmt:expr, $($arg:tt)*) => (println!(concat!("cargo:warning=", $fmt), $($arg)*));
 }
 
+use std::{env, fs, path::Path};
+
+/// 服务接口定义所在的 IDL 文件
+const IDL_FILE: &str = "../crates/syscall_consts/messages.idl";
+
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=users/target/riscv64gc-unknown-none-elf/release/shell");
+    println!("cargo:rerun-if-changed={}", IDL_FILE);
+
+    // 根据服务接口生成客户端函数和服务端 trait
+    let source = fs::read_to_string(IDL_FILE).expect("can't read messages.idl");
+    let idl = msg_idl::parse(&source).unwrap_or_else(|err| panic!("{}: {}", IDL_FILE, err));
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("rpc.rs"), idl.to_rust_rpc()).unwrap();
 }
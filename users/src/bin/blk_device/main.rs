@@ -7,10 +7,12 @@ mod virtio_impl;
 use core::ptr::NonNull;
 
 use spin::{Lazy, Mutex};
-use syscall_consts::{Message, MessageContent, IPC_ANY};
+use syscall_consts::RawMessage;
 use users::{
-    syscall::{ipc_recv, map_paddr},
-    BLOCK_SIZE, PAGE_SIZE,
+    rpc::block_device,
+    server::{Context, Reply, Runtime, Server},
+    syscall::map_paddr,
+    UserError, BLOCK_SIZE, PAGE_SIZE,
 };
 use virtio_drivers::{device::blk::VirtIOBlk, transport::mmio::MmioTransport};
 use virtio_impl::HalImpl;
@@ -93,24 +95,53 @@ pub fn init(level: Option<&str>) {
     });
 }
 
+/// virtio 块设备服务
+struct VirtIOBlkServer;
+
+impl block_device::Server for VirtIOBlkServer {
+    fn read_block(
+        &mut self,
+        _source: usize,
+        block_index: usize,
+    ) -> Result<[u8; BLOCK_SIZE], UserError> {
+        let mut buffer = [0u8; BLOCK_SIZE];
+        BLK_DEVICE
+            .lock()
+            .read_blocks(block_index, &mut buffer)
+            .map_err(|_| UserError::InvalidArg)?;
+        Ok(buffer)
+    }
+
+    fn write_block(
+        &mut self,
+        _source: usize,
+        block_index: usize,
+        buffer: [u8; BLOCK_SIZE],
+    ) -> Result<(), UserError> {
+        BLK_DEVICE
+            .lock()
+            .write_blocks(block_index, &buffer)
+            .map_err(|_| UserError::InvalidArg)
+    }
+
+    fn capacity(&mut self, _source: usize) -> Result<usize, UserError> {
+        Ok(BLK_DEVICE.lock().capacity() as usize)
+    }
+}
+
+impl Server for VirtIOBlkServer {
+    fn handle(&mut self, _ctx: &Context, request: &RawMessage) -> Reply {
+        block_device::dispatch(self, request).into()
+    }
+}
+
 #[no_mangle]
 fn main() {
     init(Some("debug"));
-    let mut message = Message::blank();
 
     // TODO: Fix virtio block
     // init_virtio_blk();
 
-    println!("register blk_device service!");
-    // ipc_register("blk_device");
-
-    loop {
-        ipc_recv(IPC_ANY, &mut message);
-        match message.content {
-            // MessageContent::BlkWriteReplyMsg()
-            // Doing Nothing here.
-            MessageContent::PageFaultReply => {}
-            _ => println!("unhandled message: {:?}", message.content),
-        }
-    }
+    // TODO: 修复 virtio 之后使用 .register("blk_device") 注册服务
+    Runtime::new().run(&mut VirtIOBlkServer);
 }
@@ -43,7 +43,7 @@ unsafe impl Hal for HalImpl {
     unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
         let vaddr = buffer.as_ptr() as *mut u8 as usize;
         println!("share {:#x}", vaddr);
-        let target_address = translate_vaddr(vaddr).unwrap_or(0);
+        let target_address = translate_vaddr(vaddr).expect("can't translate shared buffer");
         println!("target address {:#x}", target_address);
         target_address
     }
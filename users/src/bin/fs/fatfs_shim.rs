@@ -1,9 +1,12 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use users::syscall::block_read;
 
 pub struct DiskCursor {
     pub sector: u64,
     pub offset: usize,
-    pub blk_tid: usize,
+    /// 块设备服务的 task id，块设备服务重启后由文件系统服务更新
+    pub blk_tid: Arc<AtomicUsize>,
 }
 
 unsafe impl Sync for DiskCursor {}
@@ -39,7 +42,12 @@ impl fatfs::Read for DiskCursor {
         let read_size = if self.offset != 0 || buf.len() < 512 {
             let mut data = vec![0u8; 512];
             // device.read_blocks(self.sector as usize, &mut data);
-            block_read(self.blk_tid, self.sector as _, &mut data);
+            block_read(
+                self.blk_tid.load(Ordering::Relaxed),
+                self.sector as _,
+                &mut data,
+            )
+            .map_err(|_| ())?;
 
             let start = self.offset;
             let end = (self.offset + buf.len()).min(512);
@@ -51,7 +59,8 @@ impl fatfs::Read for DiskCursor {
             let rlen = (buf.len() / 512) * 512;
             assert!(rlen % 0x200 == 0);
             // 如果不用同一个数组 会导致读取数据的时候出现问题
-            block_read(self.blk_tid, self.sector as _, buf);
+            block_read(self.blk_tid.load(Ordering::Relaxed), self.sector as _, buf)
+                .map_err(|_| ())?;
             rlen
         };
         self.move_cursor(read_size);
@@ -4,8 +4,17 @@
 
 mod fatfs_shim;
 
-use syscall_consts::{Message, MessageContent, IPC_ANY, NAME_LEN};
-use users::syscall::{ipc_recv, ipc_register, ipc_reply, service_lookup};
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fatfs::{Read, Seek, SeekFrom};
+use syscall_consts::{ExitStatus, MessageType, RawMessage, BLOCK_SIZE, PATH_LEN};
+use users::{
+    env, get_string_from_slice, rpc,
+    server::{Context, Reply, Runtime, Server},
+    sync::Mutex,
+    syscall::{service_lookup, watch_task},
+    UserError,
+};
 
 use crate::fatfs_shim::DiskCursor;
 
@@ -14,23 +23,178 @@ extern crate users;
 #[macro_use]
 extern crate alloc;
 
+/// 上一次读取的文件，从读取结束的位置继续读取时直接使用
+/// 否则每次读取都需要重新打开文件，并且从第一个簇开始查找偏移所在的簇
+struct OpenFile {
+    path: [u8; PATH_LEN],
+    /// 下一次读取的偏移
+    offset: usize,
+    file: fatfs::File<'static, DiskCursor>,
+}
+
+/// 文件系统，实现 fs 服务接口，主线程和工作线程通过锁共享
+struct FileSystem {
+    /// 文件系统在服务运行期间一直存在，打开的文件可以借用它
+    fs: &'static fatfs::FileSystem<DiskCursor>,
+    last: Option<OpenFile>,
+}
+
+// 打开的文件引用了文件系统，所以不能在线程间传递，但是所有的访问都在 FsServer 的锁中进行
+unsafe impl Send for FileSystem {}
+
+/// 文件系统服务
+struct FsServer {
+    fs: Arc<Mutex<FileSystem>>,
+    /// 块设备服务名称
+    blk_device: String,
+    /// 块设备服务的 task id，和 [DiskCursor] 共享
+    blk_tid: Arc<AtomicUsize>,
+}
+
+/// 将 fatfs 的错误转换为 [UserError]
+fn fs_error<T>(err: fatfs::Error<T>) -> UserError {
+    match err {
+        fatfs::Error::NotFound => UserError::NotFound,
+        fatfs::Error::InvalidInput => UserError::InvalidArg,
+        _ => UserError::Others,
+    }
+}
+
+/// 查找块设备服务 `name` 并监控它的退出，返回服务的 task id
+/// 服务可能在查找之后、监控之前退出，这时等待它重新注册后再次监控
+fn watch_block_service(name: &str) -> usize {
+    loop {
+        let tid = service_lookup(name).expect("can't find blk_device");
+        match watch_task(tid) {
+            Ok(()) => return tid,
+            Err(err) => println!("[fs] block service {} exited before watching: {}", tid, err),
+        }
+    }
+}
+
+impl FileSystem {
+    /// 打开文件，`path` 为以 `\0` 结尾的路径，路径从根目录开始
+    fn open(&self, path: &[u8]) -> Result<fatfs::File<'static, DiskCursor>, UserError> {
+        let path = get_string_from_slice(path);
+        self.fs
+            .root_dir()
+            .open_file(path.trim_start_matches('/'))
+            .map_err(fs_error)
+    }
+}
+
+impl rpc::fs::Server for FileSystem {
+    fn read_dir(
+        &mut self,
+        _source: usize,
+        _path: [u8; PATH_LEN],
+        index: usize,
+    ) -> Result<([u8; PATH_LEN], usize), UserError> {
+        // TODO: use path instead of fixed root path
+        // let mut path = get_string_from_slice(&path).trim().to_string();
+        // let dir = self.fs.root_dir().open_dir(&path)?;
+        // 遍历文件夹，num 为 0 表示没有更多的文件
+        let mut buffer = [0u8; PATH_LEN];
+        match self.fs.root_dir().iter().skip(index).next() {
+            Some(file) => {
+                let name = file.map_err(|_| UserError::Others)?.file_name();
+                let bytes = name.as_bytes();
+                buffer[..bytes.len()].copy_from_slice(bytes);
+                Ok((buffer, 1))
+            }
+            None => Ok((buffer, 0)),
+        }
+    }
+
+    fn file_size(&mut self, _source: usize, path: [u8; PATH_LEN]) -> Result<usize, UserError> {
+        let mut file = self.open(&path)?;
+        let size = file.seek(SeekFrom::End(0)).map_err(fs_error)?;
+        Ok(size as usize)
+    }
+
+    fn read_file(
+        &mut self,
+        _source: usize,
+        path: [u8; PATH_LEN],
+        offset: usize,
+    ) -> Result<([u8; BLOCK_SIZE], usize), UserError> {
+        // 顺序读取时继续使用上一次打开的文件
+        let mut file = match self.last.take() {
+            Some(last) if last.path == path && last.offset == offset => last.file,
+            _ => {
+                let mut file = self.open(&path)?;
+                file.seek(SeekFrom::Start(offset as u64))
+                    .map_err(fs_error)?;
+                file
+            }
+        };
+        // 一次 read 不一定能读满，一直读取到块满或者文件结尾
+        let mut buffer = [0u8; BLOCK_SIZE];
+        let mut len = 0;
+        while len < BLOCK_SIZE {
+            match file.read(&mut buffer[len..]).map_err(fs_error)? {
+                0 => break,
+                size => len += size,
+            }
+        }
+        if len > 0 {
+            self.last = Some(OpenFile {
+                path,
+                offset: offset + len,
+                file,
+            });
+        }
+        Ok((buffer, len))
+    }
+}
+
+impl Server for FsServer {
+    /// 读取文件需要多次请求块设备服务，交给工作线程处理，主线程可以继续接收其他请求
+    fn handle(&mut self, ctx: &Context, request: &RawMessage) -> Reply {
+        if request.msg_type() != Some(MessageType::ReadFileMsg) {
+            return rpc::fs::dispatch(&mut *self.fs.lock(), request).into();
+        }
+        let deferred = ctx.defer(request);
+        let fs = self.fs.clone();
+        let request = *request;
+        ctx.execute(move || {
+            if let Some(reply) = rpc::fs::dispatch(&mut *fs.lock(), &request) {
+                deferred.reply(reply);
+            }
+        });
+        Reply::Later
+    }
+
+    /// 块设备服务退出后等待它重新注册，期间不处理其他请求
+    fn on_task_destroyed(&mut self, _ctx: &Context, tid: usize, status: ExitStatus) {
+        if tid != self.blk_tid.load(Ordering::Relaxed) {
+            return;
+        }
+        println!("[fs] block service {} {}, waiting for restart", tid, status);
+        let blk_tid = watch_block_service(&self.blk_device);
+        self.blk_tid.store(blk_tid, Ordering::Relaxed);
+        println!("[fs] find block service {}", blk_tid);
+    }
+}
+
 #[no_mangle]
 fn main() {
-    let mut message = Message::blank();
+    // 注册的服务名称和使用的块设备服务由启动信息指定
+    let service = env::var("service").unwrap_or(String::from("fs"));
+    let blk_device = env::var("blk_device").unwrap_or(String::from("blk_device"));
 
-    println!("register fs service!");
-    ipc_register("fs");
-
-    // 获取块设备 task id
-    let block_device_tid = service_lookup("blk_device").expect("can't find blk_device");
+    // 获取块设备 task id，块设备服务退出时会收到通知
+    let block_device_tid = watch_block_service(&blk_device);
+    let blk_tid = Arc::new(AtomicUsize::new(block_device_tid));
 
     let cursor: DiskCursor = DiskCursor {
-        blk_tid: block_device_tid,
+        blk_tid: blk_tid.clone(),
         sector: 0,
         offset: 0,
     };
     // 获取文件系统地址
     let fs = fatfs::FileSystem::new(cursor, fatfs::FsOptions::new()).expect("can't open fatfs");
+    let fs = Box::leak(Box::new(fs));
 
     println!("[fs] find root dir");
     fs.root_dir().iter().for_each(|x| {
@@ -39,43 +203,13 @@ fn main() {
     });
 
     println!("[fs] find block service {}", block_device_tid);
-    loop {
-        ipc_recv(IPC_ANY, &mut message);
-        match message.content {
-            // 读取文件夹
-            MessageContent::FSReadDirMsg { path, index } => {
-                // 定义一个闭包函数用来返回
-                let mut reply = |file: Option<&str>| {
-                    let mut buffer = [0u8; 2 * NAME_LEN];
-                    if let Some(file) = file {
-                        let bytes = file.as_bytes();
-                        buffer[..bytes.len()].copy_from_slice(bytes);
-                    }
-                    message.content = MessageContent::FSReadDirReplyMsg {
-                        buffer,
-                        num: file.map_or_else(|| 0, |_| 1),
-                    };
-                    ipc_reply(message.source, &mut message);
-                };
-                // TODO: use path instead of fixed root path
-                // let mut path = get_string_from_slice(&path).trim().to_string();
-                // let dir = match fs.root_dir().open_dir("/") {
-                //     Ok(dir) => dir,
-                //     Err(_) => {
-                //         reply(None);
-                //         continue;
-                //     }
-                // };
-                // 遍历文件夹
-                let dir = fs.root_dir();
-                match dir.iter().skip(index).next() {
-                    Some(file) => reply(Some(&file.unwrap().file_name())),
-                    None => reply(None),
-                }
-            }
-            // Doing Nothing here.
-            MessageContent::PageFaultReply => {}
-            _ => println!("unhandled message: {:?}", message.content),
-        }
-    }
+    // 文件系统准备好之后再注册服务，所有的请求共用一个文件系统，一个工作线程就足够了
+    Runtime::new()
+        .register(&service)
+        .workers(1)
+        .run(&mut FsServer {
+            fs: Arc::new(Mutex::new(FileSystem { fs, last: None })),
+            blk_device,
+            blk_tid,
+        });
 }
@@ -2,31 +2,39 @@
 #![no_main]
 #![feature(exclusive_range_pattern)]
 
-use syscall_consts::{Message, MessageContent, IPC_ANY};
-use users::syscall::{ipc_recv, ipc_register, ipc_reply, task_self};
+use syscall_consts::RawMessage;
+use users::{
+    rpc::ping,
+    server::{Context, Reply, Runtime, Server},
+    syscall::task_self,
+    UserError,
+};
 
 #[macro_use]
 extern crate users;
 extern crate alloc;
 
-#[no_mangle]
-fn main() {
-    let mut message = Message::blank();
+/// ping 服务，收到 ping 之后回复 42
+struct Pong;
 
-    println!("register ping service!");
-    ipc_register("pong");
+impl ping::Server for Pong {
+    fn ping(&mut self, _source: usize, value: usize) -> Result<usize, UserError> {
+        println!("task {} received ping {}", task_self(), value);
+        Ok(42)
+    }
+}
 
-    loop {
-        ipc_recv(IPC_ANY, &mut message);
-        match message.content {
-            MessageContent::PingMsg(value) => {
-                println!("task {} received ping {}", task_self(), value);
-                message.content = MessageContent::PingReplyMsg(42);
-                ipc_reply(message.source, &mut message);
-            }
-            // Doing Nothing here.
-            MessageContent::PageFaultReply => {}
-            _ => println!("unhandled message: {:?}", message.content),
-        }
+impl Server for Pong {
+    fn handle(&mut self, _ctx: &Context, request: &RawMessage) -> Reply {
+        ping::dispatch(self, request).into()
     }
 }
+
+#[no_mangle]
+fn main() {
+    // ping 请求只有一个字，通过寄存器接收和回复
+    Runtime::new()
+        .register("pong")
+        .short_requests()
+        .run(&mut Pong);
+}
@@ -4,15 +4,17 @@
 
 use core::arch::global_asm;
 
-use syscall_consts::{Message, MessageContent, IPC_ANY};
+use alloc::string::String;
+use syscall_consts::{MessageType, RawMessage, FROM_KERNEL};
 use users::{
-    syscall::{ipc_recv, ipc_register, ipc_reply},
-    BLOCK_SIZE,
+    env, executor,
+    rpc::{self, block_device},
+    syscall::ipc_register,
+    UserError, BLOCK_SIZE,
 };
 
 #[macro_use]
 extern crate users;
-#[macro_use]
 extern crate alloc;
 
 global_asm!(
@@ -63,44 +65,65 @@ impl RamDiskImpl {
     }
 }
 
-#[no_mangle]
-fn main() {
-    let mut message = Message::blank();
-    println!("register ramdisk for blk_device service!");
-    ipc_register("blk_device");
+/// 块设备服务
+struct RamDisk;
+
+impl block_device::Server for RamDisk {
+    fn read_block(
+        &mut self,
+        _source: usize,
+        block_index: usize,
+    ) -> Result<[u8; BLOCK_SIZE], UserError> {
+        if block_index >= self.capacity(0)? {
+            return Err(UserError::InvalidArg);
+        }
+        let mut buffer = [0; BLOCK_SIZE];
+        RamDiskImpl::read_block(block_index, &mut buffer);
+        Ok(buffer)
+    }
 
+    fn write_block(
+        &mut self,
+        _source: usize,
+        block_index: usize,
+        buffer: [u8; BLOCK_SIZE],
+    ) -> Result<(), UserError> {
+        if block_index >= self.capacity(0)? {
+            return Err(UserError::InvalidArg);
+        }
+        RamDiskImpl::write_block(block_index, &buffer);
+        Ok(())
+    }
+
+    fn capacity(&mut self, _source: usize) -> Result<usize, UserError> {
+        Ok(RamDiskImpl::get_ram_disk().len() / BLOCK_SIZE)
+    }
+}
+
+/// 在执行器中接收并处理请求，回复由执行器发送
+async fn serve() {
     loop {
-        ipc_recv(IPC_ANY, &mut message);
-        match message.content {
-            // 获取 块设备容量大小
-            MessageContent::GetBlockCapacity => {
-                message.content = MessageContent::GetBlockCapacityReplyMsg(
-                    RamDiskImpl::get_ram_disk().len() / 0x200,
-                );
-                ipc_reply(message.source, &mut message);
-            }
-            // 读取块设备
-            MessageContent::ReadBlockMsg { block_index } => {
-                let mut buffer = vec![0; BLOCK_SIZE];
-                RamDiskImpl::read_block(block_index, &mut buffer);
-                message.content = MessageContent::ReadBlockReplyMsg {
-                    buffer: buffer.try_into().unwrap(),
-                };
-                ipc_reply(message.source, &mut message);
+        let message = executor::recv().await;
+        let request = RawMessage::from(&message);
+        let reply = match block_device::dispatch(&mut RamDisk, &request) {
+            Some(reply) => reply,
+            // 不回复内核和错误消息，避免两个服务互相回复错误
+            None if message.source == FROM_KERNEL
+                || request.msg_type() == Some(MessageType::ErrorReplyMsg) =>
+            {
+                continue
             }
-            // 写入块设备
-            MessageContent::WriteBlockMsg {
-                block_index,
-                buffer,
-            } => {
-                RamDiskImpl::write_block(block_index, &buffer);
-                message.content = MessageContent::WriteBlockReplyMsg;
-                ipc_reply(message.source, &mut message);
-            }
-            // MessageContent::BlkWriteReplyMsg()
-            // Doing Nothing here.
-            MessageContent::PageFaultReply => {}
-            _ => println!("unhandled message: {:?}", message.content),
-        }
+            None => rpc::error_reply(UserError::NotSupported),
+        };
+        executor::reply_to(message.source, reply);
     }
 }
+
+#[no_mangle]
+fn main() {
+    // 注册的服务名称由启动信息指定
+    let service = env::var("service").unwrap_or(String::from("blk_device"));
+    println!("ramdisk for {} service", service);
+    ipc_register(&service).expect("can't register service");
+    executor::block_on(serve());
+}
@@ -2,11 +2,17 @@
 #![no_main]
 #![feature(exclusive_range_pattern)]
 
+mod sandbox;
+
 use alloc::{string::String, vec::Vec};
-use syscall_consts::{Message, MessageContent, IPC_ANY};
+use syscall_consts::{
+    IPCFlags, Message, MessageContent, MessageType, RawMessage, ValuePayload, IPC_ANY, VM_SERVER,
+};
 use users::syscall::{
-    fs_read_dir, get_block_capacity, ipc_call, ipc_recv, serial_read, serial_write, service_lookup, shutdown, sys_time, sys_uptime, task_self
+    fs_read_dir, ipc_recv, serial_read, serial_write, service_lookup, service_try_lookup, shutdown,
+    spawn_task, sys_ipc, sys_time, sys_uptime, task_self, watch_task,
 };
+use users::{rpc, thread};
 
 #[macro_use]
 extern crate users;
@@ -19,12 +25,76 @@ const DL: u8 = b'\x7f';
 const BS: u8 = b'\x08';
 const SPACE: u8 = b' ';
 
+/// IPC 测试的往返次数
+const BENCH_ROUNDS: usize = 10000;
+
+/// 测试 IPC 往返延迟，创建一个线程回复当前线程发送的消息
+/// `flags` 为额外的 IPC 标志，`call` 为 false 时分别发送和接收，发送后会让出 CPU，
+/// 返回每次往返的平均时间，单位: us
+/// 内核不会把时间片直接交给接收方，两种方式的差别就是让出 CPU 后等待调度的开销
+fn ipc_bench(flags: IPCFlags, call: bool) -> usize {
+    let shell_tid = task_self();
+    let echo_tid = thread::spawn(move || {
+        let mut message = Message::blank();
+        for _ in 0..BENCH_ROUNDS {
+            sys_ipc(0, shell_tid, &mut message, IPCFlags::RECV).expect("bench recv failed");
+            sys_ipc(shell_tid, 0, &mut message, IPCFlags::SEND | flags)
+                .expect("bench reply failed");
+        }
+    })
+    .expect("can't create ipc bench thread");
+
+    let mut message = Message::blank();
+    let start = sys_uptime();
+    for i in 0..BENCH_ROUNDS {
+        let ping = RawMessage::new(MessageType::PingMsg, ValuePayload { value: i });
+        message.content = MessageContent::raw(ping);
+        if call {
+            sys_ipc(echo_tid, echo_tid, &mut message, IPCFlags::CALL | flags)
+                .expect("bench call failed");
+        } else {
+            sys_ipc(echo_tid, 0, &mut message, IPCFlags::SEND | flags).expect("bench send failed");
+            sys_ipc(0, echo_tid, &mut message, IPCFlags::RECV | flags).expect("bench recv failed");
+        }
+    }
+    (sys_uptime() - start) * 1000 / BENCH_ROUNDS
+}
+
+/// 监控文件系统服务，服务退出时输出退出码，然后等待服务重新注册
+fn watch_fs() {
+    let mut message = Message::blank();
+    loop {
+        let fs_tid = match service_lookup("fs").and_then(|tid| watch_task(tid).map(|_| tid)) {
+            Ok(tid) => tid,
+            Err(err) => {
+                println!("[shell] can't watch fs: {}", err);
+                continue;
+            }
+        };
+        loop {
+            if let Err(err) = ipc_recv(IPC_ANY, &mut message) {
+                println!("[shell] failed to receive message: {}", err);
+                continue;
+            }
+            match message.content {
+                MessageContent::TaskDestroyedMsg { tid, status }
+                    if message.source == VM_SERVER && tid == fs_tid =>
+                {
+                    println!("\n[shell] fs {} {}", tid, status);
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
 /// 读取一行数据
 fn read_line() -> String {
     let mut tmp = [0u8; 32];
     let mut buffer = Vec::new();
     loop {
-        let len = serial_read(&mut tmp);
+        let len = serial_read(&mut tmp).unwrap_or(0);
         if len == 0 {
             continue;
         }
@@ -42,7 +112,7 @@ fn read_line() -> String {
                 BS | DL => {
                     if buffer.len() > 0 {
                         buffer.pop();
-                        serial_write(&[BS, SPACE, BS]);
+                        let _ = serial_write(&[BS, SPACE, BS]);
                     }
                 }
                 // 特殊字符
@@ -50,7 +120,7 @@ fn read_line() -> String {
                 // 其他字符
                 _ => {
                     buffer.push(tmp[i] as u8);
-                    serial_write(&tmp[i..i + 1]);
+                    let _ = serial_write(&tmp[i..i + 1]);
                 }
             }
         }
@@ -66,8 +136,11 @@ fn main() {
     println!("UPTIME: {}", sys_uptime());
 
     // 等待 100ms 其他任务启动完毕，否则 log 可能会混乱
-    sys_time(100);
-    ipc_recv(IPC_ANY, &mut message);
+    sys_time(100).expect("can't set timer");
+    ipc_recv(IPC_ANY, &mut message).expect("can't wait for timer");
+
+    // 在线程中监控文件系统服务，不影响读取输入
+    thread::spawn(watch_fs).expect("can't create fs watcher thread");
 
     loop {
         print!("\x1b[1mshell> \x1b[0m");
@@ -79,31 +152,63 @@ fn main() {
             "" => {}
             // Ping-Pong 命令，测试 IPC 和服务
             "ping" => {
-                if let Some(task_pong_id) = service_lookup("pong") {
-                    message.content = MessageContent::PingMsg(321);
+                let reply = service_try_lookup("pong").and_then(|task_pong_id| {
                     println!("Send ping message {} to vm server", 321);
-                    ipc_call(task_pong_id, &mut message);
-                    println!("Ping message reply {:?}", message.content);
+                    rpc::ping::ping(task_pong_id, 321)
+                });
+                match reply {
+                    Ok(value) => println!("Ping message reply {}", value),
+                    Err(err) => println!("ping failed: {}", err),
                 }
             }
-            // 显示所有的 block 设备，目前只有一个
-            "disks" => {
-                if let Some(blk_dev_tid) = service_lookup("blk_device") {
-                    println!(
-                        "block device capactiy {} MB",
-                        get_block_capacity(blk_dev_tid).unwrap_or(0) / 2048
-                    );
-                }
+            // 测试 IPC 延迟，对比快速路径和普通路径
+            "bench" => {
+                println!("ipc round trips: {}", BENCH_ROUNDS);
+                println!("fast path call: {} us", ipc_bench(IPCFlags::empty(), true));
+                println!(
+                    "fast path send + recv: {} us",
+                    ipc_bench(IPCFlags::empty(), false)
+                );
+                println!(
+                    "slow path call: {} us",
+                    ipc_bench(IPCFlags::NO_FAST_PATH, true)
+                );
             }
+            // 显示所有的 block 设备，目前只有一个
+            "disks" => match service_try_lookup("blk_device").and_then(rpc::block_device::capacity)
+            {
+                Ok(blocks) => println!("block device capactiy {} MB", blocks / 2048),
+                Err(err) => println!("can't get block device capacity: {}", err),
+            },
             // 列出文件夹下所有的文件
             "ls" => {
-                if let Some(fs_tid) = service_lookup("fs") {
+                let files = service_try_lookup("fs").and_then(|fs_tid| {
                     println!("fs tid is: {}", fs_tid);
-                    let files = fs_read_dir(fs_tid, ".");
-                    println!("files: {}", files.len());
-                    files.iter().for_each(|x| {
-                        println!("{:>4} {:<8}", "", x);
-                    });
+                    fs_read_dir(fs_tid, ".")
+                });
+                match files {
+                    Ok(files) => {
+                        println!("files: {}", files.len());
+                        files.iter().for_each(|x| {
+                            println!("{:>4} {:<8}", "", x);
+                        });
+                    }
+                    Err(err) => println!("can't read dir: {}", err),
+                }
+            }
+            // 从文件系统加载程序，由 shell 作为 pager 在沙箱中运行
+            cmd if cmd.starts_with("run --sandbox ") => {
+                let path = cmd["run --sandbox ".len()..].trim();
+                if let Err(err) = sandbox::run(path) {
+                    println!("can't run {} in sandbox: {}", path, err);
+                }
+            }
+            // 从文件系统加载并运行程序
+            cmd if cmd.starts_with("run ") => {
+                let path = cmd["run ".len()..].trim();
+                match spawn_task(path) {
+                    Ok(tid) => println!("task {} started, id {}", path, tid),
+                    Err(err) => println!("can't run {}: {}", path, err),
                 }
             }
             // 关机
@@ -113,7 +218,18 @@ fn main() {
             // 输出帮助信息
             "help" | _ => {
                 println!("commands available are below:");
-                ["help", "ping", "disks", "ls", "exit"].iter().for_each(|x| {
+                [
+                    "help",
+                    "ping",
+                    "bench",
+                    "disks",
+                    "ls",
+                    "run <path>",
+                    "run --sandbox <path>",
+                    "exit",
+                ]
+                .iter()
+                .for_each(|x| {
                     println!("{:>10}", x);
                 });
             }
@@ -0,0 +1,127 @@
+//! 在沙箱中运行程序
+//! shell 创建一个线程作为子任务的 pager，子任务的缺页、申请内存和退出都由这个线程处理，
+//! 子任务只能使用有限的内存，内核只允许它映射自己申请的物理页，pager 线程也不会为它映射设备内存。
+//! pager 线程在子任务退出后结束。如果 pager 线程先于子任务退出，没有任务接管子任务，
+//! 它的缺页会发送给不认识它的 root server，root server 会销毁它
+
+use alloc::{borrow::Cow, string::String};
+use syscall_consts::{ExitStatus, Message, PageFaultReason, IPC_ANY};
+use users::{
+    env::StartupInfo,
+    pager::{self, Pager},
+    rpc,
+    space::AddressSpace,
+    syscall::{fs_read_file, ipc_recv, service_try_lookup, task_info, task_self},
+    thread, UserError,
+};
+
+/// 沙箱中的任务的内存配额 (单位: 页)
+const SANDBOX_MEM_QUOTA: usize = 0x1000;
+
+/// 作为沙箱中的任务的 pager，只管理一个任务和它的线程
+struct Sandbox {
+    /// 沙箱中的任务的地址空间
+    space: AddressSpace,
+    /// 任务退出后的状态
+    status: Option<ExitStatus>,
+}
+
+impl Sandbox {
+    /// 检查 `tid` 是沙箱中的任务或者它的线程
+    fn check(&self, tid: usize) -> Result<(), UserError> {
+        if tid == self.space.tid || task_info(tid)?.owner == self.space.tid {
+            return Ok(());
+        }
+        Err(UserError::InvalidTask)
+    }
+}
+
+impl Pager for Sandbox {
+    fn page_fault(
+        &mut self,
+        tid: usize,
+        uaddr: usize,
+        ip: usize,
+        fault: PageFaultReason,
+    ) -> Result<(), UserError> {
+        self.check(tid)?;
+        self.space.handle_page_fault(tid, uaddr, ip, fault)
+    }
+
+    fn task_exit(&mut self, tid: usize, status: ExitStatus) {
+        if tid == self.space.tid {
+            self.status = Some(status);
+        } else {
+            self.space.remove_stack(tid);
+        }
+    }
+
+    fn alloc_memory(&mut self, source: usize, size: usize) -> Result<(usize, usize), UserError> {
+        self.check(source)?;
+        self.space.alloc_memory(source, size)
+    }
+
+    fn map_physical(
+        &mut self,
+        _source: usize,
+        _paddr: usize,
+        _size: usize,
+    ) -> Result<usize, UserError> {
+        Err(UserError::NotAllowed)
+    }
+}
+
+impl rpc::pager::Server for Sandbox {
+    fn free_memory(&mut self, source: usize, uaddr: usize, size: usize) -> Result<(), UserError> {
+        self.check(source)?;
+        self.space.free_memory(uaddr, size)
+    }
+
+    fn unmap_physical(
+        &mut self,
+        _source: usize,
+        _uaddr: usize,
+        _size: usize,
+    ) -> Result<(), UserError> {
+        Err(UserError::NotAllowed)
+    }
+}
+
+/// 从文件系统读取 `path` 并在沙箱中运行，返回 pager 线程的 ID
+/// 任务在 pager 线程中创建，创建的结果和退出状态都由 pager 线程输出
+pub fn run(path: &str) -> Result<usize, UserError> {
+    let file = service_try_lookup("fs").and_then(|fs| fs_read_file(fs, path))?;
+    let path = String::from(path);
+    thread::spawn(move || {
+        let name = path.rsplit('/').next().unwrap_or(&path);
+        let startup = StartupInfo::new().arg(&path);
+        let space = match AddressSpace::spawn(
+            name,
+            Cow::Owned(file),
+            &startup,
+            task_self(),
+            SANDBOX_MEM_QUOTA,
+            0,
+        ) {
+            Ok(space) => space,
+            Err(err) => return println!("[shell] can't run {} in sandbox: {}", path, err),
+        };
+        println!("[shell] sandbox {} started, id {}", path, space.tid);
+        let mut sandbox = Sandbox {
+            space,
+            status: None,
+        };
+        let mut message = Message::blank();
+        while sandbox.status.is_none() {
+            if let Err(err) = ipc_recv(IPC_ANY, &mut message) {
+                println!("[shell] sandbox failed to receive message: {}", err);
+                continue;
+            }
+            // 沙箱中的任务只会向 pager 发送 pager 协议中的消息，忽略其他消息
+            pager::handle(&mut sandbox, &mut message);
+        }
+        if let Some(status) = sandbox.status {
+            println!("[shell] sandbox {} {}", path, status);
+        }
+    })
+}
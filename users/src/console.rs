@@ -8,7 +8,7 @@ struct WriteImpl;
 
 impl Write for WriteImpl {
     fn write_str(&mut self, s: &str) -> Result<(), Error> {
-        let rsize = serial_write(s.as_bytes());
+        let rsize = serial_write(s.as_bytes()).map_err(|_| Error)?;
         assert_eq!(
             rsize,
             s.as_bytes().len(),
@@ -37,3 +37,8 @@ pub fn print(args: core::fmt::Arguments) {
         .write_str(&format!("{}", args))
         .expect("can't write string in logging module.");
 }
+
+/// 不申请内存的输出，用于堆无法使用时输出错误信息，输出可能会被拆分为多次系统调用
+pub(crate) fn print_unbuffered(args: core::fmt::Arguments) {
+    let _ = WriteImpl.write_fmt(args);
+}
@@ -0,0 +1,159 @@
+//! 任务启动信息，包括参数、环境变量和初始的能力 (任务 ID)
+//! 父任务通过 [StartupInfo] 生成启动信息并在创建任务时传入，
+//! 内核会将启动信息复制到新任务的栈顶，新任务可以在 `main` 中通过 [args], [var] 和 [caps] 读取
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use syscall_consts::{StartupHeader, STARTUP_MAX_SIZE};
+
+/// 启动信息中一个字的大小
+const WORD: usize = size_of::<usize>();
+
+/// 当前任务启动信息的地址，为 0 时表示没有启动信息
+static STARTUP_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+/// 记录启动信息的地址，由 `_start` 调用
+pub(crate) fn init(addr: usize) {
+    STARTUP_ADDR.store(addr, Ordering::Relaxed);
+}
+
+/// 任务启动信息
+#[derive(Debug, Clone, Default)]
+pub struct StartupInfo {
+    /// 参数，第一个参数通常为任务名称
+    pub args: Vec<String>,
+    /// 环境变量
+    pub envs: Vec<(String, String)>,
+    /// 初始能力，目前为可以直接通信的任务 ID
+    pub caps: Vec<usize>,
+}
+
+impl StartupInfo {
+    /// 创建空的启动信息
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加一个参数
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// 添加一个环境变量，`key` 中不能包含 `=`
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        assert!(!key.contains('='), "env key can't contain '='");
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// 添加一个初始能力
+    pub fn cap(mut self, tid: usize) -> Self {
+        self.caps.push(tid);
+        self
+    }
+
+    /// 生成传递给内核的启动信息，布局见 [StartupHeader]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.resize(size_of::<StartupHeader>(), 0);
+        self.caps
+            .iter()
+            .for_each(|cap| bytes.extend_from_slice(&cap.to_ne_bytes()));
+        self.args.iter().for_each(|arg| {
+            bytes.extend_from_slice(arg.as_bytes());
+            bytes.push(0);
+        });
+        self.envs.iter().for_each(|(key, value)| {
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.push(b'=');
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        });
+        assert!(bytes.len() <= STARTUP_MAX_SIZE, "startup info is too large");
+
+        // 填充信息头
+        let header = StartupHeader {
+            size: bytes.len(),
+            argc: self.args.len(),
+            envc: self.envs.len(),
+            capc: self.caps.len(),
+        };
+        let words = [header.size, header.argc, header.envc, header.capc];
+        words.iter().enumerate().for_each(|(i, word)| {
+            bytes[i * WORD..(i + 1) * WORD].copy_from_slice(&word.to_ne_bytes())
+        });
+        bytes
+    }
+
+    /// 从启动信息中解析，启动信息不完整时返回 None
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let word = |index: usize| -> Option<usize> {
+            let bytes = bytes.get(index * WORD..(index + 1) * WORD)?;
+            Some(usize::from_ne_bytes(bytes.try_into().ok()?))
+        };
+        let (size, argc, envc, capc) = (word(0)?, word(1)?, word(2)?, word(3)?);
+        let bytes = bytes.get(..size)?;
+
+        let header_words = size_of::<StartupHeader>() / WORD;
+        let caps = (0..capc)
+            .map(|i| word(header_words + i))
+            .collect::<Option<Vec<_>>>()?;
+
+        // 参数和环境变量都是以 `\0` 结尾的字符串
+        let mut strings = bytes
+            .get((header_words + capc) * WORD..)?
+            .split(|x| *x == 0)
+            .map(|x| String::from_utf8_lossy(x).to_string());
+        let args = strings.by_ref().take(argc).collect::<Vec<_>>();
+        let envs = strings
+            .take(envc)
+            .map(|x| match x.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => (x, String::new()),
+            })
+            .collect::<Vec<_>>();
+        if args.len() != argc || envs.len() != envc {
+            return None;
+        }
+        Some(Self { args, envs, caps })
+    }
+}
+
+/// 获取当前任务的启动信息，没有启动信息时返回空的启动信息
+pub fn startup_info() -> StartupInfo {
+    let addr = STARTUP_ADDR.load(Ordering::Relaxed);
+    if addr == 0 {
+        return StartupInfo::new();
+    }
+    // 启动信息的第一个字段为整个启动信息的大小
+    let size = unsafe { *(addr as *const usize) };
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, size) };
+    StartupInfo::parse(bytes).unwrap_or_default()
+}
+
+/// 获取当前任务的参数
+pub fn args() -> Vec<String> {
+    startup_info().args
+}
+
+/// 获取当前任务的环境变量 `key`
+pub fn var(key: &str) -> Option<String> {
+    startup_info()
+        .envs
+        .into_iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value)
+}
+
+/// 获取当前任务的初始能力
+pub fn caps() -> Vec<usize> {
+    startup_info().caps
+}
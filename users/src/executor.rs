@@ -0,0 +1,324 @@
+//! 用户态的异步执行器
+//! 服务在 `ipc_recv` 中阻塞时无法同时处理其他请求，执行器把 IPC 请求、定时器和中断都变成 Future，
+//! 一个任务可以同时等待多个请求的回复
+//!
+//! 执行器空闲时在 `ipc_recv(IPC_ANY)` 中等待消息，然后根据消息唤醒对应的 Future：
+//! 正在等待回复的任务发来的回复类型的消息或者错误回复作为 [call] 的回复，定时器和中断通知唤醒
+//! [sleep] 和 [irq]，其他的消息作为请求交给 [recv]。同一个任务同时只能有一个请求，否则无法区分回复
+//!
+//! 执行器只能在一个线程中运行，并且会独占这个任务的定时器，运行时不能再直接调用 `sys_time`
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use syscall_consts::{IPCFlags, Message, MessageContent, MessageType, RawMessage, IPC_ANY};
+
+use crate::{
+    println, rpc,
+    sync::Mutex,
+    syscall::{ipc_recv, ipc_send_async, sys_ipc_raw, sys_time, sys_uptime, take_async_message},
+    UserError,
+};
+
+/// 执行器中的任务
+struct Task {
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// 是否已经在就绪队列中，避免重复加入
+    queued: AtomicBool,
+}
+
+impl Task {
+    /// 运行任务，任务结束后释放 Future
+    fn poll(self: Arc<Self>) {
+        self.queued.store(false, Ordering::Release);
+        let waker = Waker::from(self.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = self.future.lock();
+        if let Some(inner) = future.as_mut() {
+            if inner.as_mut().poll(&mut cx).is_ready() {
+                *future = None;
+            }
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            READY.lock().push_back(self);
+        }
+    }
+}
+
+/// [block_on] 运行的 Future 被唤醒时设置标志
+struct MainWaker(AtomicBool);
+
+impl Wake for MainWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// 正在等待回复的请求
+struct Call {
+    /// 请求发送的任务
+    dst: usize,
+    /// 回复的消息类型，服务也可能回复 [MessageType::ErrorReplyMsg]
+    reply_type: MessageType,
+    /// 收到的回复
+    reply: Option<RawMessage>,
+    waker: Option<Waker>,
+}
+
+/// 等待消息的 Future，收到消息后由执行器唤醒
+struct Reactor {
+    /// 正在等待回复的请求，每个任务最多只有一个
+    calls: Vec<Call>,
+    /// 等待发送请求的 Future，有请求结束时唤醒
+    call_waiters: Vec<Waker>,
+    /// 定时器，到期时间 (单位: ms) 和对应的 Future
+    timers: Vec<(usize, Waker)>,
+    /// 已经设置给内核的定时器的到期时间
+    armed: Option<usize>,
+    /// 收到中断通知的次数
+    irqs: usize,
+    irq_waiters: Vec<Waker>,
+    /// 等待处理的请求
+    inbox: VecDeque<Message>,
+    recv_waiters: Vec<Waker>,
+}
+
+impl Reactor {
+    const fn new() -> Self {
+        Self {
+            calls: Vec::new(),
+            call_waiters: Vec::new(),
+            timers: Vec::new(),
+            armed: None,
+            irqs: 0,
+            irq_waiters: Vec::new(),
+            inbox: VecDeque::new(),
+            recv_waiters: Vec::new(),
+        }
+    }
+
+    /// 请求结束，唤醒等待发送请求的 Future
+    fn finish_call(&mut self, dst: usize) {
+        self.calls.retain(|x| x.dst != dst);
+        self.call_waiters.drain(..).for_each(Waker::wake);
+    }
+
+    /// 唤醒已经到期的定时器，然后将内核的定时器设置为最早的到期时间
+    fn update_timers(&mut self) {
+        let now = sys_uptime();
+        self.timers.retain(|(deadline, waker)| {
+            if *deadline <= now {
+                waker.wake_by_ref();
+            }
+            *deadline > now
+        });
+        let next = self.timers.iter().map(|x| x.0).min();
+        if next == self.armed {
+            return;
+        }
+        // 内核的定时器只有一个，新的设置会覆盖之前的设置，0 表示取消定时器
+        let ms = next.map_or(0, |deadline| (deadline - now).max(1));
+        match sys_time(ms) {
+            Ok(()) => self.armed = next,
+            Err(err) => println!("[error] failed to set timer: {}", err),
+        }
+    }
+
+    /// 根据收到的消息唤醒对应的 Future
+    fn dispatch(&mut self, message: Message) {
+        match message.content {
+            MessageContent::NotifyTimer => {
+                self.armed = None;
+                self.update_timers();
+            }
+            MessageContent::NotifyIRQ => {
+                self.irqs += 1;
+                self.irq_waiters.drain(..).for_each(Waker::wake);
+            }
+            // Doing Nothing here.
+            MessageContent::None | MessageContent::PageFaultReply => {}
+            _ => {
+                // 只有回复类型的消息才是请求的回复，服务在回复之前发来的请求仍然交给 recv
+                let raw = RawMessage::from(&message);
+                let msg_type = raw.msg_type();
+                if let Some(call) = self.calls.iter_mut().find(|x| {
+                    x.dst == message.source
+                        && x.reply.is_none()
+                        && (msg_type == Some(x.reply_type)
+                            || msg_type == Some(MessageType::ErrorReplyMsg))
+                }) {
+                    call.reply = Some(raw);
+                    if let Some(waker) = call.waker.take() {
+                        waker.wake();
+                    }
+                    return;
+                }
+                self.inbox.push_back(message);
+                self.recv_waiters.drain(..).for_each(Waker::wake);
+            }
+        }
+    }
+}
+
+/// 就绪的任务
+static READY: Mutex<VecDeque<Arc<Task>>> = Mutex::new(VecDeque::new());
+
+static REACTOR: Mutex<Reactor> = Mutex::new(Reactor::new());
+
+/// 创建一个任务，任务会在 [block_on] 中运行
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let task = Arc::new(Task {
+        future: Mutex::new(Some(Box::pin(future))),
+        queued: AtomicBool::new(false),
+    });
+    task.wake();
+}
+
+/// 运行执行器直到 `future` 完成，期间也会运行 [spawn] 创建的任务
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let main = Arc::new(MainWaker(AtomicBool::new(true)));
+    let waker = Waker::from(main.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if main.0.swap(false, Ordering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        let task = READY.lock().pop_front();
+        match task {
+            Some(task) => task.poll(),
+            None if !main.0.load(Ordering::Acquire) => wait(),
+            None => {}
+        }
+    }
+}
+
+/// 没有就绪的任务时等待消息
+fn wait() {
+    let mut message = Message::blank();
+    if let Err(err) = ipc_recv(IPC_ANY, &mut message) {
+        println!("failed to receive message: {}", err);
+        return;
+    }
+    // 来源任务取回之前发送失败的回复
+    if RawMessage::from(&message).msg_type() == Some(MessageType::AsyncRecvMsg) {
+        let reply = take_async_message(message.source)
+            .unwrap_or_else(|| rpc::error_reply(UserError::NotFound));
+        return reply_to(message.source, reply);
+    }
+    REACTOR.lock().dispatch(message);
+}
+
+/// 请求结束或者被取消时释放等待回复的位置
+struct CallSlot(usize);
+
+impl Drop for CallSlot {
+    fn drop(&mut self) {
+        REACTOR.lock().finish_call(self.0);
+    }
+}
+
+/// 发送请求并等待类型为 `reply` 的回复，同一个任务已经有请求在等待回复时会先等待它结束
+/// 服务回复 [MessageType::ErrorReplyMsg] 时返回其中的错误码
+pub async fn call(
+    dst: usize,
+    mut request: RawMessage,
+    reply: MessageType,
+) -> Result<RawMessage, UserError> {
+    poll_fn(|cx| {
+        let mut reactor = REACTOR.lock();
+        if reactor.calls.iter().any(|x| x.dst == dst) {
+            reactor.call_waiters.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        reactor.calls.push(Call {
+            dst,
+            reply_type: reply,
+            reply: None,
+            waker: None,
+        });
+        Poll::Ready(())
+    })
+    .await;
+    let _slot = CallSlot(dst);
+    // 服务通常很快就会接收请求，所以阻塞发送，回复通过执行器接收
+    sys_ipc_raw(dst, 0, &mut request, IPCFlags::SEND)?;
+    let response = poll_fn(|cx| {
+        let mut reactor = REACTOR.lock();
+        let call = reactor.calls.iter_mut().find(|x| x.dst == dst).unwrap();
+        match call.reply.take() {
+            Some(reply) => Poll::Ready(reply),
+            None => {
+                call.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await;
+    rpc::expect_reply(response, reply)
+}
+
+/// 休眠 `ms` 毫秒
+pub async fn sleep(ms: usize) {
+    let deadline = sys_uptime() + ms;
+    poll_fn(|cx| {
+        if sys_uptime() >= deadline {
+            return Poll::Ready(());
+        }
+        let mut reactor = REACTOR.lock();
+        reactor.timers.push((deadline, cx.waker().clone()));
+        reactor.update_timers();
+        Poll::Pending
+    })
+    .await
+}
+
+/// 等待下一次中断通知
+pub async fn irq() {
+    let irqs = REACTOR.lock().irqs;
+    poll_fn(|cx| {
+        let mut reactor = REACTOR.lock();
+        if reactor.irqs != irqs {
+            return Poll::Ready(());
+        }
+        reactor.irq_waiters.push(cx.waker().clone());
+        Poll::Pending
+    })
+    .await
+}
+
+/// 接收下一个请求，请求不包括 [call] 的回复和通知
+pub async fn recv() -> Message {
+    poll_fn(|cx| {
+        let mut reactor = REACTOR.lock();
+        match reactor.inbox.pop_front() {
+            Some(message) => Poll::Ready(message),
+            None => {
+                reactor.recv_waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+/// 回复 [recv] 收到的请求，请求的来源没有在等待回复时会作为异步消息等待它取回
+pub fn reply_to(dst: usize, reply: RawMessage) {
+    if let Err(err) = ipc_send_async(dst, reply) {
+        println!("[error] failed to reply to task {}: {}", dst, err);
+    }
+}
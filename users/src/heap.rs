@@ -0,0 +1,269 @@
+//! 用户程序的堆
+//! 堆一开始只有静态的 [INIT_HEAP_SIZE] 大小的空间，空间不够时向 vm 服务申请内存扩展堆，
+//! 不小于 [LARGE_ALLOC_SIZE] 的内存直接向 vm 服务申请，释放时立即归还给 vm 服务。
+//! 伙伴分配器无法移除已经加入的内存，所以扩展出来的小块内存空间不会归还
+//!
+//! 启动信息中的环境变量 `heap_init` 和 `heap_max` 可以设置堆的初始大小和最大大小 (单位: 字节)，
+//! 申请失败时会输出堆的使用情况并返回空指针，由调用者决定如何处理
+//!
+//! vm 服务不能向自己发送请求，需要通过 [set_source] 设置自己申请内存的方式后才能扩展堆
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cmp,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use buddy_system_allocator::Heap;
+use spin::Mutex;
+use syscall_consts::VM_SERVER;
+
+use crate::{
+    align_up,
+    console::print_unbuffered,
+    env,
+    syscall::{alloc_memory, free_memory, task_self},
+    UserError, PAGE_SIZE,
+};
+
+/// 静态堆空间的大小，vm 服务通过 [set_source] 设置申请内存的方式之前只能使用这部分空间
+const INIT_HEAP_SIZE: usize = 0x2000;
+
+/// 每次扩展堆的最小大小
+const GROW_SIZE: usize = 0x10000;
+
+/// 不小于这个大小的内存直接向 vm 服务申请
+const LARGE_ALLOC_SIZE: usize = 0x10000;
+
+/// 没有设置 `heap_max` 时堆的最大大小
+const DEFAULT_MAX_SIZE: usize = 0x100_0000;
+
+/// 静态堆空间
+static mut HEAP_SPACE: [u8; INIT_HEAP_SIZE] = [0; INIT_HEAP_SIZE];
+
+/// 堆分配器
+#[global_allocator]
+static HEAP: UserHeap = UserHeap::new();
+
+/// 堆的使用情况 (单位: 字节)
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// 伙伴分配器管理的空间大小
+    pub total: usize,
+    /// 伙伴分配器中已经申请的大小，包括对齐浪费的空间
+    pub used: usize,
+    /// 直接向 vm 服务申请的大块内存的大小
+    pub large: usize,
+    /// 堆的最大大小，包括大块内存
+    pub max: usize,
+}
+
+/// 扩展堆时申请和释放内存的方式
+#[derive(Clone, Copy)]
+pub struct MemorySource {
+    /// 申请 `size` 字节的内存，返回虚拟地址，`size` 是页对齐的
+    pub alloc: fn(usize) -> Result<usize, UserError>,
+    /// 释放通过 `alloc` 申请的内存
+    pub free: fn(usize, usize) -> Result<(), UserError>,
+}
+
+/// 向 vm 服务申请内存
+fn vm_alloc(size: usize) -> Result<usize, UserError> {
+    alloc_memory(size).map(|(uaddr, _)| uaddr)
+}
+
+/// 默认向 vm 服务申请和释放内存
+const VM_SOURCE: MemorySource = MemorySource {
+    alloc: vm_alloc,
+    free: free_memory,
+};
+
+/// 可以增长的堆
+struct UserHeap {
+    heap: Mutex<Heap<32>>,
+    /// 申请和释放内存的方式
+    source: Mutex<MemorySource>,
+    /// 堆的总大小，包括大块内存
+    size: AtomicUsize,
+    /// 直接向 vm 服务申请的大块内存的大小
+    large: AtomicUsize,
+    /// 堆的最大大小
+    max: AtomicUsize,
+    /// 是否可以通过 `source` 申请内存
+    growable: AtomicBool,
+}
+
+impl UserHeap {
+    const fn new() -> Self {
+        Self {
+            heap: Mutex::new(Heap::new()),
+            source: Mutex::new(VM_SOURCE),
+            size: AtomicUsize::new(INIT_HEAP_SIZE),
+            large: AtomicUsize::new(0),
+            max: AtomicUsize::new(INIT_HEAP_SIZE),
+            growable: AtomicBool::new(false),
+        }
+    }
+
+    /// 是否直接向 vm 服务申请，判断条件在申请和释放时需要保持一致
+    fn is_large(&self, layout: &Layout) -> bool {
+        layout.size() >= LARGE_ALLOC_SIZE
+            && layout.align() <= PAGE_SIZE
+            && self.growable.load(Ordering::Relaxed)
+    }
+
+    /// 在最大大小的限制内预留 `size` 字节
+    fn reserve(&self, size: usize) -> Result<(), UserError> {
+        let max = self.max.load(Ordering::Relaxed);
+        self.size
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                x.checked_add(size).filter(|x| *x <= max)
+            })
+            .map(|_| ())
+            .map_err(|_| UserError::NoResources)
+    }
+
+    /// 申请 `size` 字节，失败时取消预留
+    fn request(&self, size: usize) -> Result<usize, UserError> {
+        if !self.growable.load(Ordering::Relaxed) {
+            return Err(UserError::NotSupported);
+        }
+        self.reserve(size)?;
+        let source = *self.source.lock();
+        (source.alloc)(size).map_err(|err| {
+            self.size.fetch_sub(size, Ordering::AcqRel);
+            err
+        })
+    }
+
+    /// 扩展 `size` 字节的堆空间
+    fn grow(&self, size: usize) -> Result<(), UserError> {
+        let size = align_up(size, PAGE_SIZE);
+        let uaddr = self.request(size)?;
+        unsafe { self.heap.lock().add_to_heap(uaddr, uaddr + size) };
+        Ok(())
+    }
+
+    /// 获取堆的使用情况
+    fn stats(&self) -> HeapStats {
+        let heap = self.heap.lock();
+        HeapStats {
+            total: heap.stats_total_bytes(),
+            used: heap.stats_alloc_actual(),
+            large: self.large.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 输出申请失败的原因，这时堆已经无法使用，所以不能申请内存
+    fn report(&self, layout: &Layout, err: UserError) {
+        print_unbuffered(format_args!(
+            "[error] heap: can't allocate {} bytes: {}, {:x?}\n",
+            layout.size(),
+            err,
+            self.stats()
+        ));
+    }
+}
+
+unsafe impl GlobalAlloc for UserHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.is_large(&layout) {
+            let size = align_up(layout.size(), PAGE_SIZE);
+            return match self.request(size) {
+                Ok(uaddr) => {
+                    self.large.fetch_add(size, Ordering::Relaxed);
+                    uaddr as *mut u8
+                }
+                Err(err) => {
+                    self.report(&layout, err);
+                    null_mut()
+                }
+            };
+        }
+        loop {
+            if let Ok(ptr) = self.heap.lock().alloc(layout) {
+                return ptr.as_ptr();
+            }
+            // 伙伴分配器按照 2 的幂分配，两倍大小的空间中一定有满足对齐要求的块
+            let block = cmp::max(layout.size().next_power_of_two(), layout.align());
+            if let Err(err) = self.grow(cmp::max(block * 2, GROW_SIZE)) {
+                self.report(&layout, err);
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !self.is_large(&layout) {
+            return self
+                .heap
+                .lock()
+                .dealloc(NonNull::new_unchecked(ptr), layout);
+        }
+        let size = align_up(layout.size(), PAGE_SIZE);
+        let source = *self.source.lock();
+        match (source.free)(ptr as usize, size) {
+            Ok(()) => {
+                self.large.fetch_sub(size, Ordering::Relaxed);
+                self.size.fetch_sub(size, Ordering::AcqRel);
+            }
+            Err(err) => print_unbuffered(format_args!(
+                "[error] heap: can't free {} bytes at {:#x}: {}\n",
+                size, ptr as usize, err
+            )),
+        }
+    }
+}
+
+/// 初始化静态堆空间，由 `_start` 调用
+pub(crate) fn init() {
+    unsafe {
+        HEAP.heap
+            .lock()
+            .init(HEAP_SPACE.as_ptr() as usize, INIT_HEAP_SIZE);
+    }
+}
+
+/// 读取环境变量中的大小，支持十进制和 `0x` 开头的十六进制
+fn env_size(key: &str) -> Option<usize> {
+    let value = env::var(key)?;
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// 根据启动信息设置堆的大小，解析启动信息需要申请内存，所以要在 [init] 之后调用
+pub(crate) fn configure() {
+    // vm 服务不能向自己发送请求
+    if task_self() == VM_SERVER {
+        return;
+    }
+    let max = env_size("heap_max").unwrap_or(DEFAULT_MAX_SIZE);
+    HEAP.max
+        .store(cmp::max(max, INIT_HEAP_SIZE), Ordering::Relaxed);
+    HEAP.growable.store(true, Ordering::Relaxed);
+    if let Some(size) = env_size("heap_init").filter(|x| *x > INIT_HEAP_SIZE) {
+        if let Err(err) = HEAP.grow(size - INIT_HEAP_SIZE) {
+            print_unbuffered(format_args!(
+                "[error] heap: can't grow to {} bytes: {}\n",
+                size, err
+            ));
+        }
+    }
+}
+
+/// 设置扩展堆时申请内存的方式和堆的最大大小，由不能向 vm 服务申请内存的任务使用
+pub fn set_source(source: MemorySource, max: usize) {
+    *HEAP.source.lock() = source;
+    HEAP.max
+        .store(cmp::max(max, INIT_HEAP_SIZE), Ordering::Relaxed);
+    HEAP.growable.store(true, Ordering::Relaxed);
+}
+
+/// 获取堆的使用情况
+pub fn stats() -> HeapStats {
+    HEAP.stats()
+}
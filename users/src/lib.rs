@@ -5,12 +5,21 @@
 extern crate alloc;
 
 mod console;
+pub mod env;
+pub mod executor;
+pub mod heap;
+pub mod pager;
+mod reloc;
+pub mod rpc;
+pub mod server;
+pub mod space;
+pub mod sync;
 pub mod syscall;
+pub mod thread;
 
 use alloc::string::{String, ToString};
-use buddy_system_allocator::LockedHeap;
 pub use console::print;
-use syscall_consts::SysCallError;
+use syscall_consts::{SysCallError, EXIT_PANIC};
 
 use core::panic::PanicInfo;
 use syscall::exit;
@@ -21,12 +30,6 @@ pub const PAGE_SIZE: usize = 4096;
 /// Block 块大小
 pub const BLOCK_SIZE: usize = 0x200;
 
-/// 用户程序默认堆大小
-const USER_HEAP_SIZE: usize = 0x2000;
-
-/// 堆分配器使用的空间
-static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
-
 /// 将 SysCallError 重新导出为 UserError
 pub type UserError = SysCallError;
 
@@ -40,14 +43,11 @@ pub fn align_down(a: usize, b: usize) -> usize {
     a / b * b
 }
 
-/// 堆分配器
-#[global_allocator]
-static HEAP: LockedHeap<32> = LockedHeap::empty();
-
 /// 程序真正的入口，会在这里进行初始化
+/// `startup` 为内核复制到栈顶的启动信息的地址，没有启动信息时为 0
 #[link_section = ".text.entry"]
 #[no_mangle]
-fn _start() -> ! {
+extern "C" fn _start(startup: usize) -> ! {
     extern "Rust" {
         fn main();
         fn _sbss();
@@ -58,12 +58,15 @@ fn _start() -> ! {
         // Clear BSS
         core::slice::from_raw_parts_mut(_sbss as *mut u8, _ebss as usize - _sbss as usize).fill(0);
         // Init heap allocator
-        HEAP.lock()
-            .init(HEAP_SPACE.as_ptr() as usize, HEAP_SPACE.len());
+        heap::init();
+        // 记录启动信息
+        env::init(startup);
+        // 根据启动信息设置堆的大小
+        heap::configure();
         // Call main function
         main();
     }
-    exit();
+    exit(0);
 }
 
 /// Panic 处理程序
@@ -76,7 +79,7 @@ fn panic(info: &PanicInfo) -> ! {
     // 输出 panic 信息
     println!("\x1b[1;31mpanic: '{}'\x1b[0m", info.message().unwrap());
     // 退出当前任务
-    exit();
+    exit(EXIT_PANIC);
 }
 
 /// 从 slice 切片中匹配字符串
@@ -0,0 +1,133 @@
+//! pager 协议
+//! 创建任务时传入的 pager 管理新任务的地址空间，任何任务都可以作为自己创建的子任务的 pager，
+//! pager 必须是创建者或者创建者的线程。创建时 pager 为 0 的任务由 root server 管理。
+//! pager 退出后没有任务接管它的子任务，内核把子任务的缺页和异常发送给 root server，
+//! root server 不认识这些任务，会在缺页时销毁它们，所以 pager 应该等子任务退出后再退出。
+//!
+//! pager 会收到下面的消息:
+//!
+//! - `PageFault`: 内核在子任务缺页时发送，来源为 [FROM_KERNEL]，处理后回复 `PageFaultReply`，
+//!   回复其他内容时内核以 `InvalidPagerReply` 异常销毁子任务
+//! - `ExceptionMsg`: 子任务发生异常或者主动退出时内核发送，不需要回复，内核发送后销毁子任务
+//! - `VmAllocPhysicalMsg` 和 `VmMapPhysicalMsg`: 子任务通过 [alloc_memory](crate::syscall::alloc_memory)
+//!   和 [map_paddr](crate::syscall::map_paddr) 申请内存，失败时回复的地址为 0
+//! - [rpc::pager] 中的请求: 子任务释放申请的内存
+//!
+//! 请求的来源可能是子任务的线程，线程和子任务共享地址空间和 pager。
+//! pager 通过 `sys_pm_alloc`、`sys_vm_map_range` 等系统调用操作子任务的地址空间，内存计入子任务的配额。
+//! 子任务的地址空间可以使用 [crate::space::AddressSpace] 创建和管理，
+//! 实现 [Pager] 之后，在接收消息的循环中调用 [handle] 处理这些消息。shell 的 `run --sandbox` 是一个例子
+
+use syscall_consts::{
+    ExceptionType, ExitStatus, Message, MessageContent, PageFaultReason, FROM_KERNEL,
+};
+
+use crate::{
+    println, rpc,
+    syscall::{ipc_reply, task_destory},
+    UserError,
+};
+
+/// pager 需要实现的接口，释放内存的请求由 [rpc::pager::Server] 处理
+pub trait Pager: rpc::pager::Server {
+    /// 处理任务 `tid` 在 `ip` 处访问 `uaddr` 时发生的缺页，返回错误时任务会被销毁
+    fn page_fault(
+        &mut self,
+        tid: usize,
+        uaddr: usize,
+        ip: usize,
+        fault: PageFaultReason,
+    ) -> Result<(), UserError>;
+
+    /// 任务 `tid` 已经退出或者因为异常被销毁
+    fn task_exit(&mut self, tid: usize, status: ExitStatus);
+
+    /// 任务 `source` 申请 `size` 字节的内存，返回映射的虚拟地址和物理地址
+    fn alloc_memory(&mut self, source: usize, size: usize) -> Result<(usize, usize), UserError>;
+
+    /// 任务 `source` 申请映射物理内存 `[paddr, paddr + size)`，返回映射的虚拟地址
+    fn map_physical(
+        &mut self,
+        source: usize,
+        paddr: usize,
+        size: usize,
+    ) -> Result<usize, UserError>;
+}
+
+/// 处理 pager 协议中的消息并回复，消息不属于 pager 协议时返回 false
+pub fn handle<P: Pager>(pager: &mut P, message: &mut Message) -> bool {
+    match message.content {
+        MessageContent::PageFault {
+            tid,
+            uaddr,
+            ip,
+            fault,
+        } if message.source == FROM_KERNEL => {
+            if let Err(err) = pager.page_fault(tid, uaddr, ip, fault) {
+                println!("task {} fault: {:?}", tid, err);
+                if let Err(err) = task_destory(tid) {
+                    println!("can't destroy task {}: {}", tid, err);
+                }
+                pager.task_exit(tid, ExitStatus::Killed(ExceptionType::InvalidAddr));
+                return true;
+            }
+            message.content = MessageContent::PageFaultReply;
+            ipc_reply(tid, message);
+        }
+        // 任务主动退出时 uaddr 为退出码
+        MessageContent::ExceptionMsg {
+            tid,
+            exception,
+            uaddr,
+            ip,
+        } if message.source == FROM_KERNEL => {
+            let status = match exception {
+                ExceptionType::GraceExit => ExitStatus::Exited(uaddr),
+                _ => {
+                    println!(
+                        "task {} exception: {:?}, {:#x} @ {:#x}",
+                        tid, exception, uaddr, ip
+                    );
+                    ExitStatus::Killed(exception)
+                }
+            };
+            pager.task_exit(tid, status);
+        }
+        MessageContent::VmAllocPhysicalMsg { size } => {
+            message.content = match pager.alloc_memory(message.source, size) {
+                Ok((uaddr, paddr)) => MessageContent::VmAllocPhysicalReplyMsg { uaddr, paddr },
+                Err(err) => {
+                    println!("task {} alloc memory failed: {}", message.source, err);
+                    MessageContent::VmAllocPhysicalReplyMsg { uaddr: 0, paddr: 0 }
+                }
+            };
+            ipc_reply(message.source, message);
+        }
+        MessageContent::VmMapPhysicalMsg {
+            paddr,
+            size,
+            map_flags: _,
+        } => {
+            message.content = match pager.map_physical(message.source, paddr, size) {
+                Ok(uaddr) => MessageContent::VmMapPhysicalReplyMsg { uaddr },
+                Err(err) => {
+                    println!(
+                        "task {} map physical memory failed: {}",
+                        message.source, err
+                    );
+                    MessageContent::VmMapPhysicalReplyMsg { uaddr: 0 }
+                }
+            };
+            ipc_reply(message.source, message);
+        }
+        MessageContent::Raw(ref request) => {
+            let Some(reply) = rpc::pager::dispatch(pager, request) else {
+                return false;
+            };
+            message.content = MessageContent::raw(reply);
+            ipc_reply(message.source, message);
+        }
+        _ => return false,
+    }
+    true
+}
@@ -0,0 +1,159 @@
+//! 位置无关可执行文件 (PIE) 的重定位
+//! PIE 的类型为 `ET_DYN`，pager 把它加载到随机选择的地址，加载地址和链接地址的差值为 `bias`。
+//! 用户程序是静态链接的，只需要处理 `R_*_RELATIVE` 重定位，即把 `bias + addend` 写入 `r_offset + bias`。
+//!
+//! 段的内容在发生缺页时才会从文件中复制，所以重定位在创建任务时解析，在映射页的时候写入这个页中
+
+use alloc::vec::Vec;
+use xmas_elf::{
+    header::{Machine, Type as ElfType},
+    program::Type,
+    ElfFile,
+};
+
+use crate::{println, UserError, PAGE_SIZE};
+
+/// 动态段中的标签
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELR: u64 = 36;
+
+/// `Elf64_Dyn` 的大小
+const DYN_SIZE: usize = 16;
+/// `Elf64_Rela` 的大小
+const RELA_SIZE: usize = 24;
+
+/// 所有架构中 `R_*_NONE` 的类型都为 0
+const R_NONE: u32 = 0;
+
+/// LoongArch 的机器类型，xmas_elf 中没有定义
+const EM_LOONGARCH: u16 = 258;
+
+/// 需要写入的重定位，所有架构的 `R_*_RELATIVE` 都写入 64 位的值
+#[derive(Debug, Clone, Copy)]
+pub struct Relocation {
+    /// 加载后需要写入的地址
+    pub addr: usize,
+    /// 写入的值
+    pub value: usize,
+}
+
+/// 判断 ELF 文件是否为位置无关的可执行文件
+pub fn is_pie(elf_file: &ElfFile) -> bool {
+    elf_file.header.pt2.type_().as_type() == ElfType::SharedObject
+}
+
+/// 获取架构对应的 `R_*_RELATIVE` 类型
+fn relative_type(machine: Machine) -> Option<u32> {
+    match machine {
+        Machine::RISC_V => Some(3),
+        Machine::AArch64 => Some(1027),
+        Machine::X86_64 => Some(8),
+        Machine::Other(EM_LOONGARCH) => Some(3),
+        _ => None,
+    }
+}
+
+/// 读取 `bytes` 中 `offset` 处的 64 位小端数
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset + 8)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// 将链接地址 `vaddr` 转换为文件中的偏移，地址需要位于 LOAD 段的文件内容中
+fn vaddr_to_offset(elf_file: &ElfFile, vaddr: u64) -> Option<usize> {
+    elf_file
+        .program_iter()
+        .filter(|x| x.get_type().unwrap_or(Type::Null) == Type::Load)
+        .find(|x| x.virtual_addr() <= vaddr && vaddr < x.virtual_addr() + x.file_size())
+        .map(|x| (x.offset() + vaddr - x.virtual_addr()) as usize)
+}
+
+/// 解析 PIE 中的重定位，不是 PIE 的 ELF 文件不需要重定位，返回的重定位按照地址排序
+pub fn relocations(elf_file: &ElfFile, bias: usize) -> Result<Vec<Relocation>, UserError> {
+    if !is_pie(elf_file) {
+        return Ok(Vec::new());
+    }
+    let file = elf_file.input;
+    let relative =
+        relative_type(elf_file.header.pt2.machine().as_machine()).ok_or(UserError::NotSupported)?;
+    let Some(dynamic) = elf_file
+        .program_iter()
+        .find(|x| x.get_type().unwrap_or(Type::Null) == Type::Dynamic)
+    else {
+        return Ok(Vec::new());
+    };
+
+    // 从动态段中找到重定位表
+    let (mut rela, mut rela_size, mut rela_ent) = (None, 0, RELA_SIZE);
+    let start = dynamic.offset() as usize;
+    let end = start + dynamic.file_size() as usize;
+    for offset in (start..end).step_by(DYN_SIZE) {
+        let tag = read_u64(file, offset).ok_or(UserError::InvalidArg)?;
+        let value = read_u64(file, offset + 8).ok_or(UserError::InvalidArg)?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => rela_size = value as usize,
+            DT_RELAENT => rela_ent = value as usize,
+            // 这些架构只使用 RELA，RELR 需要链接时加上 -z pack-relative-relocs 才会生成
+            DT_REL | DT_RELR => return Err(UserError::NotSupported),
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return Ok(Vec::new());
+    };
+    if rela_ent < RELA_SIZE {
+        return Err(UserError::InvalidArg);
+    }
+    let table = vaddr_to_offset(elf_file, rela).ok_or(UserError::InvalidArg)?;
+
+    let mut relocations = Vec::new();
+    for offset in (table..table + rela_size).step_by(rela_ent) {
+        let r_offset = read_u64(file, offset).ok_or(UserError::InvalidArg)?;
+        let r_info = read_u64(file, offset + 8).ok_or(UserError::InvalidArg)?;
+        let r_addend = read_u64(file, offset + 16).ok_or(UserError::InvalidArg)?;
+        match r_info as u32 {
+            R_NONE => {}
+            x if x == relative => relocations.push(Relocation {
+                addr: r_offset as usize + bias,
+                value: bias.wrapping_add(r_addend as usize),
+            }),
+            x => {
+                println!("[error] unsupported relocation type {}", x);
+                return Err(UserError::NotSupported);
+            }
+        }
+    }
+    relocations.sort_by_key(|x| x.addr);
+    Ok(relocations)
+}
+
+/// 将和 `vaddr` 所在页重叠的重定位写入 `page`，`page` 为这个页的内容
+/// 重定位的值可能跨越两个页，只写入在这个页中的部分
+pub fn apply(relocations: &[Relocation], vaddr: usize, page: &mut [u8]) {
+    let (start, end) = (vaddr, vaddr + PAGE_SIZE);
+    let first = relocations.partition_point(|x| x.addr + 8 <= start);
+    relocations[first..]
+        .iter()
+        .take_while(|x| x.addr < end)
+        .for_each(|x| {
+            let bytes = x.value.to_le_bytes();
+            (0..bytes.len())
+                .map(|i| (x.addr + i, bytes[i]))
+                .filter(|(addr, _)| (start..end).contains(addr))
+                .for_each(|(addr, byte)| page[addr - start] = byte);
+        });
+}
+
+/// 判断 `vaddr` 所在页中是否有需要写入的重定位
+pub fn in_page(relocations: &[Relocation], vaddr: usize) -> bool {
+    let first = relocations.partition_point(|x| x.addr + 8 <= vaddr);
+    relocations
+        .get(first)
+        .is_some_and(|x| x.addr < vaddr + PAGE_SIZE)
+}
@@ -0,0 +1,72 @@
+//! 由 IDL 中的服务接口生成的客户端函数和服务端 trait
+//! 每个接口对应一个模块，例如 `rpc::block_device::read_block` 和 `rpc::block_device::Server`
+//!
+//! 服务端收到消息后调用对应模块的 `dispatch`，返回的消息回复给请求的来源任务即可
+
+// 生成的代码直接使用 IDL 中定义的常量和负载结构
+use syscall_consts::*;
+
+use crate::{
+    syscall::{sys_ipc_raw, sys_ipc_short},
+    UserError,
+};
+
+include!(concat!(env!("OUT_DIR"), "/rpc.rs"));
+
+/// 发送请求并等待回复，回复的消息类型不是 `reply` 时返回错误
+/// 服务回复 [MessageType::ErrorReplyMsg] 时返回其中的错误码
+pub(crate) fn call(
+    server: usize,
+    mut request: RawMessage,
+    reply: MessageType,
+) -> Result<RawMessage, UserError> {
+    sys_ipc_raw(server, server, &mut request, IPCFlags::CALL)?;
+    expect_reply(request, reply)
+}
+
+/// 使用短消息发送请求并等待回复，请求和回复都只通过寄存器传递
+/// 服务的回复不能用短消息表示时，内核保留这个回复，再通过普通的 IPC 接收
+fn call_short(
+    server: usize,
+    request: RawMessage,
+    reply: MessageType,
+) -> Result<RawMessage, UserError> {
+    let mut message = ShortMessage::from_raw(&request).ok_or(UserError::InvalidArg)?;
+    let response = match sys_ipc_short(server, server, &mut message, IPCFlags::CALL) {
+        Ok(()) => message.to_raw().ok_or(UserError::Unexpected)?,
+        Err(UserError::TooLarge) => {
+            let mut response = RawMessage::blank();
+            sys_ipc_raw(0, server, &mut response, IPCFlags::RECV)?;
+            response
+        }
+        Err(err) => return Err(err),
+    };
+    expect_reply(response, reply)
+}
+
+/// 检查回复的消息类型，服务回复 [MessageType::ErrorReplyMsg] 时返回其中的错误码
+pub(crate) fn expect_reply(
+    message: RawMessage,
+    reply: MessageType,
+) -> Result<RawMessage, UserError> {
+    match message.msg_type() {
+        Some(msg_type) if msg_type == reply => Ok(message),
+        Some(MessageType::ErrorReplyMsg) => {
+            Err(UserError::from(payload::<ErrorPayload>(&message)?.code))
+        }
+        _ => Err(UserError::Unexpected),
+    }
+}
+
+/// 获取消息的负载，负载长度不匹配时返回 [UserError::InvalidArg]
+fn payload<T: Payload>(message: &RawMessage) -> Result<T, UserError> {
+    message.payload().ok_or(UserError::InvalidArg)
+}
+
+/// 服务处理请求出错时回复的消息
+pub fn error_reply(err: UserError) -> RawMessage {
+    RawMessage::new(
+        MessageType::ErrorReplyMsg,
+        ErrorPayload { code: err.into() },
+    )
+}
@@ -0,0 +1,276 @@
+//! 用户服务的通用运行时
+//! 负责注册服务、接收消息、分发定时器和中断通知、回复不认识的消息以及延迟回复，
+//! 服务只需要实现 [Server]，然后调用 [Runtime::run]
+//!
+//! 内核只允许 CALL 的发起者接收来自被调用任务的回复，所以所有的回复都由注册服务的主线程发送。
+//! 工作线程通过 [Deferred::reply] 回复时，回复会先放到发件箱中，再通知主线程发送
+//! 来源任务没有在等待回复时，回复会保存为异步消息，来源任务收到通知后通过 AsyncRecvMsg 取回
+
+use alloc::{boxed::Box, collections::VecDeque, string::String, sync::Arc, vec::Vec};
+use syscall_consts::{
+    ExitStatus, IPCFlags, Message, MessageContent, MessageType, RawMessage, FROM_KERNEL, VM_SERVER,
+};
+
+use crate::{
+    println, rpc,
+    sync::{Condvar, Mutex},
+    syscall::{
+        drop_async_messages, ip_recv_any, ipc_recv_any_short, ipc_register, ipc_send_async,
+        sys_ipc, take_async_message, task_self,
+    },
+    thread, UserError,
+};
+
+/// 服务需要实现的接口
+pub trait Server {
+    /// 处理请求，请求可以通过生成的 `dispatch` 函数分发给具体的 rpc
+    fn handle(&mut self, ctx: &Context, request: &RawMessage) -> Reply;
+
+    /// 收到定时器通知
+    fn on_timer(&mut self, _ctx: &Context) {}
+
+    /// 收到中断通知
+    fn on_irq(&mut self, _ctx: &Context) {}
+
+    /// 通过 [watch_task](crate::syscall::watch_task) 监控的任务退出
+    fn on_task_destroyed(&mut self, _ctx: &Context, _tid: usize, _status: ExitStatus) {}
+}
+
+/// 请求的处理结果
+// Reply 只作为 handle 的返回值使用，马上就会被发送出去，不需要为了变体大小再分配一次堆内存
+#[allow(clippy::large_enum_variant)]
+pub enum Reply {
+    /// 立即回复这条消息
+    Now(RawMessage),
+    /// 稍后通过 [Context::defer] 得到的 [Deferred] 回复
+    Later,
+    /// 不认识的请求，运行时会回复 [UserError::NotSupported]
+    Unknown,
+}
+
+/// 生成的 `dispatch` 函数返回 None 表示请求不属于这个服务
+impl From<Option<RawMessage>> for Reply {
+    fn from(reply: Option<RawMessage>) -> Self {
+        match reply {
+            Some(reply) => Reply::Now(reply),
+            None => Reply::Unknown,
+        }
+    }
+}
+
+/// 工作线程执行的任务
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 主线程和工作线程共享的状态
+struct Shared {
+    /// 注册服务的主线程
+    main: usize,
+    /// 工作线程发送的回复，由主线程转发给请求的来源任务
+    outbox: Mutex<Vec<(usize, RawMessage)>>,
+    /// 等待工作线程执行的任务
+    jobs: Mutex<VecDeque<Job>>,
+    /// 有新的任务时通知工作线程
+    job_ready: Condvar,
+}
+
+impl Shared {
+    /// 发送回复，不在主线程时放到发件箱中并通知主线程
+    /// 来源任务没有在等待回复时，回复会作为异步消息等待它取回
+    fn send(&self, dst: usize, reply: RawMessage) {
+        if task_self() == self.main {
+            if let Err(err) = ipc_send_async(dst, reply) {
+                println!("[error] failed to reply to task {}: {}", dst, err);
+            }
+            return;
+        }
+        self.outbox.lock().push((dst, reply));
+        // 空消息只用来唤醒主线程，主线程收到后会发送发件箱中的回复
+        if let Err(err) = sys_ipc(self.main, 0, &mut Message::blank(), IPCFlags::SEND) {
+            println!("[error] failed to wake up server thread: {}", err);
+        }
+    }
+
+    /// 由主线程发送发件箱中所有的回复
+    fn flush(&self) {
+        let replies: Vec<_> = self.outbox.lock().drain(..).collect();
+        replies
+            .into_iter()
+            .for_each(|(dst, reply)| self.send(dst, reply));
+    }
+
+    /// 工作线程循环执行任务
+    fn worker(&self) -> ! {
+        loop {
+            let mut jobs = self.jobs.lock();
+            while jobs.is_empty() {
+                jobs = self.job_ready.wait(jobs);
+            }
+            let job = jobs.pop_front().unwrap();
+            drop(jobs);
+            job();
+        }
+    }
+}
+
+/// 延迟回复，请求的处理结果为 [Reply::Later] 时需要通过它回复请求
+/// 可以移动到工作线程中，回复之后就会被消耗掉，所以一个请求只会回复一次
+pub struct Deferred {
+    source: usize,
+    shared: Arc<Shared>,
+}
+
+impl Deferred {
+    /// 请求的来源任务
+    pub fn source(&self) -> usize {
+        self.source
+    }
+
+    /// 回复请求
+    pub fn reply(self, reply: RawMessage) {
+        self.shared.send(self.source, reply);
+    }
+
+    /// 回复错误
+    pub fn reply_error(self, err: UserError) {
+        self.reply(rpc::error_reply(err));
+    }
+}
+
+/// 处理请求时的上下文
+pub struct Context {
+    shared: Arc<Shared>,
+    /// 工作线程的数量，为 0 时在主线程中执行任务
+    workers: usize,
+}
+
+impl Context {
+    /// 稍后回复 `request`，处理结果需要返回 [Reply::Later]
+    pub fn defer(&self, request: &RawMessage) -> Deferred {
+        Deferred {
+            source: request.header.source,
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// 在工作线程中执行 `job`，没有工作线程时直接执行
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.workers == 0 {
+            return job();
+        }
+        self.shared.jobs.lock().push_back(Box::new(job));
+        self.shared.job_ready.notify_one();
+    }
+}
+
+/// 服务运行时
+#[derive(Default)]
+pub struct Runtime {
+    /// 注册的服务名称，为 None 时不注册
+    name: Option<String>,
+    /// 工作线程的数量
+    workers: usize,
+    /// 是否先通过寄存器接收请求
+    short_requests: bool,
+}
+
+impl Runtime {
+    /// 创建运行时，默认不注册服务，也没有工作线程
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 以 `name` 注册服务
+    pub fn register(mut self, name: &str) -> Self {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    /// 创建 `workers` 个工作线程执行 [Context::execute] 提交的任务
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// 先通过寄存器接收请求，适合请求大多可以用短消息表示的服务，较长的请求需要多一次系统调用
+    pub fn short_requests(mut self) -> Self {
+        self.short_requests = true;
+        self
+    }
+
+    /// 运行服务，不断地接收并处理消息
+    pub fn run<S: Server>(self, server: &mut S) -> ! {
+        let shared = Arc::new(Shared {
+            main: task_self(),
+            outbox: Mutex::new(Vec::new()),
+            jobs: Mutex::new(VecDeque::new()),
+            job_ready: Condvar::new(),
+        });
+        for _ in 0..self.workers {
+            let shared = shared.clone();
+            thread::spawn(move || shared.worker()).expect("can't create worker thread");
+        }
+        if let Some(name) = &self.name {
+            println!("register {} service!", name);
+            ipc_register(name).expect("can't register service");
+        }
+
+        let ctx = Context {
+            shared,
+            workers: self.workers,
+        };
+        let recv = match self.short_requests {
+            true => ipc_recv_any_short,
+            false => ip_recv_any,
+        };
+        let mut message = Message::blank();
+        loop {
+            if let Err(err) = recv(&mut message) {
+                println!("failed to receive message: {}", err);
+                continue;
+            }
+            match message.content {
+                MessageContent::NotifyTimer => server.on_timer(&ctx),
+                MessageContent::NotifyIRQ => server.on_irq(&ctx),
+                MessageContent::TaskDestroyedMsg { tid, status } if message.source == VM_SERVER => {
+                    drop_async_messages(tid);
+                    server.on_task_destroyed(&ctx, tid, status)
+                }
+                // 工作线程唤醒主线程发送回复
+                MessageContent::None => {}
+                // Doing Nothing here.
+                MessageContent::PageFaultReply => {}
+                _ => {
+                    let request = RawMessage::from(&message);
+                    // 来源任务取回之前发送失败的回复
+                    if request.msg_type() == Some(MessageType::AsyncRecvMsg) {
+                        let reply = take_async_message(message.source)
+                            .unwrap_or_else(|| rpc::error_reply(UserError::NotFound));
+                        ctx.shared.send(message.source, reply);
+                        ctx.shared.flush();
+                        continue;
+                    }
+                    match server.handle(&ctx, &request) {
+                        Reply::Now(reply) => ctx.shared.send(message.source, reply),
+                        Reply::Later => {}
+                        // 不回复内核和错误消息，避免两个服务互相回复错误
+                        Reply::Unknown
+                            if message.source == FROM_KERNEL
+                                || request.msg_type() == Some(MessageType::ErrorReplyMsg) =>
+                        {
+                            println!("unhandled message: {:?}", message.content)
+                        }
+                        Reply::Unknown => {
+                            println!("unhandled message: {:?}", request.msg_type());
+                            ctx.shared
+                                .send(message.source, rpc::error_reply(UserError::NotSupported));
+                        }
+                    }
+                }
+            }
+            ctx.shared.flush();
+        }
+    }
+}
@@ -0,0 +1,469 @@
+//! pager 管理的任务的地址空间
+//! 根据 ELF 文件的 LOAD 段建立区域，PIE 加载到随机的地址，段和栈在发生缺页时按需映射，
+//! 通过 VmAllocPhysicalMsg 和 VmMapPhysicalMsg 申请的内存在请求时映射，协议见 [crate::pager]。
+//! root server 和其他 pager 都使用 [AddressSpace] 管理以它们为 pager 的任务
+
+use core::cmp;
+
+use alloc::{borrow::Cow, vec::Vec};
+use syscall_consts::{
+    PMAllocFlags, PageFaultReason, VMMapFlags, HUGE_PAGE_1G, HUGE_PAGE_2M, STACK_AREA_SIZE,
+    USER_SPACE_END,
+};
+use xmas_elf::{program::Type, ElfFile};
+
+use crate::{
+    align_down, align_up,
+    env::StartupInfo,
+    println,
+    reloc::{self, Relocation},
+    sync::Mutex,
+    syscall::{
+        random, sys_pm_alloc, sys_pm_free, sys_task_create, sys_vm_map, sys_vm_map_range,
+        sys_vm_unmap, sys_vm_unmap_range, task_info, task_self,
+    },
+    UserError, PAGE_SIZE,
+};
+
+/// 按页对齐的缓冲区
+#[repr(C, align(4096))]
+struct PageBuffer([u8; PAGE_SIZE]);
+
+/// 临时页，写入子任务的页时把物理页映射到这里
+/// 同一个任务中可能有多个 pager 线程 (例如 shell 的多个沙箱)，持有锁期间才能映射和写入
+#[link_section = ".bss.page_data"]
+static TMP_PAGE: Mutex<PageBuffer> = Mutex::new(PageBuffer([0u8; PAGE_SIZE]));
+
+/// pager 管理的任务的地址空间，线程和所属的任务共享地址空间
+pub struct AddressSpace {
+    /// 任务 ID
+    pub tid: usize,
+    /// 当前 elf 文件，内置的服务直接引用启动镜像中的文件，运行时加载的文件保存在堆中
+    pub file: Cow<'static, [u8]>,
+    /// 申请虚拟内存的起始地址，位于 ELF 段之后
+    pub valloc_base: usize,
+    /// 栈区域的顶部，内核在 `[stack_area_top - STACK_AREA_SIZE, stack_area_top)` 中为每个线程分配栈
+    /// 这个区域不会用于申请内存
+    pub stack_area_top: usize,
+    /// 地址空间中的区域，按照起始地址排序，不在任何区域中的地址都不能访问
+    /// 区域之间的空隙就是空闲的虚拟地址
+    pub regions: Vec<Region>,
+    /// PIE 需要在映射页时写入的重定位，按照地址排序
+    relocations: Vec<Relocation>,
+}
+
+/// 地址空间中区域的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// ELF 的 LOAD 段，`offset` 为段在文件中的偏移，超过 `file_size` 的部分填充 0
+    Segment { offset: usize, file_size: usize },
+    /// 通过 VmAllocPhysicalMsg 申请的内存，申请时已经映射
+    Heap { paddr: usize },
+    /// 线程 `tid` 的栈，发生缺页时映射空白页，线程退出时内核会释放栈中的内存
+    Stack { tid: usize },
+    /// 通过 VmMapPhysicalMsg 映射的物理内存，通常为设备的 MMIO，映射时已经映射
+    Mmio,
+}
+
+/// 地址空间中的一段区域 `[start, end)`
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    /// 访问权限
+    pub flags: VMMapFlags,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// 判断区域是否覆盖了 `vaddr` 所在的页，区域的首尾可能和其他区域共享同一个页
+    pub fn covers_page(&self, vaddr: usize) -> bool {
+        let page = align_down(vaddr, PAGE_SIZE);
+        page < self.end && page + PAGE_SIZE > self.start
+    }
+
+    /// 发生缺页时是否需要按需映射，其他区域在创建时就已经映射了
+    pub fn on_demand(&self) -> bool {
+        matches!(
+            self.kind,
+            RegionKind::Segment { .. } | RegionKind::Stack { .. }
+        )
+    }
+}
+
+/// PIE 的加载地址在 `[PIE_BASE, PIE_BASE + PIE_RANDOM_SIZE)` 中随机选择
+const PIE_BASE: usize = 0x1000_0000;
+
+/// PIE 加载地址的随机范围
+const PIE_RANDOM_SIZE: usize = 0x4000_0000;
+
+/// 申请虚拟内存的起始地址在 ELF 段之后随机后移的最大距离
+const HEAP_RANDOM_SIZE: usize = 0x10_0000_0000;
+
+/// 获取 ELF 文件的加载地址和链接地址的差值，PIE 加载到随机的地址，其他的 ELF 文件加载到链接地址
+fn load_bias(elf_file: &ElfFile) -> usize {
+    if !reloc::is_pie(elf_file) {
+        return 0;
+    }
+    let loads = || {
+        elf_file
+            .program_iter()
+            .filter(|x| x.get_type().unwrap_or(Type::Null) == Type::Load)
+    };
+    // 加载地址需要满足所有段的对齐要求
+    let align = loads()
+        .map(|x| x.align() as usize)
+        .fold(PAGE_SIZE, cmp::max);
+    let min_vaddr = loads()
+        .map(|x| align_down(x.virtual_addr() as usize, align))
+        .min()
+        .unwrap_or(0);
+    let base = align_up(PIE_BASE, align) + random() % (PIE_RANDOM_SIZE / align) * align;
+    base - min_vaddr
+}
+
+/// 根据 ELF 文件的 LOAD 段生成区域，段的地址加上 `bias`
+fn elf_regions(elf_file: &ElfFile, bias: usize) -> Vec<Region> {
+    elf_file
+        .program_iter()
+        .filter(|x| x.get_type().unwrap_or(Type::Null) == Type::Load && x.mem_size() > 0)
+        .map(|x| {
+            let ph_flags = x.flags();
+            let mut flags = VMMapFlags::empty();
+            if ph_flags.is_read() {
+                flags |= VMMapFlags::READ;
+            }
+            if ph_flags.is_write() {
+                flags |= VMMapFlags::WRITE;
+            }
+            if ph_flags.is_execute() {
+                flags |= VMMapFlags::EXEC;
+            }
+            Region {
+                start: x.virtual_addr() as usize + bias,
+                end: (x.virtual_addr() + x.mem_size()) as usize + bias,
+                flags,
+                kind: RegionKind::Segment {
+                    offset: x.offset() as usize,
+                    file_size: x.file_size() as usize,
+                },
+            }
+        })
+        .collect()
+}
+
+impl AddressSpace {
+    /// 创建运行 ELF 文件 `file` 的任务，由 `pager` 处理新任务的缺页和内存请求
+    /// `pager` 需要是当前任务或者它的线程，`stack_size` 为 0 时使用内核默认的栈大小
+    pub fn spawn(
+        name: &str,
+        file: Cow<'static, [u8]>,
+        startup: &StartupInfo,
+        pager: usize,
+        mem_quota: usize,
+        stack_size: usize,
+    ) -> Result<Self, UserError> {
+        // 读取 elf 文件
+        let elf_file = ElfFile::new(&file).map_err(|_| UserError::InvalidArg)?;
+        let bias = load_bias(&elf_file);
+        let entry = elf_file.header.pt2.entry_point() as usize + bias;
+        let mut regions = elf_regions(&elf_file, bias);
+        if regions.is_empty() {
+            return Err(UserError::InvalidArg);
+        }
+        let relocations = reloc::relocations(&elf_file, bias)?;
+        let tid = sys_task_create(
+            name,
+            entry,
+            pager,
+            mem_quota,
+            stack_size,
+            &startup.to_bytes(),
+        )?;
+        // 获取内核为主线程分配的栈，主线程的栈位于栈区域的顶部
+        let info = task_info(tid)?;
+        // 根据段信息和主线程的栈建立区域表，其他线程的栈在第一次访问时加入
+        regions.push(Region {
+            start: info.stack_top - info.stack_size,
+            end: info.stack_top,
+            flags: VMMapFlags::READ | VMMapFlags::WRITE,
+            kind: RegionKind::Stack { tid },
+        });
+        regions.sort_by_key(|x| x.start);
+        // 段之后随机距离的虚拟地址用于申请内存
+        let valloc_base = regions
+            .iter()
+            .filter(|x| !matches!(x.kind, RegionKind::Stack { .. }))
+            .map(|x| align_up(x.end, PAGE_SIZE))
+            .max()
+            .unwrap_or(0)
+            + random() % (HEAP_RANDOM_SIZE / PAGE_SIZE) * PAGE_SIZE;
+        Ok(Self {
+            tid,
+            file,
+            valloc_base,
+            stack_area_top: info.stack_top,
+            regions,
+            relocations,
+        })
+    }
+
+    /// 处理线程 `tid` 的页表错误
+    /// 错误地址所在的页可能被多个段共享，页的内容和权限由所有覆盖这个页的段共同决定
+    pub fn handle_page_fault(
+        &mut self,
+        tid: usize,
+        uaddr: usize,
+        ip: usize,
+        fault: PageFaultReason,
+    ) -> Result<(), UserError> {
+        // 第一个页通常不会使用，如果错误的位置为 0, 则用户态无法处理
+        if uaddr < PAGE_SIZE {
+            println!("[WARN] task {} access {:#x} @ {:#x}", self.tid, uaddr, ip);
+            return Err(UserError::NotAllowed);
+        }
+
+        let vaddr = align_down(uaddr, PAGE_SIZE);
+        // 线程的栈由内核分配，线程第一次访问栈区域时加入它的栈
+        if vaddr < self.stack_area_top
+            && vaddr >= self.stack_area_top - STACK_AREA_SIZE
+            && !self.regions.iter().any(|x| x.covers_page(vaddr))
+        {
+            self.add_stack(tid)?;
+        }
+        let regions: Vec<&Region> = self
+            .regions
+            .iter()
+            .filter(|x| x.covers_page(vaddr))
+            .collect();
+        // 不在任何区域中的地址不能访问，已经映射的区域发生缺页说明访问权限不正确
+        if regions.is_empty() || !regions.iter().all(|x| x.on_demand()) {
+            println!(
+                "[WARN] task {} access {:#x} @ {:#x} outside of regions",
+                self.tid, uaddr, ip
+            );
+            return Err(UserError::NotAllowed);
+        }
+
+        // FIXME: x86_64 will have present flags, need to fix
+        // 页已经映射时 PRESENT 不可靠，所以只根据访问类型检查权限
+        let flags = regions
+            .iter()
+            .fold(VMMapFlags::empty(), |flags, x| flags | x.flags);
+        if (fault.contains(PageFaultReason::WRITE) && !flags.contains(VMMapFlags::WRITE))
+            || (fault.contains(PageFaultReason::EXEC) && !flags.contains(VMMapFlags::EXEC))
+        {
+            println!(
+                "[WARN] task {} access {:#x} @ {:#x} with {:?}, allowed {:?}",
+                self.tid, uaddr, ip, fault, flags
+            );
+            return Err(UserError::NotAllowed);
+        }
+
+        // 内核申请的物理页已经清零，bss 和段之间的空隙不需要再处理
+        let paddr = sys_pm_alloc(self.tid, PAGE_SIZE, 0)?;
+
+        // 所有段在这个页中的文件内容
+        let copies: Vec<_> = regions
+            .iter()
+            .filter_map(|region| {
+                let RegionKind::Segment { offset, file_size } = region.kind else {
+                    return None;
+                };
+                let start = cmp::max(region.start, vaddr);
+                let end = cmp::min(region.start + file_size, vaddr + PAGE_SIZE);
+                (start < end).then_some((start, end, offset + start - region.start))
+            })
+            .collect();
+        let relocated = reloc::in_page(&self.relocations, vaddr);
+
+        // 需要写入内容时通过临时页写入
+        if !copies.is_empty() || relocated {
+            let mut tmp_page = TMP_PAGE.lock();
+            let tmp_page_addr = tmp_page.0.as_ptr() as usize;
+            sys_vm_map(
+                task_self(),
+                tmp_page_addr,
+                paddr,
+                (VMMapFlags::READ | VMMapFlags::WRITE).bits(),
+            )?;
+            copies.into_iter().for_each(|(start, end, file_offset)| {
+                tmp_page.0[start - vaddr..end - vaddr]
+                    .copy_from_slice(&self.file[file_offset..file_offset + end - start]);
+            });
+            reloc::apply(&self.relocations, vaddr, &mut tmp_page.0);
+            // 写入后立即取消映射，任务退出释放这个页之后 pager 不能再访问它
+            sys_vm_unmap(task_self(), tmp_page_addr)?;
+        }
+
+        sys_vm_map(self.tid, vaddr, paddr, flags.bits())
+    }
+
+    /// 为线程 `tid` 加入栈区域，栈的位置从内核获取
+    /// 内核会复用已经退出的线程的栈，所以先移除和新的栈重叠的旧区域
+    fn add_stack(&mut self, tid: usize) -> Result<(), UserError> {
+        let info = task_info(tid)?;
+        if info.owner != self.tid {
+            return Err(UserError::InvalidTask);
+        }
+        let (start, end) = (info.stack_top - info.stack_size, info.stack_top);
+        self.regions.retain(|x| {
+            !matches!(x.kind, RegionKind::Stack { .. }) || x.end <= start || x.start >= end
+        });
+        self.add_region(Region {
+            start,
+            end,
+            flags: VMMapFlags::READ | VMMapFlags::WRITE,
+            kind: RegionKind::Stack { tid },
+        });
+        Ok(())
+    }
+
+    /// 线程 `tid` 退出后移除它的栈区域，内核已经释放了栈中的内存
+    pub fn remove_stack(&mut self, tid: usize) {
+        self.regions.retain(|x| x.kind != RegionKind::Stack { tid });
+    }
+
+    /// 在区域之间找到大小为 `size` 并且按照 `align` 对齐的空闲虚拟地址
+    pub fn alloc_size(&self, size: usize, align: usize) -> Result<usize, UserError> {
+        // 请求的大小来自其他任务，先排除对齐和相加时会溢出的大小
+        if size > USER_SPACE_END {
+            return Err(UserError::NoResources);
+        }
+        let size = align_up(size, PAGE_SIZE);
+        let mut start = align_up(self.valloc_base, align);
+        // 整个栈区域都由内核管理，线程的栈都在这个区域中
+        let mut used: Vec<_> = self
+            .regions
+            .iter()
+            .filter(|x| !matches!(x.kind, RegionKind::Stack { .. }))
+            .map(|x| (x.start, x.end))
+            .chain([(self.stack_area_top - STACK_AREA_SIZE, self.stack_area_top)])
+            .collect();
+        used.sort_unstable();
+        // 区域按照起始地址排序，第一个能放下的空隙就是结果
+        for (region_start, region_end) in used {
+            if align_down(region_start, PAGE_SIZE) >= start + size {
+                break;
+            }
+            start = cmp::max(start, align_up(align_up(region_end, PAGE_SIZE), align));
+        }
+        match start.checked_add(size) {
+            Some(end) if end <= USER_SPACE_END => Ok(start),
+            _ => Err(UserError::NoResources),
+        }
+    }
+
+    /// 加入区域，保持区域按照起始地址排序
+    pub fn add_region(&mut self, region: Region) {
+        let index = self.regions.partition_point(|x| x.start < region.start);
+        self.regions.insert(index, region);
+    }
+
+    /// 为 `tid` 申请物理内存并映射到空闲的虚拟地址，返回 (uaddr, paddr)，`size` 会向上对齐到页
+    pub fn alloc_memory(&mut self, tid: usize, size: usize) -> Result<(usize, usize), UserError> {
+        if size == 0 {
+            return Err(UserError::InvalidArg);
+        }
+        let uaddr = self.alloc_size(size, huge_align(size))?;
+        let size = align_up(size, PAGE_SIZE);
+        // TODO: use mapping attrs to improve security
+        // 申请对齐的物理内存，以便于使用大页映射
+        let paddr = sys_pm_alloc(tid, size, PMAllocFlags::ALIGNED.bits())?;
+        // 映射失败时不会映射任何页，归还物理内存
+        if let Err(err) = map_region(tid, uaddr, paddr, size) {
+            let _ = sys_pm_free(tid, paddr, size);
+            return Err(err);
+        }
+        self.add_region(Region {
+            start: uaddr,
+            end: uaddr + size,
+            flags: VMMapFlags::READ | VMMapFlags::WRITE,
+            kind: RegionKind::Heap { paddr },
+        });
+        Ok((uaddr, paddr))
+    }
+
+    /// 释放通过 [AddressSpace::alloc_memory] 申请的内存，取消映射后归还物理页
+    /// `uaddr` 和 `size` 需要和申请时一致，`size` 会向上对齐到页
+    pub fn free_memory(&mut self, uaddr: usize, size: usize) -> Result<(), UserError> {
+        if size > USER_SPACE_END {
+            return Err(UserError::InvalidArg);
+        }
+        let size = align_up(size, PAGE_SIZE);
+        let index = self
+            .regions
+            .iter()
+            .position(|x| x.start == uaddr && x.end - x.start == size)
+            .ok_or(UserError::InvalidArg)?;
+        let RegionKind::Heap { paddr } = self.regions[index].kind else {
+            return Err(UserError::InvalidArg);
+        };
+        sys_vm_unmap_range(self.tid, uaddr, size)?;
+        sys_pm_free(self.tid, paddr, size)?;
+        self.regions.remove(index);
+        Ok(())
+    }
+
+    /// 将物理内存 `[paddr, paddr + size)` 映射到 `tid` 空闲的虚拟地址，返回 `paddr` 对应的虚拟地址
+    pub fn map_physical(
+        &mut self,
+        tid: usize,
+        paddr: usize,
+        size: usize,
+    ) -> Result<usize, UserError> {
+        if size == 0 {
+            return Err(UserError::InvalidArg);
+        }
+        // 虚拟地址和物理地址在大页内的偏移保持一致，以便使用大页映射
+        let align = huge_align(size);
+        let offset = paddr % align;
+        let uaddr = self.alloc_size(offset + size, align)? + offset;
+        map_region(tid, uaddr, paddr, size)?;
+        self.add_region(Region {
+            start: align_down(uaddr, PAGE_SIZE),
+            end: align_up(uaddr + size, PAGE_SIZE),
+            flags: VMMapFlags::READ | VMMapFlags::WRITE,
+            kind: RegionKind::Mmio,
+        });
+        Ok(uaddr)
+    }
+
+    /// 取消通过 [AddressSpace::map_physical] 映射的物理内存，`uaddr` 和 `size` 需要和映射时一致
+    pub fn unmap_physical(&mut self, uaddr: usize, size: usize) -> Result<(), UserError> {
+        let (start, end) = (
+            align_down(uaddr, PAGE_SIZE),
+            align_up(uaddr + size, PAGE_SIZE),
+        );
+        let index = self
+            .regions
+            .iter()
+            .position(|x| x.start == start && x.end == end && x.kind == RegionKind::Mmio)
+            .ok_or(UserError::InvalidArg)?;
+        sys_vm_unmap_range(self.tid, start, end - start)?;
+        self.regions.remove(index);
+        Ok(())
+    }
+}
+
+/// 可以使用的大页，按照从大到小的顺序排列
+const HUGE_PAGES: [usize; 2] = [HUGE_PAGE_1G, HUGE_PAGE_2M];
+
+/// 获取大小为 `size` 的内存区域适合的对齐，以便尽可能使用大页映射
+pub fn huge_align(size: usize) -> usize {
+    HUGE_PAGES
+        .into_iter()
+        .find(|page_size| size >= *page_size)
+        .unwrap_or(PAGE_SIZE)
+}
+
+/// 将 `tid` 任务的 `[uaddr, uaddr + size)` 映射到 `[paddr, paddr + size)`
+/// 内核会在地址对齐并且剩余大小足够时使用大页映射，减少页表项和 TLB 的压力
+pub fn map_region(tid: usize, uaddr: usize, paddr: usize, size: usize) -> Result<(), UserError> {
+    // 将区域扩展到页边界
+    let start = align_down(uaddr, PAGE_SIZE);
+    let size = align_up(uaddr + size, PAGE_SIZE) - start;
+    let paddr = align_down(paddr, PAGE_SIZE);
+    let flags = VMMapFlags::HUGE_1G | VMMapFlags::READ | VMMapFlags::WRITE;
+    sys_vm_map_range(tid, start, paddr, size, flags.bits())
+}
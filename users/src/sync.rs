@@ -0,0 +1,143 @@
+//! 基于 futex 的同步原语，获取不到锁时会阻塞在内核中，而不是一直自旋
+//! 同一个地址空间中的线程以及共享同一块物理内存的任务都可以使用
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    syscall::{futex_wait, futex_wake},
+    UserError,
+};
+
+/// 锁没有被持有
+const UNLOCKED: u32 = 0;
+/// 锁被持有，并且没有其他任务在等待
+const LOCKED: u32 = 1;
+/// 锁被持有，并且可能有其他任务在等待
+const CONTENDED: u32 = 2;
+
+/// 互斥锁，`state` 为 [UNLOCKED], [LOCKED] 或 [CONTENDED]
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// 创建互斥锁
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// 获取锁，锁被其他任务持有时阻塞
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // 标记为有任务在等待，然后阻塞直到锁被释放
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                // 被唤醒或者值已经改变时都会返回，重新检查锁的状态即可
+                let _ = futex_wait(&self.state, CONTENDED, 0);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// 尝试获取锁，锁被其他任务持有时返回 None
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    /// 释放锁，如果有任务在等待，那么唤醒其中一个
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = futex_wake(&self.state, 1);
+        }
+    }
+}
+
+/// 互斥锁的守卫，释放时会自动解锁
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// 条件变量，需要和 [Mutex] 一起使用
+/// `seq` 在每次通知时增加，等待的任务通过比较 `seq` 判断在释放锁之后是否有新的通知
+pub struct Condvar {
+    seq: AtomicU32,
+}
+
+impl Condvar {
+    /// 创建条件变量
+    pub const fn new() -> Self {
+        Condvar {
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// 释放锁并等待通知，被唤醒后重新获取锁
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_timeout(guard, 0).0
+    }
+
+    /// 释放锁并等待通知，最多等待 `timeout` ms，0 表示不会超时
+    /// 返回重新获取的锁以及是否超时
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: usize,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        let timed_out = futex_wait(&self.seq, seq, timeout) == Err(UserError::TimedOut);
+        (mutex.lock(), timed_out)
+    }
+
+    /// 唤醒一个等待的任务
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.seq, 1);
+    }
+
+    /// 唤醒所有等待的任务
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.seq, usize::MAX);
+    }
+}
@@ -1,19 +1,24 @@
-use core::{arch::asm, panic};
+use core::{
+    arch::asm,
+    mem::size_of,
+    panic,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
 
 use alloc::{string::String, vec::Vec};
-use spin::Mutex;
 use syscall_consts::{
-    IPCFlags, Message, MessageContent, Notify,
+    IPCFlags, Message, MessageContent, MessageType, NamePayload, Notify,
     NotifyEnum::{self, IRQ, TIMER},
-    SysCall, IPC_ANY, NAME_LEN, VM_SERVER,
+    RawMessage, ShortMessage, SysCall, TaskInfo, IPC_ANY, NAME_LEN, PATH_LEN, SHORT_TAG_SHIFT,
+    VM_SERVER,
 };
 
-use crate::{get_string_from_slice, println};
+use crate::{get_string_from_slice, println, rpc, sync::Mutex, UserError};
 
 /// riscv64 发送 syscall
 #[cfg(target_arch = "riscv64")]
 #[inline]
-fn syscall(id: usize, args: [usize; 4]) -> isize {
+fn syscall(id: usize, args: [usize; 6]) -> isize {
     let mut ret: isize;
     unsafe {
         asm!(
@@ -22,16 +27,39 @@ fn syscall(id: usize, args: [usize; 4]) -> isize {
             in("x11") args[1],
             in("x12") args[2],
             in("x13") args[3],
+            in("x14") args[4],
+            in("x15") args[5],
             in("x17") id
         );
     }
     ret
 }
 
+/// riscv64 发送 syscall，除了返回值之外还会返回 a1 到 a4 寄存器
+#[cfg(target_arch = "riscv64")]
+#[inline]
+fn syscall_regs(id: usize, args: [usize; 6]) -> (isize, [usize; 4]) {
+    let ret: isize;
+    let mut regs = [0; 4];
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => ret,
+            inlateout("x11") args[1] => regs[0],
+            inlateout("x12") args[2] => regs[1],
+            inlateout("x13") args[3] => regs[2],
+            inlateout("x14") args[4] => regs[3],
+            in("x15") args[5],
+            in("x17") id
+        );
+    }
+    (ret, regs)
+}
+
 /// aarch64 发送 syscall
 #[cfg(target_arch = "aarch64")]
 #[inline]
-fn syscall(id: usize, args: [usize; 4]) -> isize {
+fn syscall(id: usize, args: [usize; 6]) -> isize {
     let mut ret: isize;
     unsafe {
         asm!(
@@ -40,16 +68,39 @@ fn syscall(id: usize, args: [usize; 4]) -> isize {
             in("x1") args[1],
             in("x2") args[2],
             in("x3") args[3],
+            in("x4") args[4],
+            in("x5") args[5],
             in("x8") id
         );
     }
     ret
 }
 
+/// aarch64 发送 syscall，除了返回值之外还会返回 x1 到 x4 寄存器
+#[cfg(target_arch = "aarch64")]
+#[inline]
+fn syscall_regs(id: usize, args: [usize; 6]) -> (isize, [usize; 4]) {
+    let ret: isize;
+    let mut regs = [0; 4];
+    unsafe {
+        asm!(
+            "svc #0",
+            inlateout("x0") args[0] => ret,
+            inlateout("x1") args[1] => regs[0],
+            inlateout("x2") args[2] => regs[1],
+            inlateout("x3") args[3] => regs[2],
+            inlateout("x4") args[4] => regs[3],
+            in("x5") args[5],
+            in("x8") id
+        );
+    }
+    (ret, regs)
+}
+
 /// x86_64 发送 syscall
 #[cfg(target_arch = "x86_64")]
 #[inline]
-fn syscall(id: usize, args: [usize; 4]) -> isize {
+fn syscall(id: usize, args: [usize; 6]) -> isize {
     let mut ret: isize;
     unsafe {
         asm!(
@@ -64,16 +115,45 @@ fn syscall(id: usize, args: [usize; 4]) -> isize {
             in("rsi") args[1],
             in("rdx") args[2],
             in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
             inlateout("rax") id => ret
         );
     }
     ret
 }
 
+/// x86_64 发送 syscall，除了返回值之外还会返回 rsi、rdx、r10 和 r8 寄存器
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn syscall_regs(id: usize, args: [usize; 6]) -> (isize, [usize; 4]) {
+    let ret: isize;
+    let mut regs = [0; 4];
+    unsafe {
+        asm!(
+            "
+                push r11
+                push rcx
+                syscall
+                pop  rcx
+                pop  r11
+            ",
+            in("rdi") args[0],
+            inlateout("rsi") args[1] => regs[0],
+            inlateout("rdx") args[2] => regs[1],
+            inlateout("r10") args[3] => regs[2],
+            inlateout("r8") args[4] => regs[3],
+            in("r9") args[5],
+            inlateout("rax") id => ret
+        );
+    }
+    (ret, regs)
+}
+
 /// loongarch64 发送 syscall
 #[cfg(target_arch = "loongarch64")]
 #[inline]
-fn syscall(id: usize, args: [usize; 4]) -> isize {
+fn syscall(id: usize, args: [usize; 6]) -> isize {
     let mut ret: isize;
     unsafe {
         asm!(
@@ -82,44 +162,105 @@ fn syscall(id: usize, args: [usize; 4]) -> isize {
             in("$r5") args[1],
             in("$r6") args[2],
             in("$r7") args[3],
+            in("$r8") args[4],
+            in("$r9") args[5],
             in("$r11") id
         );
     }
     ret
 }
 
+/// loongarch64 发送 syscall，除了返回值之外还会返回 $r5 到 $r8 寄存器
+#[cfg(target_arch = "loongarch64")]
+#[inline]
+fn syscall_regs(id: usize, args: [usize; 6]) -> (isize, [usize; 4]) {
+    let ret: isize;
+    let mut regs = [0; 4];
+    unsafe {
+        asm!(
+            "syscall 0",
+            inlateout("$r4") args[0] => ret,
+            inlateout("$r5") args[1] => regs[0],
+            inlateout("$r6") args[2] => regs[1],
+            inlateout("$r7") args[3] => regs[2],
+            inlateout("$r8") args[4] => regs[3],
+            in("$r9") args[5],
+            in("$r11") id
+        );
+    }
+    (ret, regs)
+}
+
+/// 将系统调用的返回值转换为 [Result]，负数为 [UserError] 的错误码
+#[inline]
+fn check(ret: isize) -> Result<usize, UserError> {
+    match ret < 0 {
+        true => Err(UserError::from(ret)),
+        false => Ok(ret as usize),
+    }
+}
+
 /// 等待处理的通知集合，这其实是一个 bitset
 static PENDING_NOTIFICATIONS: Mutex<Notify> = Mutex::new(Notify::new());
 
 /// 将通知转换为消息
-pub fn recv_notification_as_message(message: &mut Message) -> isize {
+pub fn recv_notification_as_message(message: &mut Message) -> Result<(), UserError> {
     assert!(!PENDING_NOTIFICATIONS.lock().is_empty());
     match PENDING_NOTIFICATIONS.lock().pop().unwrap() {
         TIMER => {
             message.content = MessageContent::NotifyTimer;
-            0
+            Ok(())
         }
         IRQ => {
             message.content = MessageContent::NotifyIRQ;
-            0
+            Ok(())
+        }
+        // 有任务发送失败的异步消息，通过 AsyncRecvMsg 逐个取回
+        NotifyEnum::ASYNC => {
+            let tid = sys_async_sender()?;
+            message.content = MessageContent::raw(RawMessage::empty(MessageType::AsyncRecvMsg));
+            ipc_call(tid, message)
         }
-        NotifyEnum::ASYNC(tid) => todo!("tid: {tid} implment async task notification"),
         unexpected => panic!("unhandled notification: {:?}", unexpected),
     }
 }
 
 /// 接受 any 的 message
-pub fn ip_recv_any(message: &mut Message) -> isize {
+pub fn ip_recv_any(message: &mut Message) -> Result<(), UserError> {
+    recv_any_with(message, |message| {
+        sys_ipc(0, IPC_ANY, message, IPCFlags::RECV)
+    })
+}
+
+/// 接收任意任务的消息，能用短消息表示的消息只通过寄存器接收
+/// 不能用短消息表示的消息会多一次系统调用，适合大部分请求都很短的服务
+pub fn ipc_recv_any_short(message: &mut Message) -> Result<(), UserError> {
+    recv_any_with(message, |message| {
+        let mut short = ShortMessage::default();
+        match sys_ipc_short(0, IPC_ANY, &mut short, IPCFlags::RECV) {
+            Ok(()) => {
+                *message = Message::try_from(&short.to_raw().ok_or(UserError::Unexpected)?)?;
+                Ok(())
+            }
+            // 内核保留了这条消息，通过缓冲区接收
+            Err(UserError::TooLarge) => sys_ipc(0, IPC_ANY, message, IPCFlags::RECV),
+            Err(err) => Err(err),
+        }
+    })
+}
+
+/// 通过 `recv` 接收任意任务的消息，收到的通知会逐个转换为消息
+fn recv_any_with(
+    message: &mut Message,
+    recv: impl Fn(&mut Message) -> Result<(), UserError>,
+) -> Result<(), UserError> {
     loop {
         // 如果有收到通知，则将通知转换为消息并返回。
         if !PENDING_NOTIFICATIONS.lock().is_empty() {
             return recv_notification_as_message(message);
         }
         // 发送 IPC 请求，阻塞直到有消息返回
-        let ret = sys_ipc(0, IPC_ANY, message, IPCFlags::RECV);
-        if ret != 0 {
-            return ret;
-        }
+        recv(message)?;
 
         // 匹配消息内容
         match message.content {
@@ -130,13 +271,13 @@ pub fn ip_recv_any(message: &mut Message) -> isize {
                 *PENDING_NOTIFICATIONS.lock() |= notications;
                 return recv_notification_as_message(message);
             }
-            _ => return ret,
+            _ => return Ok(()),
         }
     }
 }
 
 // 接受 IPC 消息
-pub fn ipc_recv(src: usize, message: &mut Message) -> isize {
+pub fn ipc_recv(src: usize, message: &mut Message) -> Result<(), UserError> {
     if src == IPC_ANY {
         return ip_recv_any(message);
     }
@@ -147,78 +288,241 @@ pub fn ipc_recv(src: usize, message: &mut Message) -> isize {
 
 /// 发送或接收 IPC
 #[inline]
-pub fn sys_ipc(dst: usize, src: usize, message: &mut Message, flags: IPCFlags) -> isize {
-    syscall(
+pub fn sys_ipc(
+    dst: usize,
+    src: usize,
+    message: &mut Message,
+    flags: IPCFlags,
+) -> Result<(), UserError> {
+    // 内核只接受固定布局的 RawMessage
+    let mut raw = RawMessage::from(&*message);
+    sys_ipc_raw(dst, src, &mut raw, flags)?;
+    if flags.contains(IPCFlags::RECV) {
+        *message = Message::try_from(&raw)?;
+    }
+    Ok(())
+}
+
+/// 使用固定布局的 [RawMessage] 发送或接收 IPC，不需要转换消息
+#[inline]
+pub fn sys_ipc_raw(
+    dst: usize,
+    src: usize,
+    message: &mut RawMessage,
+    flags: IPCFlags,
+) -> Result<(), UserError> {
+    check(syscall(
         SysCall::IPC.into(),
-        [dst, src, message as *mut _ as usize, flags.bits()],
-    )
+        [dst, src, message as *mut _ as usize, flags.bits(), 0, 0],
+    ))?;
+    Ok(())
 }
 
 /// 发送并接收 IPC
 #[inline]
-pub fn ipc_call(dst: usize, message: &mut Message) -> isize {
+pub fn ipc_call(dst: usize, message: &mut Message) -> Result<(), UserError> {
     sys_ipc(dst, dst, message, IPCFlags::CALL)
 }
 
+/// 发送或接收短消息 IPC，消息的标签和负载只通过寄存器传递
+/// 接收到消息时写入 `message`，接收到的消息不能用短消息表示时返回 [UserError::TooLarge]，
+/// 内核会保留这条消息，需要再通过普通的 IPC 接收
+#[inline]
+pub fn sys_ipc_short(
+    dst: usize,
+    src: usize,
+    message: &mut ShortMessage,
+    flags: IPCFlags,
+) -> Result<(), UserError> {
+    let (ret, regs) = syscall_regs(
+        SysCall::IPCShort.into(),
+        [
+            dst,
+            src,
+            flags.bits() | message.tag() << SHORT_TAG_SHIFT,
+            message.words[0],
+            message.words[1],
+            message.words[2],
+        ],
+    );
+    let tag = check(ret)?;
+    if flags.contains(IPCFlags::RECV) {
+        *message = ShortMessage::from_tag(regs[3], tag, [regs[0], regs[1], regs[2]]);
+    }
+    Ok(())
+}
+
+/// 发送短消息并接收回复
+#[inline]
+pub fn ipc_call_short(dst: usize, message: &mut ShortMessage) -> Result<(), UserError> {
+    sys_ipc_short(dst, dst, message, IPCFlags::CALL)
+}
+
 /// 发送 ipc 请求
 #[inline]
-pub fn ipc_send_noblock(dst: usize, message: &mut Message) -> isize {
-    return sys_ipc(dst, 0, message, IPCFlags::SEND | IPCFlags::NON_BLOCK);
+pub fn ipc_send_noblock(dst: usize, message: &mut Message) -> Result<(), UserError> {
+    sys_ipc(dst, 0, message, IPCFlags::SEND | IPCFlags::NON_BLOCK)
 }
 
-/// 回复 IPC 请求
+/// 回复 IPC 请求，回复失败不影响服务继续运行，所以只输出错误信息
 #[inline]
-pub fn ipc_reply(dst: usize, message: &mut Message) -> isize {
-    let ret = ipc_send_noblock(dst, message);
-    if ret < 0 {
-        println!("[error] unexpected error {}", ret);
+pub fn ipc_reply(dst: usize, message: &mut Message) {
+    if let Err(err) = ipc_send_noblock(dst, message) {
+        println!("[error] failed to reply to task {}: {}", dst, err);
     }
-    ret
 }
 
-/// 创建任务
+/// 等待对方取回的异步消息，对方没有在接收消息时发送失败的消息会放在这里
+static ASYNC_MESSAGES: Mutex<Vec<(usize, RawMessage)>> = Mutex::new(Vec::new());
+
+/// 通知 `dst` 当前任务有等待它取回的异步消息
 #[inline]
-pub fn sys_task_create(name: &str, entry: usize, pager: usize) -> isize {
-    syscall(
-        SysCall::TaskCreate.into(),
-        [name.as_ptr() as usize, entry, pager, 0],
-    )
+pub fn sys_notify(dst: usize) -> Result<(), UserError> {
+    check(syscall(SysCall::Notify.into(), [dst, 0, 0, 0, 0, 0]))?;
+    Ok(())
 }
 
-/// 串口输出
+/// 取出下一个有异步消息等待当前任务取回的任务
 #[inline]
-pub fn serial_write(buf: &[u8]) -> usize {
-    syscall(
+pub fn sys_async_sender() -> Result<usize, UserError> {
+    check(syscall(SysCall::AsyncSender.into(), [0, 0, 0, 0, 0, 0]))
+}
+
+/// 不阻塞地发送消息，对方没有在接收消息时放到异步消息中并通知对方取回
+/// 能用短消息表示的消息只通过寄存器发送
+pub fn ipc_send_async(dst: usize, mut message: RawMessage) -> Result<(), UserError> {
+    let flags = IPCFlags::SEND | IPCFlags::NON_BLOCK;
+    let result = match ShortMessage::from_raw(&message) {
+        Some(mut short) => sys_ipc_short(dst, 0, &mut short, flags),
+        None => sys_ipc_raw(dst, 0, &mut message, flags),
+    };
+    match result {
+        Err(UserError::WouldBlock) => {
+            ASYNC_MESSAGES.lock().push((dst, message));
+            // 通知失败时对方永远不会取回这条消息
+            sys_notify(dst).map_err(|err| {
+                let mut messages = ASYNC_MESSAGES.lock();
+                if let Some(index) = messages.iter().rposition(|x| x.0 == dst) {
+                    messages.remove(index);
+                }
+                err
+            })
+        }
+        result => result,
+    }
+}
+
+/// 取出发送给 `dst` 的异步消息，还有剩余的消息时再次通知对方
+pub fn take_async_message(dst: usize) -> Option<RawMessage> {
+    let mut messages = ASYNC_MESSAGES.lock();
+    let index = messages.iter().position(|x| x.0 == dst)?;
+    let (_, message) = messages.remove(index);
+    if messages.iter().any(|x| x.0 == dst) {
+        let _ = sys_notify(dst);
+    }
+    Some(message)
+}
+
+/// 丢弃发送给已经退出的任务 `dst` 的异步消息
+pub fn drop_async_messages(dst: usize) {
+    ASYNC_MESSAGES.lock().retain(|x| x.0 != dst);
+}
+
+/// 创建任务，返回新任务的 id，`mem_quota` 为任务的内存配额 (单位: 页)，
+/// 配额不能为 0，并且从当前任务剩余的配额中扣除，新任务退出后归还
+/// `stack_size` 为栈的最大大小，0 表示使用内核默认的大小
+/// `startup` 为任务的启动信息，可以通过 [crate::env::StartupInfo::to_bytes] 生成
+/// 内核读取任务名称直到 `\0`，所以会复制一份以 `\0` 结尾的名称，名称最长为 [PATH_LEN] 个字节
+#[inline]
+pub fn sys_task_create(
+    name: &str,
+    entry: usize,
+    pager: usize,
+    mem_quota: usize,
+    stack_size: usize,
+    startup: &[u8],
+) -> Result<usize, UserError> {
+    let startup = match startup.is_empty() {
+        true => 0,
+        false => startup.as_ptr() as usize,
+    };
+    let name = [name, "\0"].concat();
+    check(syscall(
+        SysCall::TaskCreate.into(),
+        [
+            name.as_ptr() as usize,
+            entry,
+            pager,
+            mem_quota,
+            stack_size,
+            startup,
+        ],
+    ))
+}
+
+/// 获取任务信息，包括内存配额和当前的内存使用量
+pub fn task_info(tid: usize) -> Result<TaskInfo, UserError> {
+    let mut info = TaskInfo::default();
+    check(syscall(
+        SysCall::TaskInfo.into(),
+        [tid, &mut info as *mut _ as usize, 0, 0, 0, 0],
+    ))?;
+    Ok(info)
+}
+
+/// 串口输出，返回值为写入的字符数
+#[inline]
+pub fn serial_write(buf: &[u8]) -> Result<usize, UserError> {
+    check(syscall(
         SysCall::SerialWrite.into(),
-        [buf.as_ptr() as usize, buf.len(), 0, 0],
-    ) as _
+        [buf.as_ptr() as usize, buf.len(), 0, 0, 0, 0],
+    ))
 }
 
 /// 串口输入，返回值为读取的字符数
 #[inline]
-pub fn serial_read(buf: &[u8]) -> usize {
-    syscall(
+pub fn serial_read(buf: &mut [u8]) -> Result<usize, UserError> {
+    check(syscall(
         SysCall::SerialRead.into(),
-        [buf.as_ptr() as usize, buf.len(), 0, 0],
-    ) as _
+        [buf.as_mut_ptr() as usize, buf.len(), 0, 0, 0, 0],
+    ))
 }
 
 /// 设置一个定时器, 时间到了内核会发送 Notification (单位: ms)
 #[inline]
-pub fn sys_time(ms: usize) -> usize {
-    syscall(SysCall::Time.into(), [ms, 0, 0, 0]) as _
+pub fn sys_time(ms: usize) -> Result<(), UserError> {
+    check(syscall(SysCall::Time.into(), [ms, 0, 0, 0, 0, 0]))?;
+    Ok(())
 }
 
-/// 获取从开机到现在多长时间 (单位: ms)
+/// 获取从开机到现在多长时间 (单位: ms)，这个系统调用不会失败
 #[inline]
 pub fn sys_uptime() -> usize {
-    syscall(SysCall::UPTime.into(), [0, 0, 0, 0]) as _
+    syscall(SysCall::UPTime.into(), [0, 0, 0, 0, 0, 0]) as _
+}
+
+/// 使用内核的随机数填充 `buffer`，随机数不能用于密码学用途
+#[inline]
+pub fn get_random(buffer: &mut [u8]) -> Result<(), UserError> {
+    check(syscall(
+        SysCall::GetRandom.into(),
+        [buffer.as_mut_ptr() as usize, buffer.len(), 0, 0, 0, 0],
+    ))
+    .map(|_| ())
+}
+
+/// 获取一个内核生成的随机数
+pub fn random() -> usize {
+    let mut bytes = [0u8; size_of::<usize>()];
+    get_random(&mut bytes).expect("can't get random number from kernel");
+    usize::from_ne_bytes(bytes)
 }
 
-/// 退出当前任务
+/// 以 `exit_code` 退出当前任务，主线程退出时会销毁所有的线程
+/// 监控这个任务的任务会收到带有退出码的 TaskDestroyedMsg
 #[inline]
-pub fn exit() -> ! {
-    syscall(SysCall::TaskExit.into(), [0, 0, 0, 0]);
+pub fn exit(exit_code: usize) -> ! {
+    syscall(SysCall::TaskExit.into(), [exit_code, 0, 0, 0, 0, 0]);
     unreachable!("This task should already exited.")
 }
 
@@ -231,106 +535,211 @@ pub fn shutdown() -> ! {
 
 /// 销毁任务
 #[inline]
-pub fn task_destory(tid: usize) -> isize {
-    syscall(SysCall::TaskDestory.into(), [tid, 0, 0, 0]) as _
+pub fn task_destory(tid: usize) -> Result<(), UserError> {
+    check(syscall(SysCall::TaskDestory.into(), [tid, 0, 0, 0, 0, 0]))?;
+    Ok(())
 }
 
-/// 获取当前的任务 id
+/// 获取当前的任务 id，同一个任务中的每个线程都有不同的任务 id，所以不能缓存
+/// 这个系统调用不会失败
 pub fn task_self() -> usize {
-    static TASK_SELF: Mutex<usize> = Mutex::new(0);
-    if *TASK_SELF.lock() > 0 {
-        return *TASK_SELF.lock();
-    }
+    syscall(SysCall::TaskSelf.into(), Default::default()) as usize
+}
 
-    let tid = syscall(SysCall::TaskSelf.into(), Default::default()) as usize;
-    *TASK_SELF.lock() = tid;
-    tid
+/// 如果 `futex` 中的值等于 `expected`，那么阻塞直到被唤醒
+/// `timeout` 的单位为 ms，0 表示不会超时，超时时返回 [UserError::TimedOut]
+#[inline]
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: usize) -> Result<(), UserError> {
+    check(syscall(
+        SysCall::FutexWait.into(),
+        [futex.as_ptr() as usize, expected as usize, timeout, 0, 0, 0],
+    ))?;
+    Ok(())
+}
+
+/// 唤醒在 `futex` 上等待的最多 `count` 个任务，返回唤醒的任务数量
+#[inline]
+pub fn futex_wake(futex: &AtomicU32, count: usize) -> Result<usize, UserError> {
+    check(syscall(
+        SysCall::FutexWake.into(),
+        [futex.as_ptr() as usize, count, 0, 0, 0, 0],
+    ))
+}
+
+/// 创建共享当前地址空间的线程，返回线程的任务 id，线程从 `entry` 开始运行，`arg` 作为第一个参数
+/// `stack_size` 为线程栈的最大大小，0 表示使用内核默认的大小
+#[inline]
+pub fn sys_thread_create(entry: usize, arg: usize, stack_size: usize) -> Result<usize, UserError> {
+    check(syscall(
+        SysCall::ThreadCreate.into(),
+        [entry, arg, stack_size, 0, 0, 0],
+    ))
+}
+
+/// 给特定的 task 申请物理页，返回物理地址
+#[inline]
+pub fn sys_pm_alloc(tid: usize, size: usize, flags: usize) -> Result<usize, UserError> {
+    check(syscall(
+        SysCall::PMAlloc.into(),
+        [tid, size, flags, 0, 0, 0],
+    ))
 }
 
-/// 给特定的 task 申请物理页
+/// 释放特定 task 的物理内存，只有 task 的 pager 可以释放，root server 可以释放自己的内存
+/// 释放前 task 和当前任务都需要取消这段内存的所有映射，否则返回 [UserError::InvalidArg]
 #[inline]
-pub fn sys_pm_alloc(tid: usize, size: usize, flags: usize) -> isize {
-    syscall(SysCall::PMAlloc.into(), [tid, size, flags, 0])
+pub fn sys_pm_free(tid: usize, paddr: usize, size: usize) -> Result<(), UserError> {
+    check(syscall(SysCall::PMFree.into(), [tid, paddr, size, 0, 0, 0]))?;
+    Ok(())
 }
 
 /// 给特定的 task 映射内存
 #[inline]
-pub fn sys_vm_map(tid: usize, uaddr: usize, paddr: usize, attrs: usize) -> isize {
-    syscall(SysCall::VMMap.into(), [tid, uaddr, paddr, attrs])
+pub fn sys_vm_map(tid: usize, uaddr: usize, paddr: usize, attrs: usize) -> Result<(), UserError> {
+    check(syscall(
+        SysCall::VMMap.into(),
+        [tid, uaddr, paddr, attrs, 0, 0],
+    ))?;
+    Ok(())
 }
 
 /// 给特定的 task 取消映射内存
 #[inline]
-pub fn sys_vm_unmap(tid: usize, uaddr: usize) -> isize {
-    syscall(SysCall::VMUnmap.into(), [tid, uaddr, 0, 0])
+pub fn sys_vm_unmap(tid: usize, uaddr: usize) -> Result<(), UserError> {
+    check(syscall(SysCall::VMUnmap.into(), [tid, uaddr, 0, 0, 0, 0]))?;
+    Ok(())
 }
 
-/// 注册服务
-pub fn ipc_register(name: &str) -> isize {
+/// 给特定的 task 映射一段连续的内存，`[uaddr, uaddr + size)` -> `[paddr, paddr + size)`
+/// 映射是原子的，失败时不会映射任何页
+#[inline]
+pub fn sys_vm_map_range(
+    tid: usize,
+    uaddr: usize,
+    paddr: usize,
+    size: usize,
+    flags: usize,
+) -> Result<(), UserError> {
+    check(syscall(
+        SysCall::VMMapRange.into(),
+        [tid, uaddr, paddr, size, flags, 0],
+    ))?;
+    Ok(())
+}
+
+/// 给特定的 task 取消映射一段连续的内存
+#[inline]
+pub fn sys_vm_unmap_range(tid: usize, uaddr: usize, size: usize) -> Result<(), UserError> {
+    check(syscall(
+        SysCall::VMUnmapRange.into(),
+        [tid, uaddr, size, 0, 0, 0],
+    ))?;
+    Ok(())
+}
+
+/// 翻译虚拟地址，返回对应的物理地址，物理地址可能为 0
+pub fn translate_vaddr(vaddr: usize) -> Result<usize, UserError> {
+    check(syscall(SysCall::TransVAddr.into(), [vaddr, 0, 0, 0, 0, 0]))
+}
+
+/// 将服务名称复制到以 `\0` 结尾的定长缓冲区中，名称太长时返回 [UserError::TooLarge]
+fn name_buffer(name: &str) -> Result<[u8; NAME_LEN], UserError> {
     let bytes = name.as_bytes();
-    assert!(bytes.len() < NAME_LEN, "Service name too long");
-    let mut message = Message::blank();
-    // 复制 name 到 buffer 中，以便于进行 IPC
+    if bytes.len() >= NAME_LEN {
+        return Err(UserError::TooLarge);
+    }
     let mut name_buffer = [0; NAME_LEN];
+    name_buffer[..bytes.len()].copy_from_slice(bytes);
+    Ok(name_buffer)
+}
 
-    name_buffer[..bytes.len()].copy_from_slice(&bytes[..bytes.len()]);
-    name_buffer[bytes.len()..].fill(0);
-
-    message.content = MessageContent::ServiceRegisterMsg { name_buffer };
+/// 注册服务，名称已经被其他任务注册时返回 [UserError::AlreadyExists]
+pub fn ipc_register(name: &str) -> Result<(), UserError> {
+    let request = RawMessage::new(
+        MessageType::ServiceRegisterMsg,
+        NamePayload {
+            name: name_buffer(name)?,
+        },
+    );
+    rpc::call(VM_SERVER, request, MessageType::ServiceRegisterReplyMsg)?;
+    Ok(())
+}
 
-    ipc_call(VM_SERVER, &mut message)
+/// 注销当前任务注册的服务
+pub fn ipc_unregister(name: &str) -> Result<(), UserError> {
+    rpc::vm::unregister_service(VM_SERVER, name_buffer(name)?)
 }
 
-/// 搜索服务对应的 taskid
-pub fn service_lookup(name: &str) -> Option<usize> {
-    let bytes = name.as_bytes();
-    assert!(bytes.len() < NAME_LEN, "Service name too long");
+/// 搜索服务对应的 taskid，服务还没有注册时会一直等待，直到服务注册
+pub fn service_lookup(name: &str) -> Result<usize, UserError> {
     let mut message = Message::blank();
-    // 复制 name 到 buffer 中，以便于进行 IPC
-    let mut name_buffer = [0; NAME_LEN];
+    message.content = MessageContent::ServiceLookupMsg {
+        name_buffer: name_buffer(name)?,
+    };
 
-    name_buffer[..bytes.len()].copy_from_slice(&bytes[..bytes.len()]);
-    name_buffer[bytes.len()..].fill(0);
+    ipc_call(VM_SERVER, &mut message)?;
+    match message.content {
+        MessageContent::ServiceLookupReplyMsg(tid) => Ok(tid),
+        _ => Err(UserError::Unexpected),
+    }
+}
 
-    message.content = MessageContent::ServiceLookupMsg { name_buffer };
+/// 搜索服务对应的 taskid，不会等待服务注册，服务不存在时返回 [UserError::NotFound]
+pub fn service_try_lookup(name: &str) -> Result<usize, UserError> {
+    rpc::vm::find_service(VM_SERVER, name_buffer(name)?)
+}
 
-    let ret = ipc_call(VM_SERVER, &mut message);
-    match ret >= 0 {
-        true => {
-            if let MessageContent::ServiceLookupReplyMsg(reply) = message.content {
-                Some(reply)
-            } else {
-                None
-            }
+/// 监控任务 `tid` 的退出，任务退出时当前任务会收到一次 TaskDestroyedMsg
+/// `tid` 已经退出时返回 [UserError::InvalidTask]
+pub fn watch_task(tid: usize) -> Result<(), UserError> {
+    rpc::vm::watch_tasks(VM_SERVER, tid)
+}
+
+/// 当前任务的 pager，0 表示还没有查询
+static PAGER: AtomicUsize = AtomicUsize::new(0);
+
+/// 获取当前任务的 pager，申请和释放内存的请求都发送给 pager，协议见 [crate::pager]
+/// pager 在创建任务时确定并且由线程共享，所以查询一次后缓存，没有 pager 时使用 root server
+pub fn pager() -> usize {
+    match PAGER.load(Ordering::Relaxed) {
+        0 => {
+            let pager = match task_info(task_self()).map_or(0, |x| x.pager) {
+                0 => VM_SERVER,
+                pager => pager,
+            };
+            PAGER.store(pager, Ordering::Relaxed);
+            pager
         }
-        false => None,
+        pager => pager,
     }
 }
 
-/// 申请内存，如果申请成功，返回一个 tuple, 0: vaddr, 1: paddr
-pub fn alloc_memory(size: usize) -> Option<(usize, usize)> {
+/// 向 pager 申请内存，如果申请成功，返回一个 tuple, 0: vaddr, 1: paddr
+pub fn alloc_memory(size: usize) -> Result<(usize, usize), UserError> {
     let mut message = Message::blank();
 
     // 设置申请内存的消息
     message.content = MessageContent::VmAllocPhysicalMsg { size };
 
-    let ret = ipc_call(VM_SERVER, &mut message);
-    // 判断是否申请成功
-    match ret >= 0 {
-        true => {
-            // 判断返回的消息是否正确
-            if let MessageContent::VmAllocPhysicalReplyMsg { uaddr, paddr } = message.content {
-                Some((uaddr, paddr))
-            } else {
-                None
-            }
+    ipc_call(pager(), &mut message)?;
+    // 判断返回的消息是否正确
+    // uaddr 为 0 表示 pager 申请内存失败
+    match message.content {
+        MessageContent::VmAllocPhysicalReplyMsg { uaddr, paddr } if uaddr != 0 => {
+            Ok((uaddr, paddr))
         }
-        false => None,
+        MessageContent::VmAllocPhysicalReplyMsg { .. } => Err(UserError::NoMemory),
+        _ => Err(UserError::Unexpected),
     }
 }
 
-/// 映射物理内存，如果映射成功，返回一个映射的虚拟地址
-pub fn map_paddr(paddr: usize, size: usize) -> Option<usize> {
+/// 释放通过 [alloc_memory] 申请的内存，`uaddr` 和 `size` 需要和申请时一致
+pub fn free_memory(uaddr: usize, size: usize) -> Result<(), UserError> {
+    rpc::pager::free_memory(pager(), uaddr, size)
+}
+
+/// 通过 pager 映射物理内存，如果映射成功，返回一个映射的虚拟地址
+pub fn map_paddr(paddr: usize, size: usize) -> Result<usize, UserError> {
     let mut message = Message::blank();
 
     // 设置需要映射的物理地址和 size
@@ -340,121 +749,71 @@ pub fn map_paddr(paddr: usize, size: usize) -> Option<usize> {
         map_flags: 0,
     };
 
-    let ret = ipc_call(VM_SERVER, &mut message);
-    // 判断是否映射成功
-    match ret >= 0 {
-        true => {
-            // 判断返回的消息是否正确
-            if let MessageContent::VmMapPhysicalReplyMsg { uaddr } = message.content {
-                Some(uaddr)
-            } else {
-                None
-            }
-        }
-        false => None,
+    ipc_call(pager(), &mut message)?;
+    // 判断返回的消息是否正确
+    // uaddr 为 0 表示 pager 映射内存失败
+    match message.content {
+        MessageContent::VmMapPhysicalReplyMsg { uaddr } if uaddr != 0 => Ok(uaddr),
+        MessageContent::VmMapPhysicalReplyMsg { .. } => Err(UserError::InvalidPaddr),
+        _ => Err(UserError::Unexpected),
     }
 }
 
-/// 获取块设备大小, 返回值为块的数量
-pub fn get_block_capacity(task_id: usize) -> Option<usize> {
-    let mut message = Message::blank();
-
-    // 设置需要映射的物理地址和 size
-    message.content = MessageContent::GetBlockCapacity;
-
-    let ret = ipc_call(task_id, &mut message);
-    // 判断是否映射成功
-    match ret >= 0 {
-        true => {
-            // 判断返回的消息是否正确
-            if let MessageContent::GetBlockCapacityReplyMsg(size) = message.content {
-                Some(size)
-            } else {
-                None
-            }
-        }
-        false => None,
-    }
+/// 取消通过 [map_paddr] 映射的物理内存，`uaddr` 和 `size` 需要和映射时一致
+pub fn unmap_paddr(uaddr: usize, size: usize) -> Result<(), UserError> {
+    rpc::pager::unmap_physical(pager(), uaddr, size)
 }
 
 /// 读取块设备，block_index 是需要读取的块设备地址，buffer 是读取后的数据存放的缓冲区
-pub fn block_read(task_id: usize, block_index: usize, buf: &mut [u8]) -> Option<()> {
-    let mut message = Message::blank();
-
-    // 设置需要读取的块索引和数据存储地址
-    message.content = MessageContent::ReadBlockMsg { block_index };
-
-    let ret = ipc_call(task_id, &mut message);
-    // 判断读取是否成功
-    match ret >= 0 {
-        true => {
-            // 判断返回的消息是否正确
-            if let MessageContent::ReadBlockReplyMsg { buffer } = message.content {
-                buf.copy_from_slice(&buffer);
-                Some(())
-            } else {
-                None
-            }
-        }
-        false => None,
-    }
+pub fn block_read(task_id: usize, block_index: usize, buf: &mut [u8]) -> Result<(), UserError> {
+    let buffer = rpc::block_device::read_block(task_id, block_index)?;
+    buf.copy_from_slice(&buffer);
+    Ok(())
 }
 
-/// 写入块设备，block_index 是需要写入的块设备地址，buufer 是需要写入的数据
-pub fn block_write(task_id: usize, block_index: usize, buf: &mut [u8]) -> Option<()> {
-    let mut message = Message::blank();
-
-    // 设置需要读取的块索引和数据存储地址
-    message.content = MessageContent::WriteBlockMsg {
-        block_index,
-        buffer: buf.try_into().unwrap(),
-    };
-
-    let ret = ipc_call(task_id, &mut message);
-    // 判断读取是否成功
-    match ret >= 0 {
-        true => {
-            // 判断返回的消息是否正确
-            if let MessageContent::WriteBlockReplyMsg = message.content {
-                Some(())
-            } else {
-                None
-            }
-        }
-        false => None,
+/// 将路径复制到以 `\0` 结尾的定长缓冲区中，路径太长时返回 [UserError::TooLarge]
+fn path_buffer(path: &str) -> Result<[u8; PATH_LEN], UserError> {
+    let bytes = path.as_bytes();
+    if bytes.len() >= PATH_LEN {
+        return Err(UserError::TooLarge);
     }
+    let mut buffer = [0u8; PATH_LEN];
+    buffer[..bytes.len()].copy_from_slice(bytes);
+    Ok(buffer)
 }
 
 /// 读取文件夹
-pub fn fs_read_dir(task_id: usize, dir: &str) -> Vec<String> {
+pub fn fs_read_dir(task_id: usize, dir: &str) -> Result<Vec<String>, UserError> {
     let mut container = Vec::new();
-    let mut message = Message::blank();
-    let mut path_buffer = [0u8; 2 * NAME_LEN];
-    let bytes = dir.as_bytes();
-    path_buffer[..bytes.len()].copy_from_slice(bytes);
+    let path = path_buffer(dir)?;
     loop {
-        message.content = MessageContent::FSReadDirMsg {
-            path: path_buffer,
-            index: container.len(),
-        };
-        if ipc_call(task_id, &mut message) < 0 {
+        let (buffer, num) = rpc::fs::read_dir(task_id, path, container.len())?;
+        if num == 0 {
             break;
         }
-        if let MessageContent::FSReadDirReplyMsg { buffer, num } = message.content {
-            if num == 0 {
-                break;
-            }
-            container.push(get_string_from_slice(&buffer));
+        container.push(get_string_from_slice(&buffer));
+    }
+    Ok(container)
+}
+
+/// 读取整个文件
+pub fn fs_read_file(task_id: usize, path: &str) -> Result<Vec<u8>, UserError> {
+    let path = path_buffer(path)?;
+    let size = rpc::fs::file_size(task_id, path)?;
+    let mut data = Vec::new();
+    data.try_reserve_exact(size)
+        .map_err(|_| UserError::NoMemory)?;
+    while data.len() < size {
+        let (buffer, len) = rpc::fs::read_file(task_id, path, data.len())?;
+        if len == 0 {
+            return Err(UserError::EOF);
         }
+        data.extend_from_slice(&buffer[..len.min(size - data.len())]);
     }
-    container
+    Ok(data)
 }
 
-/// 翻译虚拟地址
-pub fn translate_vaddr(vaddr: usize) -> Option<usize> {
-    let ret = syscall(SysCall::TransVAddr.into(), [vaddr, 0, 0, 0]);
-    match ret > 0 {
-        true => Some(ret as usize),
-        false => None,
-    }
+/// 从文件系统加载 ELF 文件并创建任务，返回任务 id
+pub fn spawn_task(path: &str) -> Result<usize, UserError> {
+    rpc::loader::spawn_task(VM_SERVER, path_buffer(path)?)
 }
@@ -0,0 +1,41 @@
+//! 线程，同一个任务中的线程共享地址空间，但是拥有各自的栈和任务 ID
+//! 每个线程都可以独立地进行 IPC，因此服务可以使用多个线程同时处理多个客户端的请求
+
+use alloc::boxed::Box;
+
+use crate::{
+    syscall::{exit, sys_thread_create},
+    UserError,
+};
+
+/// 线程需要执行的函数
+type ThreadMain = Box<dyn FnOnce() + Send + 'static>;
+
+/// 创建一个新的线程运行 `f`，返回线程的任务 ID
+pub fn spawn<F>(f: F) -> Result<usize, UserError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_stack(0, f)
+}
+
+/// 创建一个新的线程运行 `f`，`stack_size` 为线程栈的最大大小，0 表示使用内核默认的大小
+pub fn spawn_with_stack<F>(stack_size: usize, f: F) -> Result<usize, UserError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    // 将闭包放到堆上，由新的线程取出并释放
+    let main: Box<ThreadMain> = Box::new(Box::new(f));
+    let arg = Box::into_raw(main) as usize;
+    sys_thread_create(thread_entry as usize, arg, stack_size).inspect_err(|_| {
+        // 线程没有创建成功，由当前线程释放闭包
+        drop(unsafe { Box::from_raw(arg as *mut ThreadMain) });
+    })
+}
+
+/// 线程的入口，`arg` 为 [spawn] 中放到堆上的闭包
+extern "C" fn thread_entry(arg: usize) -> ! {
+    let main = unsafe { Box::from_raw(arg as *mut ThreadMain) };
+    main();
+    exit(0);
+}